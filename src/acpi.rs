@@ -0,0 +1,339 @@
+#![allow(dead_code)]
+
+use core::{mem, slice};
+use spin::Mutex;
+use bytes::{read_u16, read_u32, read_u64};
+
+// RSDP
+const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const EBDA_POINTER: usize = 0x040E;
+const EBDA_SEARCH_SIZE: usize = 0x0400;
+const BIOS_AREA_START: usize = 0x000E0000;
+const BIOS_AREA_END: usize = 0x00100000;
+
+// Table signatures
+pub const MADT_SIGNATURE: &'static [u8; 4] = b"APIC";
+pub const FADT_SIGNATURE: &'static [u8; 4] = b"FACP";
+pub const HPET_SIGNATURE: &'static [u8; 4] = b"HPET";
+pub const MCFG_SIGNATURE: &'static [u8; 4] = b"MCFG";
+
+// FADT
+const FADT_CENTURY_OFFSET: usize = 108;
+
+// HPET
+const HPET_ADDRESS_OFFSET: usize = 8;
+const GAS_SYSTEM_MEMORY: u8 = 0;
+
+// MCFG
+const MCFG_ENTRIES_OFFSET: usize = 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+// MADT
+const MADT_HEADER_SIZE: usize = 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+pub const MADT_FLAG_PCAT_COMPAT: u32 = 0x01;
+
+/// Root System Description Pointer.
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// System Description Table header, shared by all ACPI tables.
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Location of the RSDT or XSDT.
+#[derive(Copy, Clone)]
+struct RootTable {
+    addr: usize,
+    entry_size: usize,
+}
+
+/// The root table, once found.
+static ROOT: Mutex<Option<RootTable>> = Mutex::new(None);
+
+/// Multiple APIC Description Table.
+pub struct Madt {
+    pub local_apic_address: u32,
+    pub flags: u32,
+    entries: &'static [u8],
+}
+
+/// MADT interrupt controller structure.
+#[derive(Copy, Clone, Debug)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    InterruptOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicNmi {
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicAddress(u64),
+    Unknown(u8),
+}
+
+/// Iterator over the MADT interrupt controller structures.
+pub struct MadtIter {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl SdtHeader {
+    /// Gets the whole table, including the header.
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+    /// Gets the table contents following the header.
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes()[mem::size_of::<SdtHeader>()..]
+    }
+}
+
+/// Locates the RSDP and the root table.
+pub fn init() -> bool {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            klog!("[acpi] RSDP not found");
+            return false;
+        }
+    };
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable {
+            addr: rsdp.xsdt_address as usize,
+            entry_size: 8,
+        }
+    } else {
+        RootTable {
+            addr: rsdp.rsdt_address as usize,
+            entry_size: 4,
+        }
+    };
+    let header = unsafe { &*(root.addr as *const SdtHeader) };
+    if !checksum(header.bytes()) {
+        klog!("[acpi] Invalid root table checksum");
+        return false;
+    }
+    klog!("[acpi] Revision {} root table at {:#x}", rsdp.revision, root.addr);
+    *ROOT.lock() = Some(root);
+    true
+}
+
+/// Finds a table by its signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let root = match *ROOT.lock() {
+        Some(root) => root,
+        None => return None,
+    };
+    let header = unsafe { &*(root.addr as *const SdtHeader) };
+    let entries = header.data();
+    for i in 0..(entries.len() / root.entry_size) {
+        let addr = match root.entry_size {
+            8 => read_u64(entries, i * 8) as usize,
+            _ => read_u32(entries, i * 4) as usize,
+        };
+        let table = unsafe { &*(addr as *const SdtHeader) };
+        if &table.signature == signature && checksum(table.bytes()) {
+            return Some(table);
+        }
+    }
+    None
+}
+
+/// Gets the MADT.
+pub fn madt() -> Option<Madt> {
+    find_table(MADT_SIGNATURE).map(|table| {
+        let data = table.data();
+        Madt {
+            local_apic_address: read_u32(data, 0),
+            flags: read_u32(data, 4),
+            entries: &data[MADT_HEADER_SIZE..],
+        }
+    })
+}
+
+/// Gets the CMOS index of the RTC century register from the FADT.
+pub fn century_register() -> Option<u8> {
+    find_table(FADT_SIGNATURE).and_then(|table| {
+        let bytes = table.bytes();
+        if bytes.len() > FADT_CENTURY_OFFSET && bytes[FADT_CENTURY_OFFSET] != 0 {
+            Some(bytes[FADT_CENTURY_OFFSET])
+        } else {
+            None
+        }
+    })
+}
+
+/// Gets the MMIO base address of the HPET from the HPET table.
+pub fn hpet_address() -> Option<usize> {
+    find_table(HPET_SIGNATURE).and_then(|table| {
+        let data = table.data();
+        // The address is a Generic Address Structure following the block id.
+        if data.len() < HPET_ADDRESS_OFFSET + 8 || data[4] != GAS_SYSTEM_MEMORY {
+            None
+        } else {
+            Some(read_u64(data, HPET_ADDRESS_OFFSET) as usize)
+        }
+    })
+}
+
+/// PCI Express memory-mapped configuration space of one segment.
+#[derive(Copy, Clone, Debug)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Gets the ECAM region covering the given PCI segment from the MCFG.
+pub fn mcfg_entry(segment: u16) -> Option<McfgEntry> {
+    find_table(MCFG_SIGNATURE).and_then(|table| {
+        let data = table.data();
+        if data.len() < MCFG_ENTRIES_OFFSET {
+            return None;
+        }
+        data[MCFG_ENTRIES_OFFSET..]
+            .chunks(MCFG_ENTRY_SIZE)
+            .filter(|entry| entry.len() == MCFG_ENTRY_SIZE)
+            .map(|entry| {
+                McfgEntry {
+                    base_address: read_u64(entry, 0),
+                    segment: read_u16(entry, 8),
+                    start_bus: entry[10],
+                    end_bus: entry[11],
+                }
+            })
+            .find(|entry| entry.segment == segment)
+    })
+}
+
+impl Madt {
+    /// Iterates over the interrupt controller structures.
+    pub fn entries(&self) -> MadtIter {
+        MadtIter {
+            data: self.entries,
+            offset: 0,
+        }
+    }
+}
+
+impl Iterator for MadtIter {
+    type Item = MadtEntry;
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.offset + 2 > self.data.len() {
+            return None;
+        }
+        let kind = self.data[self.offset];
+        let length = self.data[self.offset + 1] as usize;
+        if length < 2 || self.offset + length > self.data.len() {
+            return None;
+        }
+        let e = &self.data[self.offset..self.offset + length];
+        self.offset += length;
+        Some(match kind {
+            MADT_LOCAL_APIC => {
+                MadtEntry::LocalApic {
+                    processor_id: e[2],
+                    apic_id: e[3],
+                    flags: read_u32(e, 4),
+                }
+            }
+            MADT_IO_APIC => {
+                MadtEntry::IoApic {
+                    id: e[2],
+                    address: read_u32(e, 4),
+                    gsi_base: read_u32(e, 8),
+                }
+            }
+            MADT_INTERRUPT_OVERRIDE => {
+                MadtEntry::InterruptOverride {
+                    bus: e[2],
+                    source: e[3],
+                    gsi: read_u32(e, 4),
+                    flags: read_u16(e, 8),
+                }
+            }
+            MADT_LOCAL_APIC_NMI => {
+                MadtEntry::LocalApicNmi {
+                    processor_id: e[2],
+                    flags: read_u16(e, 3),
+                    lint: e[5],
+                }
+            }
+            MADT_LOCAL_APIC_ADDRESS => MadtEntry::LocalApicAddress(read_u64(e, 4)),
+            _ => MadtEntry::Unknown(kind),
+        })
+    }
+}
+
+/// Searches the EBDA and the BIOS area for the RSDP.
+fn find_rsdp() -> Option<&'static Rsdp> {
+    let ebda = unsafe { (*(EBDA_POINTER as *const u16) as usize) << 4 };
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, ebda + EBDA_SEARCH_SIZE) {
+            return Some(rsdp);
+        }
+    }
+    scan_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// Scans a memory range for the RSDP on 16-byte boundaries.
+fn scan_rsdp(start: usize, end: usize) -> Option<&'static Rsdp> {
+    let mut addr = start;
+    while addr + RSDP_V1_SIZE <= end {
+        let candidate = unsafe { &*(addr as *const Rsdp) };
+        if &candidate.signature == RSDP_SIGNATURE {
+            let bytes = unsafe { slice::from_raw_parts(addr as *const u8, RSDP_V1_SIZE) };
+            if checksum(bytes) {
+                return Some(candidate);
+            }
+        }
+        addr += 16;
+    }
+    None
+}
+
+/// Validates an ACPI checksum.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
@@ -0,0 +1,512 @@
+#![allow(dead_code)]
+
+use core::{ptr, slice};
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use block::{self, BlockDevice};
+use device::*;
+use pci::{self, PciDevice, PciDriver, PciMatch};
+use apic;
+use cpu;
+use heap;
+use irq;
+use pit;
+
+// Limits
+pub const AHCI_SECTOR_SIZE: usize = 512;
+const AHCI_MAX_CONTROLLERS: usize = 4;
+const AHCI_MAX_PORTS: usize = 32;
+const AHCI_BOUNCE_SIZE: usize = 64 * 1024;
+const AHCI_TIMEOUT_MS: u64 = 5000;
+const AHCI_POLL_LIMIT: usize = 1000000;
+const ABAR_INDEX: usize = 5;
+
+// Generic host control
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const HBA_GHC_IE: u32 = 0x00000002;
+const HBA_GHC_AE: u32 = 0x80000000;
+
+// Port registers
+const HBA_PORT_BASE: usize = 0x100;
+const HBA_PORT_SIZE: usize = 0x80;
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SCTL: usize = 0x2C;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+// Port command
+const PORT_CMD_ST: u32 = 0x0001;
+const PORT_CMD_FRE: u32 = 0x0010;
+const PORT_CMD_FR: u32 = 0x4000;
+const PORT_CMD_CR: u32 = 0x8000;
+
+// Port interrupts
+const PORT_IS_DHRS: u32 = 0x00000001;
+const PORT_IS_PSS: u32 = 0x00000002;
+const PORT_IS_SDBS: u32 = 0x00000008;
+const PORT_IS_TFES: u32 = 0x40000000;
+
+// Port status
+const PORT_SSTS_DET_MASK: u32 = 0x0F;
+const PORT_SSTS_DET_PRESENT: u32 = 0x03;
+const PORT_SSTS_IPM_MASK: u32 = 0xF00;
+const PORT_SSTS_IPM_ACTIVE: u32 = 0x100;
+const PORT_SIG_ATA: u32 = 0x00000101;
+
+// Port control
+const PORT_SCTL_DET_MASK: u32 = 0x0F;
+const PORT_SCTL_DET_INIT: u32 = 0x01;
+
+// Task file
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+
+// Command list and tables
+const COMMAND_LIST_SIZE: usize = 1024;
+const RECEIVED_FIS_SIZE: usize = 256;
+const COMMAND_TABLE_SIZE: usize = 256;
+const COMMAND_TABLE_PRDT: usize = 0x80;
+const COMMAND_HEADER_WRITE: u32 = 0x40;
+const PRD_INTERRUPT: u32 = 0x80000000;
+
+// FIS
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_H2D_COMMAND: u8 = 0x80;
+const FIS_H2D_LENGTH: u32 = 5;
+const FIS_DEVICE_LBA: u8 = 0x40;
+
+// ATA commands
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+// IDENTIFY words
+const ATA_IDENT_MODEL: usize = 27;
+const ATA_IDENT_MODEL_WORDS: usize = 20;
+const ATA_IDENT_LBA28_SECTORS: usize = 60;
+const ATA_IDENT_LBA48_SECTORS: usize = 100;
+
+/// ABAR of each controller, for the interrupt handler.
+static CONTROLLERS: [AtomicUsize; AHCI_MAX_CONTROLLERS] = [AtomicUsize::new(0),
+                                                           AtomicUsize::new(0),
+                                                           AtomicUsize::new(0),
+                                                           AtomicUsize::new(0)];
+
+/// Ports with a task file error seen by the interrupt handler.
+static TASK_FILE_ERRORS: [AtomicUsize; AHCI_MAX_CONTROLLERS] = [AtomicUsize::new(0),
+                                                                AtomicUsize::new(0),
+                                                                AtomicUsize::new(0),
+                                                                AtomicUsize::new(0)];
+
+/// IRQ lines carrying the shared interrupt handler.
+static IRQ_LINES: AtomicUsize = AtomicUsize::new(0);
+
+/// Next disk number.
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+static AHCI_MATCHES: [PciMatch; 1] = [PciMatch {
+                                          vendor_id: None,
+                                          device_id: None,
+                                          class: Some(0x01),
+                                          subclass: Some(0x06),
+                                      }];
+
+static AHCI_DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &AHCI_MATCHES,
+    probe: probe,
+};
+
+/// Reads an HBA register.
+#[inline]
+fn read(base: usize, reg: usize) -> u32 {
+    unsafe { ptr::read_volatile((base + reg) as *const u32) }
+}
+
+/// Writes an HBA register.
+#[inline]
+fn write(base: usize, reg: usize, val: u32) {
+    unsafe { ptr::write_volatile((base + reg) as *mut u32, val) }
+}
+
+/// SATA disk attached to an AHCI port.
+pub struct AhciDisk {
+    controller: usize,
+    port: usize,
+    regs: usize,
+    command_list: usize,
+    command_table: usize,
+    bounce: usize,
+    sectors: u64,
+    model: [u8; ATA_IDENT_MODEL_WORDS * 2],
+}
+
+impl AhciDisk {
+    /// Sets up a port's command list and FIS area and starts it.
+    fn new(controller: usize, base: usize, port: usize) -> Option<Self> {
+        let regs = base + HBA_PORT_BASE + port * HBA_PORT_SIZE;
+        let command_list = heap::kalloc_aligned(COMMAND_LIST_SIZE, 1024);
+        let received_fis = heap::kalloc_aligned(RECEIVED_FIS_SIZE, 256);
+        let command_table = heap::kalloc_aligned(COMMAND_TABLE_SIZE, 128);
+        let bounce = heap::kalloc_aligned(AHCI_BOUNCE_SIZE, 4096);
+        let (command_list, received_fis, command_table, bounce) =
+            match (command_list, received_fis, command_table, bounce) {
+                (Some(list), Some(fis), Some(table), Some(bounce)) => {
+                    (list as usize, fis as usize, table as usize, bounce as usize)
+                }
+                _ => return None,
+            };
+        if !stop(regs) {
+            return None;
+        }
+        write(regs, PORT_CLB, command_list as u32);
+        write(regs, PORT_CLBU, (command_list as u64 >> 32) as u32);
+        write(regs, PORT_FB, received_fis as u32);
+        write(regs, PORT_FBU, (received_fis as u64 >> 32) as u32);
+        write(regs, PORT_SERR, 0xFFFFFFFF);
+        write(regs, PORT_IS, 0xFFFFFFFF);
+        write(regs, PORT_IE, PORT_IS_DHRS | PORT_IS_PSS | PORT_IS_SDBS | PORT_IS_TFES);
+        if !start(regs) {
+            return None;
+        }
+        let mut disk = AhciDisk {
+            controller: controller,
+            port: port,
+            regs: regs,
+            command_list: command_list,
+            command_table: command_table,
+            bounce: bounce,
+            sectors: 0,
+            model: [0; ATA_IDENT_MODEL_WORDS * 2],
+        };
+        disk.identify().ok().map(|_| disk)
+    }
+    /// Gets the model string reported by IDENTIFY.
+    pub fn model(&self) -> &str {
+        let len = self.model.iter().rposition(|&c| c != b' ' && c != 0).map_or(0, |i| i + 1);
+        match ::core::str::from_utf8(&self.model[..len]) {
+            Ok(model) => model,
+            Err(_) => "",
+        }
+    }
+    /// Sends IDENTIFY and records the capacity and model.
+    fn identify(&mut self) -> Result<(), DeviceError> {
+        self.run(ATA_CMD_IDENTIFY, 0, 0, AHCI_SECTOR_SIZE, false)?;
+        let ident = unsafe { &*(self.bounce as *const [u16; 256]) };
+        let lba48 = (ident[ATA_IDENT_LBA48_SECTORS] as u64) |
+                    (ident[ATA_IDENT_LBA48_SECTORS + 1] as u64) << 16 |
+                    (ident[ATA_IDENT_LBA48_SECTORS + 2] as u64) << 32 |
+                    (ident[ATA_IDENT_LBA48_SECTORS + 3] as u64) << 48;
+        self.sectors = if lba48 != 0 {
+            lba48
+        } else {
+            (ident[ATA_IDENT_LBA28_SECTORS] as u64) |
+            (ident[ATA_IDENT_LBA28_SECTORS + 1] as u64) << 16
+        };
+        // The model string is stored as big-endian words.
+        for i in 0..ATA_IDENT_MODEL_WORDS {
+            let word = ident[ATA_IDENT_MODEL + i];
+            self.model[i * 2] = (word >> 8) as u8;
+            self.model[i * 2 + 1] = word as u8;
+        }
+        Ok(())
+    }
+    /// Runs a command in slot 0, moving `len` bytes through the bounce buffer.
+    /// The port is recovered if the command fails.
+    fn run(&mut self, command: u8, lba: u64, count: u16, len: usize, write_data: bool)
+           -> Result<(), DeviceError> {
+        let idle = (0..AHCI_POLL_LIMIT).any(|_| {
+            read(self.regs, PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0
+        });
+        if !idle {
+            self.recover();
+            return Err(DeviceError::Io);
+        }
+        unsafe {
+            ptr::write_bytes(self.command_table as *mut u8, 0, COMMAND_TABLE_SIZE);
+            let fis = self.command_table as *mut u8;
+            *fis.offset(0) = FIS_TYPE_REG_H2D;
+            *fis.offset(1) = FIS_H2D_COMMAND;
+            *fis.offset(2) = command;
+            *fis.offset(4) = lba as u8;
+            *fis.offset(5) = (lba >> 8) as u8;
+            *fis.offset(6) = (lba >> 16) as u8;
+            *fis.offset(7) = FIS_DEVICE_LBA;
+            *fis.offset(8) = (lba >> 24) as u8;
+            *fis.offset(9) = (lba >> 32) as u8;
+            *fis.offset(10) = (lba >> 40) as u8;
+            *fis.offset(12) = count as u8;
+            *fis.offset(13) = (count >> 8) as u8;
+            let prdt_length = if len > 0 {
+                let prd = (self.command_table + COMMAND_TABLE_PRDT) as *mut u32;
+                *prd.offset(0) = self.bounce as u32;
+                *prd.offset(1) = (self.bounce as u64 >> 32) as u32;
+                *prd.offset(3) = (len as u32 - 1) | PRD_INTERRUPT;
+                1
+            } else {
+                0
+            };
+            let header = self.command_list as *mut u32;
+            let flags = if write_data { COMMAND_HEADER_WRITE } else { 0 };
+            *header.offset(0) = FIS_H2D_LENGTH | flags | prdt_length << 16;
+            *header.offset(1) = 0;
+            *header.offset(2) = self.command_table as u32;
+            *header.offset(3) = (self.command_table as u64 >> 32) as u32;
+        }
+        TASK_FILE_ERRORS[self.controller].fetch_and(!(1 << self.port), Ordering::SeqCst);
+        write(self.regs, PORT_IS, 0xFFFFFFFF);
+        fence(Ordering::SeqCst);
+        write(self.regs, PORT_CI, 1);
+        if let Err(err) = self.wait() {
+            self.recover();
+            return Err(err);
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+    /// Brings a port back after a failed command. Stopping the port
+    /// clears CI, then the errors are cleared and the port restarted.
+    /// A device that stays busy gets a COMRESET first.
+    fn recover(&self) {
+        stop(self.regs);
+        write(self.regs, PORT_SERR, 0xFFFFFFFF);
+        write(self.regs, PORT_IS, 0xFFFFFFFF);
+        TASK_FILE_ERRORS[self.controller].fetch_and(!(1 << self.port), Ordering::SeqCst);
+        if read(self.regs, PORT_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+            comreset(self.regs);
+        }
+        if !start(self.regs) {
+            klog!("[ahci] Port {} failed to restart", self.port);
+        }
+    }
+    /// Waits for slot 0 to complete. Under the 8259 PIC, the interrupt
+    /// handler acknowledges the port and wakes us up. Under the APIC, or
+    /// without a usable line, only the timer does and the port is polled.
+    fn wait(&self) -> Result<(), DeviceError> {
+        let deadline = pit::uptime() + AHCI_TIMEOUT_MS;
+        let mut polls = 0;
+        loop {
+            let errors = TASK_FILE_ERRORS[self.controller].load(Ordering::SeqCst);
+            let failed = errors & (1 << self.port) != 0 ||
+                         read(self.regs, PORT_IS) & PORT_IS_TFES != 0;
+            if failed {
+                return Err(DeviceError::Io);
+            }
+            if read(self.regs, PORT_CI) & 1 == 0 {
+                break;
+            }
+            if cpu::interrupts_enabled() {
+                if pit::uptime() > deadline {
+                    return Err(DeviceError::Io);
+                }
+                cpu::hlt();
+            } else {
+                polls += 1;
+                if polls > AHCI_POLL_LIMIT {
+                    return Err(DeviceError::Io);
+                }
+            }
+        }
+        if read(self.regs, PORT_TFD) & TFD_ERR != 0 {
+            return Err(DeviceError::Io);
+        }
+        Ok(())
+    }
+    /// Largest number of sectors moved by one command.
+    fn chunk_sectors() -> usize {
+        AHCI_BOUNCE_SIZE / AHCI_SECTOR_SIZE
+    }
+}
+
+/// Stops a port's command engine and FIS receive.
+fn stop(regs: usize) -> bool {
+    let cmd = read(regs, PORT_CMD);
+    write(regs, PORT_CMD, cmd & !(PORT_CMD_ST | PORT_CMD_FRE));
+    for _ in 0..AHCI_POLL_LIMIT {
+        if read(regs, PORT_CMD) & (PORT_CMD_CR | PORT_CMD_FR) == 0 {
+            return true;
+        }
+    }
+    false
+}
+
+/// Resets the link of a stopped port and waits for the device to come
+/// back.
+fn comreset(regs: usize) {
+    let sctl = read(regs, PORT_SCTL) & !PORT_SCTL_DET_MASK;
+    write(regs, PORT_SCTL, sctl | PORT_SCTL_DET_INIT);
+    // DET must stay set for at least 1 ms.
+    pit::sleep(2);
+    write(regs, PORT_SCTL, sctl);
+    for _ in 0..AHCI_POLL_LIMIT {
+        if read(regs, PORT_SSTS) & PORT_SSTS_DET_MASK == PORT_SSTS_DET_PRESENT {
+            break;
+        }
+    }
+    write(regs, PORT_SERR, 0xFFFFFFFF);
+}
+
+/// Starts a port's FIS receive and command engine.
+fn start(regs: usize) -> bool {
+    let cmd = read(regs, PORT_CMD);
+    write(regs, PORT_CMD, cmd | PORT_CMD_FRE);
+    for _ in 0..AHCI_POLL_LIMIT {
+        if read(regs, PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0 {
+            let cmd = read(regs, PORT_CMD);
+            write(regs, PORT_CMD, cmd | PORT_CMD_ST);
+            return true;
+        }
+    }
+    false
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_size(&self) -> usize {
+        AHCI_SECTOR_SIZE
+    }
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        let chunk = AhciDisk::chunk_sectors() * AHCI_SECTOR_SIZE;
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            let start = lba + (i * AhciDisk::chunk_sectors()) as u64;
+            let count = (part.len() / AHCI_SECTOR_SIZE) as u16;
+            self.run(ATA_CMD_READ_DMA_EXT, start, count, part.len(), false)?;
+            let bounce = unsafe { slice::from_raw_parts(self.bounce as *const u8, part.len()) };
+            part.copy_from_slice(bounce);
+        }
+        Ok(())
+    }
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        let chunk = AhciDisk::chunk_sectors() * AHCI_SECTOR_SIZE;
+        for (i, part) in buf.chunks(chunk).enumerate() {
+            let start = lba + (i * AhciDisk::chunk_sectors()) as u64;
+            let count = (part.len() / AHCI_SECTOR_SIZE) as u16;
+            let bounce = unsafe { slice::from_raw_parts_mut(self.bounce as *mut u8, part.len()) };
+            bounce.copy_from_slice(part);
+            self.run(ATA_CMD_WRITE_DMA_EXT, start, count, part.len(), true)?;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        self.run(ATA_CMD_FLUSH_CACHE_EXT, 0, 0, 0, false)
+    }
+}
+
+/// Interrupt handler, shared by all controllers.
+fn interrupt(_: u8) {
+    for index in 0..AHCI_MAX_CONTROLLERS {
+        let base = CONTROLLERS[index].load(Ordering::SeqCst);
+        if base == 0 {
+            continue;
+        }
+        let pending = read(base, HBA_IS);
+        for port in 0..AHCI_MAX_PORTS {
+            if pending & (1 << port) == 0 {
+                continue;
+            }
+            let regs = base + HBA_PORT_BASE + port * HBA_PORT_SIZE;
+            let status = read(regs, PORT_IS);
+            if status & PORT_IS_TFES != 0 {
+                TASK_FILE_ERRORS[index].fetch_or(1 << port, Ordering::SeqCst);
+            }
+            write(regs, PORT_IS, status);
+        }
+        write(base, HBA_IS, pending);
+    }
+}
+
+/// Sets up an AHCI controller and registers its disks.
+fn probe(dev: &PciDevice) -> bool {
+    let base = match dev.bars[ABAR_INDEX].memory_address() {
+        Some(address) if address != 0 && address < 0x100000000 => address as usize,
+        _ => return false,
+    };
+    let slot = CONTROLLERS.iter().position(|slot| {
+        slot.compare_and_swap(0, base, Ordering::SeqCst) == 0
+    });
+    let index = match slot {
+        Some(index) => index,
+        None => return false,
+    };
+    dev.enable_bus_master();
+    let ghc = read(base, HBA_GHC);
+    write(base, HBA_GHC, ghc | HBA_GHC_AE);
+    let implemented = read(base, HBA_PI);
+    klog!("[ahci] Controller at {}, ABAR {:#x}, ports {:#x}",
+          dev.address,
+          base,
+          implemented);
+    for port in 0..AHCI_MAX_PORTS {
+        if implemented & (1 << port) == 0 {
+            continue;
+        }
+        let regs = base + HBA_PORT_BASE + port * HBA_PORT_SIZE;
+        let ssts = read(regs, PORT_SSTS);
+        if ssts & PORT_SSTS_DET_MASK != PORT_SSTS_DET_PRESENT ||
+           ssts & PORT_SSTS_IPM_MASK != PORT_SSTS_IPM_ACTIVE ||
+           read(regs, PORT_SIG) != PORT_SIG_ATA {
+            continue;
+        }
+        let disk = match AhciDisk::new(index, base, port) {
+            Some(disk) => disk,
+            None => {
+                klog!("[ahci] Port {} failed to start", port);
+                continue;
+            }
+        };
+        let number = NEXT_DISK.fetch_add(1, Ordering::SeqCst);
+        let name = match device_name(format_args!("ahci{}", number)) {
+            Some(name) => name,
+            None => continue,
+        };
+        klog!("[ahci] {}: port {}, {} ({} MiB)",
+              name,
+              port,
+              disk.model(),
+              disk.sectors * AHCI_SECTOR_SIZE as u64 / (1024 * 1024));
+        if let Err(err) = block::register(disk, name) {
+            klog!("[ahci] Unable to register {}: {:?}", name, err);
+        }
+    }
+    // The PCI interrupt line is only an ISA IRQ number while the 8259 PIC
+    // is in charge; under the APIC the pin is routed elsewhere.
+    let line = dev.interrupt_line;
+    if apic::is_enabled() {
+        klog!("[ahci] No legacy IRQ routing under the APIC, polling for completion");
+        return true;
+    }
+    // Controllers on the same line share the handler.
+    let shared = line < 16 && IRQ_LINES.load(Ordering::SeqCst) & (1 << line) != 0;
+    if shared || irq::register(line, interrupt).is_ok() {
+        IRQ_LINES.fetch_or(1 << line, Ordering::SeqCst);
+        irq::enable(line);
+        let ghc = read(base, HBA_GHC);
+        write(base, HBA_GHC, ghc | HBA_GHC_IE);
+    } else {
+        klog!("[ahci] IRQ {} unavailable, polling for completion", line);
+    }
+    true
+}
+
+/// Registers the AHCI driver with the PCI subsystem. Returns the
+/// number of controllers.
+pub fn init() -> usize {
+    pci::register_driver(&AHCI_DRIVER)
+}
@@ -0,0 +1,460 @@
+#![allow(dead_code)]
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use acpi::{self, MadtEntry};
+use cpu;
+use idt::{self, IRQ_BASE, IRQ_COUNT, LAPIC_TIMER_VECTOR};
+use pic::PIC;
+use pit;
+
+// MSRs
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0xFFFFF000;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// CPUID
+const CPUID_FEATURES: u32 = 0x01;
+const CPUID_EDX_APIC: u32 = 1 << 9;
+const CPUID_ECX_X2APIC: u32 = 1 << 21;
+
+// Local APIC registers
+const LAPIC_ID: u32 = 0x020;
+const LAPIC_VERSION: u32 = 0x030;
+const LAPIC_TPR: u32 = 0x080;
+const LAPIC_EOI: u32 = 0x0B0;
+const LAPIC_SVR: u32 = 0x0F0;
+const LAPIC_ESR: u32 = 0x280;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_LVT_LINT0: u32 = 0x350;
+const LAPIC_LVT_LINT1: u32 = 0x360;
+const LAPIC_LVT_ERROR: u32 = 0x370;
+const LAPIC_TIMER_INITIAL: u32 = 0x380;
+const LAPIC_TIMER_CURRENT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+// Local APIC values
+const LAPIC_SVR_ENABLE: u32 = 0x100;
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+const LAPIC_LVT_NMI: u32 = 0x400;
+const LAPIC_LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LAPIC_LVT_LEVEL: u32 = 1 << 15;
+const LAPIC_TIMER_DIVIDE_16: u32 = 0x03;
+pub const SPURIOUS_VECTOR: usize = 0xFF;
+
+// Timer calibration
+const CALIBRATION_MS: u32 = 10;
+
+// I/O APIC registers
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+// I/O APIC redirection entry
+const IOAPIC_ACTIVE_LOW: u64 = 1 << 13;
+const IOAPIC_LEVEL: u64 = 1 << 15;
+const IOAPIC_MASKED: u64 = 1 << 16;
+const IOAPIC_DEST_SHIFT: u64 = 56;
+
+// MPS INTI flags
+const MPS_POLARITY_MASK: u16 = 0x03;
+const MPS_POLARITY_LOW: u16 = 0x03;
+const MPS_TRIGGER_MASK: u16 = 0x0C;
+const MPS_TRIGGER_LEVEL: u16 = 0x0C;
+
+// Limits
+const MAX_IO_APICS: usize = 8;
+const ALL_PROCESSORS: u8 = 0xFF;
+
+extern "C" {
+    /// Spurious interrupt stub, defined in `asm/interrupts.asm`.
+    fn isr_spurious();
+}
+
+/// Local APIC access mode.
+#[derive(Copy, Clone, PartialEq)]
+pub enum LapicMode {
+    XApic(usize),
+    X2Apic,
+}
+
+/// Local APIC.
+pub struct LocalApic {
+    mode: LapicMode,
+}
+
+/// I/O APIC.
+#[derive(Copy, Clone)]
+pub struct IoApic {
+    id: u8,
+    base: usize,
+    gsi_base: u32,
+    entries: u32,
+}
+
+/// Routing of a legacy ISA IRQ to a global system interrupt.
+#[derive(Copy, Clone)]
+struct IsaRoute {
+    gsi: u32,
+    flags: u64,
+    present: bool,
+}
+
+/// I/O APIC routing state.
+struct Routing {
+    ioapics: [Option<IoApic>; MAX_IO_APICS],
+    isa: [IsaRoute; IRQ_COUNT],
+}
+
+/// Whether the APICs have replaced the 8259 PIC.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The local APIC of the bootstrap processor.
+static LAPIC: Once<LocalApic> = Once::new();
+
+/// I/O APIC routing, once initialized.
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+impl LocalApic {
+    /// Reads a local APIC register.
+    pub fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            match self.mode {
+                LapicMode::XApic(base) => read_volatile((base + reg as usize) as *const u32),
+                LapicMode::X2Apic => cpu::rdmsr(X2APIC_MSR_BASE + (reg >> 4)) as u32,
+            }
+        }
+    }
+    /// Writes a local APIC register.
+    pub fn write(&self, reg: u32, val: u32) {
+        unsafe {
+            match self.mode {
+                LapicMode::XApic(base) => write_volatile((base + reg as usize) as *mut u32, val),
+                LapicMode::X2Apic => cpu::wrmsr(X2APIC_MSR_BASE + (reg >> 4), val as u64),
+            }
+        }
+    }
+    /// Gets the access mode.
+    pub fn mode(&self) -> LapicMode {
+        self.mode
+    }
+    /// Gets the APIC id.
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            LapicMode::XApic(_) => self.read(LAPIC_ID) >> 24,
+            LapicMode::X2Apic => self.read(LAPIC_ID),
+        }
+    }
+    /// Signals the end of an interrupt.
+    #[inline]
+    pub fn eoi(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+    /// Measures the timer frequency (after the divider) in Hz against the PIT.
+    pub fn calibrate_timer(&self) -> u64 {
+        self.write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_16);
+        self.write(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED);
+        self.write(LAPIC_TIMER_INITIAL, 0xFFFFFFFF);
+        let elapsed = pit::calibrate(CALIBRATION_MS,
+                                     || (0xFFFFFFFF - self.read(LAPIC_TIMER_CURRENT)) as u64);
+        self.write(LAPIC_TIMER_INITIAL, 0);
+        elapsed * 1000 / CALIBRATION_MS as u64
+    }
+    /// Arms the timer to fire once after `count` timer ticks.
+    pub fn start_oneshot(&self, count: u32) {
+        self.write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_16);
+        self.write(LAPIC_LVT_TIMER, LAPIC_TIMER_VECTOR as u32);
+        self.write(LAPIC_TIMER_INITIAL, count);
+    }
+    /// Stops and masks the timer.
+    pub fn stop_timer(&self) {
+        self.write(LAPIC_TIMER_INITIAL, 0);
+        self.write(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED);
+    }
+    /// Configures the local vector table and enables the local APIC.
+    fn enable(&self, nmi_lint: Option<(u8, u16)>) {
+        self.write(LAPIC_TPR, 0);
+        self.write(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED);
+        self.write(LAPIC_LVT_LINT0, LAPIC_LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LAPIC_LVT_MASKED);
+        self.write(LAPIC_LVT_ERROR, LAPIC_LVT_MASKED);
+        if let Some((lint, flags)) = nmi_lint {
+            let mut lvt = LAPIC_LVT_NMI;
+            if flags & MPS_POLARITY_MASK == MPS_POLARITY_LOW {
+                lvt |= LAPIC_LVT_ACTIVE_LOW;
+            }
+            if flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL {
+                lvt |= LAPIC_LVT_LEVEL;
+            }
+            let reg = if lint == 0 { LAPIC_LVT_LINT0 } else { LAPIC_LVT_LINT1 };
+            self.write(reg, lvt);
+        }
+        // The ESR has to be written before it is read.
+        self.write(LAPIC_ESR, 0);
+        self.write(LAPIC_ESR, 0);
+        self.write(LAPIC_SVR, LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        self.eoi();
+    }
+}
+
+impl IoApic {
+    /// Reads an I/O APIC register.
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+        }
+    }
+    /// Writes an I/O APIC register.
+    fn write(&self, reg: u32, val: u32) {
+        unsafe {
+            write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, val);
+        }
+    }
+    /// Reads a redirection table entry.
+    fn read_entry(&self, index: u32) -> u64 {
+        let reg = IOAPIC_REDIRECTION + index * 2;
+        (self.read(reg + 1) as u64) << 32 | self.read(reg) as u64
+    }
+    /// Writes a redirection table entry.
+    fn write_entry(&self, index: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + index * 2;
+        // Keep the entry masked while it is half-written.
+        self.write(reg, IOAPIC_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+    /// Checks whether this I/O APIC handles the given GSI.
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+}
+
+impl IsaRoute {
+    /// Constructs an identity route with ISA defaults (edge, active high).
+    fn identity(irq: u32) -> Self {
+        IsaRoute {
+            gsi: irq,
+            flags: 0,
+            present: true,
+        }
+    }
+    /// Constructs a route from an interrupt source override.
+    fn from_override(gsi: u32, mps_flags: u16) -> Self {
+        let mut flags = 0;
+        if mps_flags & MPS_POLARITY_MASK == MPS_POLARITY_LOW {
+            flags |= IOAPIC_ACTIVE_LOW;
+        }
+        if mps_flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL {
+            flags |= IOAPIC_LEVEL;
+        }
+        IsaRoute {
+            gsi: gsi,
+            flags: flags,
+            present: true,
+        }
+    }
+}
+
+impl Routing {
+    /// Finds the I/O APIC pin for a legacy ISA IRQ.
+    fn lookup(&self, irq: u8) -> Option<(IoApic, u32)> {
+        let route = self.isa[irq as usize];
+        if !route.present {
+            return None;
+        }
+        for ioapic in self.ioapics.iter() {
+            if let Some(ioapic) = *ioapic {
+                if ioapic.handles(route.gsi) {
+                    return Some((ioapic, route.gsi - ioapic.gsi_base));
+                }
+            }
+        }
+        None
+    }
+    /// Sets or clears the mask bit of a legacy ISA IRQ.
+    fn set_masked(&self, irq: u8, masked: bool) {
+        if let Some((ioapic, pin)) = self.lookup(irq) {
+            let entry = ioapic.read_entry(pin);
+            ioapic.write_entry(pin,
+                               if masked {
+                                   entry | IOAPIC_MASKED
+                               } else {
+                                   entry & !IOAPIC_MASKED
+                               });
+        }
+    }
+}
+
+/// Switches from the 8259 PIC to the local and I/O APICs.
+///
+/// Returns `false` and leaves the PIC in charge if the CPU has no APIC
+/// or ACPI does not describe an I/O APIC.
+pub fn init() -> bool {
+    let features = cpu::cpuid(CPUID_FEATURES, 0);
+    if features.edx & CPUID_EDX_APIC == 0 {
+        klog!("[apic] No local APIC");
+        return false;
+    }
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => {
+            klog!("[apic] No MADT");
+            return false;
+        }
+    };
+    let mut lapic_address = madt.local_apic_address as usize;
+    let mut nmi_lint = None;
+    let mut routing = Routing {
+        ioapics: [None; MAX_IO_APICS],
+        isa: [IsaRoute::identity(0); IRQ_COUNT],
+    };
+    for irq in 0..IRQ_COUNT {
+        routing.isa[irq] = IsaRoute::identity(irq as u32);
+    }
+    let mut ioapic_count = 0;
+    let mut overridden: u32 = 0;
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic { id, address, gsi_base } => {
+                if ioapic_count < MAX_IO_APICS {
+                    let mut ioapic = IoApic {
+                        id: id,
+                        base: address as usize,
+                        gsi_base: gsi_base,
+                        entries: 0,
+                    };
+                    ioapic.entries = ((ioapic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+                    routing.ioapics[ioapic_count] = Some(ioapic);
+                    ioapic_count += 1;
+                }
+            }
+            MadtEntry::InterruptOverride { bus: 0, source, gsi, flags } => {
+                if (source as usize) < IRQ_COUNT {
+                    routing.isa[source as usize] = IsaRoute::from_override(gsi, flags);
+                    overridden |= 1 << source;
+                }
+            }
+            MadtEntry::LocalApicNmi { processor_id, flags, lint } => {
+                if processor_id == ALL_PROCESSORS || processor_id == 0 {
+                    nmi_lint = Some((lint, flags));
+                }
+            }
+            MadtEntry::LocalApicAddress(address) => lapic_address = address as usize,
+            _ => {}
+        }
+    }
+    if ioapic_count == 0 {
+        klog!("[apic] No I/O APIC");
+        return false;
+    }
+    // An identity-mapped IRQ loses its GSI if another IRQ was overridden onto it.
+    for irq in 0..IRQ_COUNT {
+        if overridden & (1 << irq) != 0 {
+            continue;
+        }
+        let gsi = routing.isa[irq].gsi;
+        for other in 0..IRQ_COUNT {
+            if other != irq && overridden & (1 << other) != 0 && routing.isa[other].gsi == gsi {
+                routing.isa[irq].present = false;
+            }
+        }
+    }
+
+    PIC::disable();
+    let lapic = LAPIC.call_once(|| enable_local_apic(features.ecx, lapic_address));
+    lapic.enable(nmi_lint);
+
+    let dest = (lapic.id() as u64 & 0xFF) << IOAPIC_DEST_SHIFT;
+    for irq in 0..IRQ_COUNT {
+        if let Some((ioapic, pin)) = routing.lookup(irq as u8) {
+            let route = routing.isa[irq];
+            let vector = (IRQ_BASE + irq) as u64;
+            ioapic.write_entry(pin, dest | IOAPIC_MASKED | route.flags | vector);
+        }
+    }
+    unsafe {
+        idt::set_gate(SPURIOUS_VECTOR, isr_spurious as usize);
+    }
+    *ROUTING.lock() = Some(routing);
+    ENABLED.store(true, Ordering::SeqCst);
+
+    klog!("[apic] Local APIC {} in {} mode, {} I/O APIC(s)",
+          lapic.id(),
+          match lapic.mode() {
+              LapicMode::XApic(_) => "xAPIC",
+              LapicMode::X2Apic => "x2APIC",
+          },
+          ioapic_count);
+    true
+}
+
+/// Enables the local APIC through the APIC base MSR, preferring x2APIC mode.
+fn enable_local_apic(cpuid_ecx: u32, address: usize) -> LocalApic {
+    unsafe {
+        let mut base = cpu::rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+        cpu::wrmsr(IA32_APIC_BASE, base);
+        if cpuid_ecx & CPUID_ECX_X2APIC != 0 {
+            base |= APIC_BASE_X2APIC;
+            cpu::wrmsr(IA32_APIC_BASE, base);
+            return LocalApic { mode: LapicMode::X2Apic };
+        }
+        let address = if address != 0 {
+            address
+        } else {
+            (base & APIC_BASE_ADDR_MASK) as usize
+        };
+        LocalApic { mode: LapicMode::XApic(address) }
+    }
+}
+
+/// Checks whether the APICs have replaced the 8259 PIC.
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Gets the local APIC, once initialized.
+pub fn local() -> Option<&'static LocalApic> {
+    LAPIC.try()
+}
+
+/// Signals the end of an interrupt to the local APIC.
+#[inline]
+pub fn eoi() {
+    if let Some(lapic) = LAPIC.try() {
+        lapic.eoi();
+    }
+}
+
+/// Gets the global system interrupt a legacy ISA IRQ is routed to.
+pub fn isa_gsi(irq: u8) -> Option<u32> {
+    if irq as usize >= IRQ_COUNT {
+        return None;
+    }
+    match *ROUTING.lock() {
+        Some(ref routing) if routing.isa[irq as usize].present => {
+            Some(routing.isa[irq as usize].gsi)
+        }
+        _ => None,
+    }
+}
+
+/// Masks a legacy ISA IRQ at the I/O APIC.
+pub fn mask_isa(irq: u8) {
+    if let Some(ref routing) = *ROUTING.lock() {
+        routing.set_masked(irq, true);
+    }
+}
+
+/// Unmasks a legacy ISA IRQ at the I/O APIC.
+pub fn unmask_isa(irq: u8) {
+    if let Some(ref routing) = *ROUTING.lock() {
+        routing.set_masked(irq, false);
+    }
+}
@@ -54,10 +54,23 @@ setup_paging:
     mov dword [page_tables.%2], eax
     %endmacro
     makelink p3, p4
-    makelink p2, p3
     mov ecx, 0
 ;
-; Maps the p2 table.
+; Links the four p2 tables into the p3 table.
+;
+.link_p2:
+    mov eax, 0x1000
+    mul ecx
+    add eax, page_tables.p2
+    or eax, 0x03
+    mov [page_tables.p3 + ecx * 8], eax
+    inc ecx
+    cmp ecx, 4
+    jne .link_p2
+    mov ecx, 0
+;
+; Identity maps the first 4 GiB with 2 MiB pages,
+; so that MMIO (LAPIC, I/O APIC, HPET) below 4 GiB is reachable.
 ;
 .map:
     mov eax, 0x200000
@@ -65,7 +78,7 @@ setup_paging:
     or eax, 0x83
     mov [page_tables.p2 + ecx * 8], eax
     inc ecx
-    cmp ecx, 512
+    cmp ecx, 2048
     jne .map
 ;
 ; Loads the p4 table into cr3.
@@ -179,7 +192,7 @@ page_tables:
 .p3:
     resb 4096
 .p2:
-    resb 4096
+    resb 4096 * 4
 stack:
 .bottom:
     resb 4096
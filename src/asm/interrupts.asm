@@ -0,0 +1,145 @@
+section .text
+extern interrupt_dispatch
+bits 64
+
+;
+; Stub for vectors where the CPU does not push an error code.
+;
+%macro isr_noerr 1
+isr%1:
+    push 0
+    push %1
+    jmp isr_common
+%endmacro
+
+;
+; Stub for vectors where the CPU pushes an error code.
+;
+%macro isr_err 1
+isr%1:
+    push %1
+    jmp isr_common
+%endmacro
+
+;
+; CPU exceptions.
+;
+isr_noerr 0
+isr_noerr 1
+isr_noerr 2
+isr_noerr 3
+isr_noerr 4
+isr_noerr 5
+isr_noerr 6
+isr_noerr 7
+isr_err   8
+isr_noerr 9
+isr_err   10
+isr_err   11
+isr_err   12
+isr_err   13
+isr_err   14
+isr_noerr 15
+isr_noerr 16
+isr_err   17
+isr_noerr 18
+isr_noerr 19
+isr_noerr 20
+isr_err   21
+isr_noerr 22
+isr_noerr 23
+isr_noerr 24
+isr_noerr 25
+isr_noerr 26
+isr_noerr 27
+isr_noerr 28
+isr_err   29
+isr_err   30
+isr_noerr 31
+
+;
+; Hardware IRQs (remapped to 0x20-0x2F).
+;
+isr_noerr 32
+isr_noerr 33
+isr_noerr 34
+isr_noerr 35
+isr_noerr 36
+isr_noerr 37
+isr_noerr 38
+isr_noerr 39
+isr_noerr 40
+isr_noerr 41
+isr_noerr 42
+isr_noerr 43
+isr_noerr 44
+isr_noerr 45
+isr_noerr 46
+isr_noerr 47
+
+;
+; Local APIC timer.
+;
+isr_noerr 48
+
+;
+; Local APIC spurious interrupts need neither handling nor an EOI.
+;
+global isr_spurious
+isr_spurious:
+    iretq
+
+;
+; Saves the general purpose registers and hands the frame to Rust.
+;
+isr_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call interrupt_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    ;
+    ; Drops the vector and the error code.
+    ;
+    add rsp, 16
+    iretq
+
+section .rodata
+;
+; Addresses of all interrupt stubs, indexed by vector.
+;
+global isr_table
+isr_table:
+%assign i 0
+%rep 49
+    dq isr %+ i
+%assign i i + 1
+%endrep
@@ -0,0 +1,394 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use cpuio::{inb, inw, outb, outw};
+use block::{self, BlockDevice};
+use device::*;
+use cpu;
+use irq;
+use pit;
+
+// Channels
+const ATA_PRIMARY_IO: u16 = 0x1F0;
+const ATA_PRIMARY_CONTROL: u16 = 0x3F6;
+const ATA_PRIMARY_IRQ: u8 = 14;
+const ATA_SECONDARY_IO: u16 = 0x170;
+const ATA_SECONDARY_CONTROL: u16 = 0x376;
+const ATA_SECONDARY_IRQ: u8 = 15;
+
+macro_rules! ata_data { ($io:expr) => ($io + 0x00); }
+macro_rules! ata_error { ($io:expr) => ($io + 0x01); }
+macro_rules! ata_sector_count { ($io:expr) => ($io + 0x02); }
+macro_rules! ata_lba_low { ($io:expr) => ($io + 0x03); }
+macro_rules! ata_lba_mid { ($io:expr) => ($io + 0x04); }
+macro_rules! ata_lba_high { ($io:expr) => ($io + 0x05); }
+macro_rules! ata_drive { ($io:expr) => ($io + 0x06); }
+macro_rules! ata_status { ($io:expr) => ($io + 0x07); }
+macro_rules! ata_command { ($io:expr) => ($io + 0x07); }
+
+// Status
+const ATA_SR_ERR: u8 = 0x01;
+const ATA_SR_DRQ: u8 = 0x08;
+const ATA_SR_DF: u8 = 0x20;
+const ATA_SR_BSY: u8 = 0x80;
+
+// Device control
+const ATA_CTL_NIEN: u8 = 0x02;
+
+// Drive select
+const ATA_DRIVE_BASE: u8 = 0xA0;
+const ATA_DRIVE_LBA: u8 = 0x40;
+const ATA_DRIVE_SLAVE: u8 = 0x10;
+
+// Commands
+const ATA_CMD_READ_PIO: u8 = 0x20;
+const ATA_CMD_READ_PIO_EXT: u8 = 0x24;
+const ATA_CMD_WRITE_PIO: u8 = 0x30;
+const ATA_CMD_WRITE_PIO_EXT: u8 = 0x34;
+const ATA_CMD_CACHE_FLUSH: u8 = 0xE7;
+const ATA_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+// IDENTIFY words
+const ATA_IDENT_MODEL: usize = 27;
+const ATA_IDENT_MODEL_WORDS: usize = 20;
+const ATA_IDENT_LBA28_SECTORS: usize = 60;
+const ATA_IDENT_COMMAND_SETS: usize = 83;
+const ATA_IDENT_LBA48_SECTORS: usize = 100;
+const ATA_IDENT_LBA48_SUPPORTED: u16 = 0x0400;
+
+// Limits
+pub const ATA_SECTOR_SIZE: usize = 512;
+const ATA_LBA28_MAX: u64 = 0x0FFFFFFF;
+const ATA_MAX_SECTORS_PER_COMMAND: u64 = 256;
+const ATA_TIMEOUT_MS: u64 = 1000;
+const ATA_POLL_LIMIT: usize = 1000000;
+
+/// IDE channel.
+struct Channel {
+    io: u16,
+    control: u16,
+    irq: u8,
+}
+
+static CHANNELS: [Channel; 2] = [Channel {
+                                     io: ATA_PRIMARY_IO,
+                                     control: ATA_PRIMARY_CONTROL,
+                                     irq: ATA_PRIMARY_IRQ,
+                                 },
+                                 Channel {
+                                     io: ATA_SECONDARY_IO,
+                                     control: ATA_SECONDARY_CONTROL,
+                                     irq: ATA_SECONDARY_IRQ,
+                                 }];
+
+/// Serializes commands to both drives of a channel.
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+
+/// Whether completion is signalled through IRQ14/15.
+static CHANNEL_IRQS: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// Set by IRQ14/15 when a channel completes a command.
+static CHANNEL_PENDING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// ATA disk attached to an IDE channel.
+pub struct AtaDisk {
+    channel: usize,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    model: [u8; ATA_IDENT_MODEL_WORDS * 2],
+}
+
+impl Channel {
+    /// Waits about 400ns by reading the alternate status register.
+    fn delay(&self) {
+        for _ in 0..4 {
+            unsafe {
+                inb(self.control);
+            }
+        }
+    }
+    /// Waits until the channel is not busy. Returns the status.
+    fn wait_not_busy(&self) -> Result<u8, DeviceError> {
+        for _ in 0..ATA_POLL_LIMIT {
+            let status = unsafe { inb(self.control) };
+            if status & ATA_SR_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(DeviceError::Io)
+    }
+    /// Waits until the drive is ready to transfer data.
+    fn wait_data(&self) -> Result<(), DeviceError> {
+        let status = self.wait_not_busy()?;
+        if status & (ATA_SR_ERR | ATA_SR_DF) != 0 || status & ATA_SR_DRQ == 0 {
+            return Err(DeviceError::Io);
+        }
+        Ok(())
+    }
+    /// Selects a drive and loads the task file.
+    fn select(&self, slave: bool, lba: u64, count: u16, lba48: bool) {
+        let slave_bit = if slave { ATA_DRIVE_SLAVE } else { 0 };
+        unsafe {
+            if lba48 {
+                outb(ATA_DRIVE_BASE | ATA_DRIVE_LBA | slave_bit, ata_drive!(self.io));
+                self.delay();
+                outb((count >> 8) as u8, ata_sector_count!(self.io));
+                outb((lba >> 24) as u8, ata_lba_low!(self.io));
+                outb((lba >> 32) as u8, ata_lba_mid!(self.io));
+                outb((lba >> 40) as u8, ata_lba_high!(self.io));
+            } else {
+                outb(ATA_DRIVE_BASE | ATA_DRIVE_LBA | slave_bit | ((lba >> 24) as u8 & 0x0F),
+                     ata_drive!(self.io));
+                self.delay();
+            }
+            outb(count as u8, ata_sector_count!(self.io));
+            outb(lba as u8, ata_lba_low!(self.io));
+            outb((lba >> 8) as u8, ata_lba_mid!(self.io));
+            outb((lba >> 16) as u8, ata_lba_high!(self.io));
+        }
+    }
+}
+
+/// Issues a command, arming the completion flag first.
+fn issue(index: usize, command: u8) {
+    CHANNEL_PENDING[index].store(false, Ordering::SeqCst);
+    unsafe {
+        outb(command, ata_command!(CHANNELS[index].io));
+    }
+}
+
+/// Waits for a channel to complete a command, through IRQ14/15 when
+/// available and by polling otherwise.
+fn wait_completion(index: usize) -> Result<u8, DeviceError> {
+    let channel = &CHANNELS[index];
+    if CHANNEL_IRQS[index].load(Ordering::SeqCst) && cpu::interrupts_enabled() {
+        let deadline = pit::uptime() + ATA_TIMEOUT_MS;
+        while !CHANNEL_PENDING[index].swap(false, Ordering::SeqCst) {
+            if pit::uptime() > deadline {
+                return Err(DeviceError::Io);
+            }
+            cpu::hlt();
+        }
+    }
+    channel.delay();
+    let status = channel.wait_not_busy()?;
+    // Reading the status register acknowledges the interrupt.
+    let _ = unsafe { inb(ata_status!(channel.io)) };
+    if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+        return Err(DeviceError::Io);
+    }
+    Ok(status)
+}
+
+/// IRQ14/15 handler.
+fn interrupt(irq: u8) {
+    let index = if irq == ATA_PRIMARY_IRQ { 0 } else { 1 };
+    unsafe {
+        inb(ata_status!(CHANNELS[index].io));
+    }
+    CHANNEL_PENDING[index].store(true, Ordering::SeqCst);
+}
+
+/// Sends IDENTIFY to a drive. Returns `None` if there is no ATA drive.
+fn identify(index: usize, slave: bool) -> Option<AtaDisk> {
+    let channel = &CHANNELS[index];
+    let mut ident = [0u16; 256];
+    unsafe {
+        if inb(ata_status!(channel.io)) == 0xFF {
+            // Floating bus, no controller on this channel.
+            return None;
+        }
+        channel.select(slave, 0, 0, false);
+        outb(ATA_CMD_IDENTIFY, ata_command!(channel.io));
+        channel.delay();
+        if inb(ata_status!(channel.io)) == 0 {
+            return None;
+        }
+        if channel.wait_not_busy().is_err() {
+            return None;
+        }
+        // ATAPI and SATA devices put a signature into the LBA registers.
+        if inb(ata_lba_mid!(channel.io)) != 0 || inb(ata_lba_high!(channel.io)) != 0 {
+            return None;
+        }
+        if channel.wait_data().is_err() {
+            return None;
+        }
+        for word in ident.iter_mut() {
+            *word = inw(ata_data!(channel.io));
+        }
+    }
+    let lba48 = ident[ATA_IDENT_COMMAND_SETS] & ATA_IDENT_LBA48_SUPPORTED != 0;
+    let sectors = if lba48 {
+        (ident[ATA_IDENT_LBA48_SECTORS] as u64) |
+        (ident[ATA_IDENT_LBA48_SECTORS + 1] as u64) << 16 |
+        (ident[ATA_IDENT_LBA48_SECTORS + 2] as u64) << 32 |
+        (ident[ATA_IDENT_LBA48_SECTORS + 3] as u64) << 48
+    } else {
+        (ident[ATA_IDENT_LBA28_SECTORS] as u64) | (ident[ATA_IDENT_LBA28_SECTORS + 1] as u64) << 16
+    };
+    if sectors == 0 {
+        return None;
+    }
+    // The model string is stored as big-endian words.
+    let mut model = [0u8; ATA_IDENT_MODEL_WORDS * 2];
+    for i in 0..ATA_IDENT_MODEL_WORDS {
+        let word = ident[ATA_IDENT_MODEL + i];
+        model[i * 2] = (word >> 8) as u8;
+        model[i * 2 + 1] = word as u8;
+    }
+    Some(AtaDisk {
+        channel: index,
+        slave: slave,
+        lba48: lba48,
+        sectors: sectors,
+        model: model,
+    })
+}
+
+impl AtaDisk {
+    /// Gets the model string reported by IDENTIFY.
+    pub fn model(&self) -> &str {
+        let len = self.model.iter().rposition(|&c| c != b' ' && c != 0).map_or(0, |i| i + 1);
+        match ::core::str::from_utf8(&self.model[..len]) {
+            Ok(model) => model,
+            Err(_) => "",
+        }
+    }
+    /// Checks whether the range needs 48-bit addressing.
+    fn needs_lba48(&self, lba: u64, count: u64) -> Result<bool, DeviceError> {
+        if lba + count - 1 <= ATA_LBA28_MAX && count <= ATA_MAX_SECTORS_PER_COMMAND {
+            Ok(false)
+        } else if self.lba48 {
+            Ok(true)
+        } else {
+            Err(DeviceError::InvalidArgument)
+        }
+    }
+    /// Reads up to 256 sectors with a single command.
+    fn read_chunk(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let count = (buf.len() / ATA_SECTOR_SIZE) as u64;
+        let lba48 = self.needs_lba48(lba, count)?;
+        let channel = &CHANNELS[self.channel];
+        channel.wait_not_busy()?;
+        channel.select(self.slave, lba, count as u16, lba48);
+        issue(self.channel,
+              if lba48 { ATA_CMD_READ_PIO_EXT } else { ATA_CMD_READ_PIO });
+        for sector in buf.chunks_mut(ATA_SECTOR_SIZE) {
+            wait_completion(self.channel)?;
+            channel.wait_data()?;
+            for word in sector.chunks_mut(2) {
+                let data = unsafe { inw(ata_data!(channel.io)) };
+                word[0] = data as u8;
+                word[1] = (data >> 8) as u8;
+            }
+        }
+        Ok(())
+    }
+    /// Writes up to 256 sectors with a single command.
+    fn write_chunk(&self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        let count = (buf.len() / ATA_SECTOR_SIZE) as u64;
+        let lba48 = self.needs_lba48(lba, count)?;
+        let channel = &CHANNELS[self.channel];
+        channel.wait_not_busy()?;
+        channel.select(self.slave, lba, count as u16, lba48);
+        issue(self.channel,
+              if lba48 { ATA_CMD_WRITE_PIO_EXT } else { ATA_CMD_WRITE_PIO });
+        for sector in buf.chunks(ATA_SECTOR_SIZE) {
+            channel.delay();
+            channel.wait_data()?;
+            CHANNEL_PENDING[self.channel].store(false, Ordering::SeqCst);
+            for word in sector.chunks(2) {
+                unsafe {
+                    outw(word[0] as u16 | (word[1] as u16) << 8, ata_data!(channel.io));
+                }
+            }
+            wait_completion(self.channel)?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn sector_size(&self) -> usize {
+        ATA_SECTOR_SIZE
+    }
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let chunk = ATA_MAX_SECTORS_PER_COMMAND as usize * ATA_SECTOR_SIZE;
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            self.read_chunk(lba + (i * chunk / ATA_SECTOR_SIZE) as u64, part)?;
+        }
+        Ok(())
+    }
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let chunk = ATA_MAX_SECTORS_PER_COMMAND as usize * ATA_SECTOR_SIZE;
+        for (i, part) in buf.chunks(chunk).enumerate() {
+            self.write_chunk(lba + (i * chunk / ATA_SECTOR_SIZE) as u64, part)?;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let channel = &CHANNELS[self.channel];
+        channel.wait_not_busy()?;
+        channel.select(self.slave, 0, 0, false);
+        issue(self.channel,
+              if self.lba48 { ATA_CMD_CACHE_FLUSH_EXT } else { ATA_CMD_CACHE_FLUSH });
+        wait_completion(self.channel).map(|_| ())
+    }
+}
+
+/// Detects the drives on both IDE channels and registers them as
+/// `ata0`..`ata3`. Returns the number of disks.
+pub fn init() -> usize {
+    let mut count = 0;
+    for index in 0..CHANNELS.len() {
+        let channel = &CHANNELS[index];
+        // Keep the drives quiet while probing.
+        unsafe {
+            outb(ATA_CTL_NIEN, channel.control);
+        }
+        let mut found = false;
+        for &slave in [false, true].iter() {
+            let disk = match identify(index, slave) {
+                Some(disk) => disk,
+                None => continue,
+            };
+            let number = index * 2 + if slave { 1 } else { 0 };
+            let name = match device_name(format_args!("ata{}", number)) {
+                Some(name) => name,
+                None => continue,
+            };
+            klog!("[ata] {}: {} ({} MiB{})",
+                  name,
+                  disk.model(),
+                  disk.sectors * ATA_SECTOR_SIZE as u64 / (1024 * 1024),
+                  if disk.lba48 { ", LBA48" } else { "" });
+            match block::register(disk, name) {
+                Ok(_) => {
+                    found = true;
+                    count += 1;
+                }
+                Err(err) => klog!("[ata] Unable to register {}: {:?}", name, err),
+            }
+        }
+        if found && irq::register(channel.irq, interrupt).is_ok() {
+            CHANNEL_IRQS[index].store(true, Ordering::SeqCst);
+            irq::enable(channel.irq);
+            unsafe {
+                outb(0x00, channel.control);
+            }
+        }
+    }
+    count
+}
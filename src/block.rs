@@ -0,0 +1,65 @@
+#![allow(dead_code)]
+
+use spin::Mutex;
+use device::*;
+use heap;
+use ioctl::BlockGeometry;
+
+/// Provides sector-addressed access to storage.
+///
+/// Buffers passed to `read_sectors` and `write_sectors` must hold a
+/// whole number of sectors.
+pub trait BlockDevice {
+    /// Gets the sector size in bytes.
+    fn sector_size(&self) -> usize;
+    /// Gets the number of sectors.
+    fn sector_count(&self) -> u64;
+    /// Reads the sectors starting at `lba` into `buf`.
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError>;
+    /// Writes `buf` to the sectors starting at `lba`.
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError>;
+    /// Commits any data held in volatile device caches.
+    fn flush(&mut self) -> Result<(), DeviceError>;
+    /// Gets the device and first sector this device is a window on, if
+    /// any. The buffer cache keeps such sectors with the backing device.
+    fn backing(&self) -> Option<(BlockHandle, u64)> {
+        None
+    }
+    /// Gets the device geometry.
+    fn geometry(&self) -> BlockGeometry {
+        BlockGeometry {
+            sector_size: self.sector_size(),
+            sector_count: self.sector_count(),
+        }
+    }
+}
+
+/// Validates an LBA range against a device. Returns the sector count.
+pub fn check_range<B: BlockDevice + ?Sized>(dev: &B,
+                                            lba: u64,
+                                            len: usize)
+                                            -> Result<u64, DeviceError> {
+    let size = dev.sector_size();
+    if size == 0 || len % size != 0 {
+        return Err(DeviceError::InvalidArgument);
+    }
+    let count = (len / size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= dev.sector_count() => Ok(count),
+        _ => Err(DeviceError::InvalidArgument),
+    }
+}
+
+/// Moves a block device onto the kernel heap and registers it.
+pub fn register<B>(disk: B, name: &'static str) -> Result<BlockHandle, RegistryError>
+    where B: BlockDevice + Send + 'static
+{
+    let dev: &'static Mutex<Device<'static, B>> =
+        match heap::leak(Mutex::new(Device::new(disk, DeviceKind::BlockDevice, name))) {
+            Some(dev) => dev,
+            None => return Err(RegistryError::OutOfMemory),
+        };
+    let info = dev.lock().info;
+    DeviceManager::register(DeviceHandle::new(info).with_block(dev))?;
+    Ok(dev)
+}
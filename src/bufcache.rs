@@ -0,0 +1,265 @@
+#![allow(dead_code)]
+
+use core::cmp;
+use spin::Mutex;
+use device::*;
+
+/// Number of cached sectors.
+pub const CACHE_ENTRIES: usize = 64;
+/// Largest cacheable sector size.
+pub const CACHE_SECTOR_SIZE: usize = 4096;
+
+/// Cached sector metadata.
+#[derive(Copy, Clone)]
+struct Entry {
+    dev: Option<BlockHandle>,
+    id: usize,
+    lba: u64,
+    size: usize,
+    dirty: bool,
+    used: u64,
+}
+
+/// LRU sector cache with write-back.
+struct Cache {
+    entries: [Entry; CACHE_ENTRIES],
+    data: [[u8; CACHE_SECTOR_SIZE]; CACHE_ENTRIES],
+    clock: u64,
+}
+
+const EMPTY_ENTRY: Entry = Entry {
+    dev: None,
+    id: 0,
+    lba: 0,
+    size: 0,
+    dirty: false,
+    used: 0,
+};
+
+/// Buffer cache shared by all block devices.
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    entries: [EMPTY_ENTRY; CACHE_ENTRIES],
+    data: [[0; CACHE_SECTOR_SIZE]; CACHE_ENTRIES],
+    clock: 0,
+});
+
+/// Cached device parameters.
+struct Target {
+    /// Device holding the cached sectors.
+    dev: BlockHandle,
+    id: usize,
+    size: usize,
+    /// Byte offset of the accessed device on `dev`.
+    base: u64,
+    /// Size of the accessed device in bytes.
+    end: u64,
+}
+
+/// Follows partitions down to the device holding their sectors.
+/// Returns it with the sector offset.
+fn backing(mut dev: BlockHandle) -> (BlockHandle, u64) {
+    let mut start = 0;
+    loop {
+        let backing = dev.lock().proto.backing();
+        match backing {
+            Some((parent, offset)) => {
+                dev = parent;
+                start += offset;
+            }
+            None => return (dev, start),
+        }
+    }
+}
+
+impl Target {
+    /// Reads the parameters of a device.
+    fn new(dev: BlockHandle) -> Result<Self, DeviceError> {
+        let (size, count) = {
+            let dev = dev.lock();
+            (dev.proto.sector_size(), dev.proto.sector_count())
+        };
+        if size == 0 || size > CACHE_SECTOR_SIZE {
+            return Err(DeviceError::Unsupported);
+        }
+        let (dev, start) = backing(dev);
+        let id = dev.lock().info.id();
+        Ok(Target {
+            dev: dev,
+            id: id,
+            size: size,
+            base: start * size as u64,
+            end: count * size as u64,
+        })
+    }
+    /// Checks that a byte range lies on the device.
+    fn check(&self, offset: u64, len: usize) -> Result<(), DeviceError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.end => Ok(()),
+            _ => Err(DeviceError::InvalidArgument),
+        }
+    }
+}
+
+impl Cache {
+    /// Finds the entry holding a sector.
+    fn lookup(&self, id: usize, lba: u64) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.dev.is_some() && entry.id == id && entry.lba == lba)
+    }
+    /// Writes an entry back to its device if it is dirty.
+    fn write_back(&mut self, index: usize) -> Result<(), DeviceError> {
+        let entry = self.entries[index];
+        if let (true, Some(dev)) = (entry.dirty, entry.dev) {
+            dev.lock().proto.write_sectors(entry.lba, &self.data[index][..entry.size])?;
+            self.entries[index].dirty = false;
+        }
+        Ok(())
+    }
+    /// Frees an entry, preferring unused ones over the least recently used.
+    ///
+    /// A dirty entry that cannot be written back is dropped, as it would
+    /// otherwise stay the eviction candidate and fail every later miss.
+    fn evict(&mut self) -> usize {
+        let index = match self.entries.iter().position(|entry| entry.dev.is_none()) {
+            Some(index) => index,
+            None => {
+                let mut lru = 0;
+                for (index, entry) in self.entries.iter().enumerate() {
+                    if entry.used < self.entries[lru].used {
+                        lru = index;
+                    }
+                }
+                lru
+            }
+        };
+        if let Err(err) = self.write_back(index) {
+            let entry = self.entries[index];
+            klog!("[bufcache] Dropping sector {} of device {}: {:?}", entry.lba, entry.id, err);
+        }
+        self.entries[index] = EMPTY_ENTRY;
+        index
+    }
+    /// Gets the entry for a sector, reading it in if `fill` is set.
+    fn get(&mut self, target: &Target, lba: u64, fill: bool) -> Result<usize, DeviceError> {
+        self.clock += 1;
+        let index = match self.lookup(target.id, lba) {
+            Some(index) => index,
+            None => {
+                let index = self.evict();
+                if fill {
+                    let data = &mut self.data[index][..target.size];
+                    target.dev.lock().proto.read_sectors(lba, data)?;
+                }
+                self.entries[index] = Entry {
+                    dev: Some(target.dev),
+                    id: target.id,
+                    lba: lba,
+                    size: target.size,
+                    dirty: false,
+                    used: 0,
+                };
+                index
+            }
+        };
+        self.entries[index].used = self.clock;
+        Ok(index)
+    }
+    /// Writes back all dirty entries of a device.
+    fn sync(&mut self, id: usize) -> Result<(), DeviceError> {
+        for index in 0..CACHE_ENTRIES {
+            if self.entries[index].dev.is_some() && self.entries[index].id == id {
+                self.write_back(index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads bytes at a byte offset through the cache.
+pub fn read_at(dev: BlockHandle, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+    let target = Target::new(dev)?;
+    target.check(offset, buf.len())?;
+    let mut cache = CACHE.lock();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = target.base + offset + done as u64;
+        let skip = (pos % target.size as u64) as usize;
+        let len = cmp::min(target.size - skip, buf.len() - done);
+        let index = cache.get(&target, pos / target.size as u64, true)?;
+        buf[done..done + len].copy_from_slice(&cache.data[index][skip..skip + len]);
+        done += len;
+    }
+    Ok(())
+}
+
+/// Writes bytes at a byte offset through the cache.
+///
+/// The data reaches the device on eviction, `sync` or `sync_all`.
+pub fn write_at(dev: BlockHandle, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+    let target = Target::new(dev)?;
+    target.check(offset, buf.len())?;
+    let mut cache = CACHE.lock();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = target.base + offset + done as u64;
+        let skip = (pos % target.size as u64) as usize;
+        let len = cmp::min(target.size - skip, buf.len() - done);
+        // Whole sectors are overwritten, so there is no need to read them in.
+        let fill = len != target.size;
+        let index = cache.get(&target, pos / target.size as u64, fill)?;
+        cache.data[index][skip..skip + len].copy_from_slice(&buf[done..done + len]);
+        cache.entries[index].dirty = true;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Reads whole sectors through the cache.
+pub fn read(dev: BlockHandle, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+    let size = dev.lock().proto.sector_size() as u64;
+    read_at(dev, lba * size, buf)
+}
+
+/// Writes whole sectors through the cache.
+pub fn write(dev: BlockHandle, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+    let size = dev.lock().proto.sector_size() as u64;
+    write_at(dev, lba * size, buf)
+}
+
+/// Writes back the dirty sectors of a device, then flushes it.
+///
+/// Partitions sync their whole backing device.
+pub fn sync(dev: BlockHandle) -> Result<(), DeviceError> {
+    let (dev, _) = backing(dev);
+    let id = dev.lock().info.id();
+    CACHE.lock().sync(id)?;
+    dev.lock().proto.flush()
+}
+
+/// Writes back all dirty sectors.
+pub fn sync_all() -> Result<(), DeviceError> {
+    let mut cache = CACHE.lock();
+    for index in 0..CACHE_ENTRIES {
+        cache.write_back(index)?;
+    }
+    Ok(())
+}
+
+/// Writes back and drops the cached sectors of a device.
+///
+/// The sectors are dropped even if writing them back fails, in which
+/// case the first error is returned.
+pub fn invalidate(id: usize) -> Result<(), DeviceError> {
+    let mut cache = CACHE.lock();
+    let mut res = Ok(());
+    for index in 0..CACHE_ENTRIES {
+        if cache.entries[index].dev.is_some() && cache.entries[index].id == id {
+            if let Err(err) = cache.write_back(index) {
+                res = res.and(Err(err));
+            }
+            cache.entries[index] = EMPTY_ENTRY;
+        }
+    }
+    res
+}
@@ -0,0 +1,40 @@
+#![allow(dead_code)]
+
+/// Reads a little-endian `u16` at the given offset.
+#[inline]
+pub fn read_u16(buf: &[u8], off: usize) -> u16 {
+    buf[off] as u16 | (buf[off + 1] as u16) << 8
+}
+
+/// Reads a little-endian `u32` at the given offset.
+#[inline]
+pub fn read_u32(buf: &[u8], off: usize) -> u32 {
+    read_u16(buf, off) as u32 | (read_u16(buf, off + 2) as u32) << 16
+}
+
+/// Reads a little-endian `u64` at the given offset.
+#[inline]
+pub fn read_u64(buf: &[u8], off: usize) -> u64 {
+    read_u32(buf, off) as u64 | (read_u32(buf, off + 4) as u64) << 32
+}
+
+/// Writes a little-endian `u16` at the given offset.
+#[inline]
+pub fn write_u16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off] = val as u8;
+    buf[off + 1] = (val >> 8) as u8;
+}
+
+/// Writes a little-endian `u32` at the given offset.
+#[inline]
+pub fn write_u32(buf: &mut [u8], off: usize, val: u32) {
+    write_u16(buf, off, val as u16);
+    write_u16(buf, off + 2, (val >> 16) as u16);
+}
+
+/// Writes a little-endian `u64` at the given offset.
+#[inline]
+pub fn write_u64(buf: &mut [u8], off: usize, val: u64) {
+    write_u32(buf, off, val as u32);
+    write_u32(buf, off + 4, (val >> 32) as u32);
+}
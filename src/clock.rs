@@ -0,0 +1,203 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use apic;
+use cpu;
+use hpet;
+use pit;
+
+// General
+const NANOS_PER_SEC: u64 = 1000000000;
+const CALIBRATION_MS: u32 = 10;
+
+// CPUID
+const CPUID_EXTENDED_MAX: u32 = 0x80000000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x80000007;
+const CPUID_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Longest interval the LAPIC timer is armed for before re-arming.
+const MAX_ARM_NS: u64 = 10 * NANOS_PER_SEC;
+
+/// Clock source backing `now()`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClockSource {
+    Pit = 0,
+    Tsc = 1,
+    Hpet = 2,
+}
+
+/// Deadline callback, invoked from interrupt context.
+pub type DeadlineHandler = fn();
+
+/// Pending one-shot deadline.
+#[derive(Copy, Clone)]
+struct Deadline {
+    at: u64,
+    handler: DeadlineHandler,
+}
+
+/// Selected clock source, stored as its discriminant so that
+/// interrupt handlers can read it without locking.
+static SOURCE: AtomicUsize = AtomicUsize::new(ClockSource::Pit as usize);
+
+/// Calibrated TSC frequency in Hz.
+static TSC_HZ: AtomicUsize = AtomicUsize::new(0);
+
+/// TSC value at calibration, the zero point of `now()`.
+static TSC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the TSC runs at a constant rate across power states.
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// Calibrated LAPIC timer frequency in Hz, or 0 if unavailable.
+static LAPIC_TIMER_HZ: AtomicUsize = AtomicUsize::new(0);
+
+/// Pending deadline, if any.
+static DEADLINE: Mutex<Option<Deadline>> = Mutex::new(None);
+
+/// Calibrates the TSC (against the HPET if present, the PIT otherwise)
+/// and the LAPIC timer, and selects the best clock source.
+pub fn init() {
+    let invariant = tsc_invariant();
+    TSC_INVARIANT.store(invariant, Ordering::SeqCst);
+    let tsc_cycles = if hpet::is_present() {
+        hpet::calibrate(CALIBRATION_MS, cpu::rdtsc)
+    } else {
+        pit::calibrate(CALIBRATION_MS, cpu::rdtsc)
+    };
+    let tsc_hz = tsc_cycles * 1000 / CALIBRATION_MS as u64;
+    TSC_HZ.store(tsc_hz as usize, Ordering::SeqCst);
+    TSC_BASE.store(cpu::rdtsc() as usize, Ordering::SeqCst);
+    if let Some(lapic) = apic::local() {
+        LAPIC_TIMER_HZ.store(lapic.calibrate_timer() as usize, Ordering::SeqCst);
+    }
+    // An invariant TSC is the cheapest to read; a 64-bit HPET never
+    // wraps and does not drift with power states.
+    let source = if invariant && tsc_hz != 0 {
+        ClockSource::Tsc
+    } else if hpet::is_64bit() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    };
+    SOURCE.store(source as usize, Ordering::SeqCst);
+    klog!("[clock] TSC {} kHz (invariant: {}), LAPIC timer {} kHz, using {:?} clock source",
+          tsc_hz / 1000,
+          invariant,
+          LAPIC_TIMER_HZ.load(Ordering::SeqCst) / 1000,
+          source);
+}
+
+/// Checks CPUID for an invariant TSC.
+fn tsc_invariant() -> bool {
+    if cpu::cpuid(CPUID_EXTENDED_MAX, 0).eax < CPUID_POWER_MANAGEMENT {
+        return false;
+    }
+    cpu::cpuid(CPUID_POWER_MANAGEMENT, 0).edx & CPUID_EDX_INVARIANT_TSC != 0
+}
+
+/// Gets the selected clock source.
+pub fn source() -> ClockSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => ClockSource::Tsc,
+        2 => ClockSource::Hpet,
+        _ => ClockSource::Pit,
+    }
+}
+
+/// Gets the calibrated TSC frequency in Hz.
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed) as u64
+}
+
+/// Gets nanoseconds since calibration on the monotonic clock.
+pub fn now() -> u64 {
+    match source() {
+        ClockSource::Tsc => {
+            let hz = tsc_frequency();
+            let delta = cpu::rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed) as u64);
+            scale(delta, hz)
+        }
+        ClockSource::Hpet => hpet::nanos(),
+        ClockSource::Pit => scale(pit::ticks(), pit::frequency() as u64),
+    }
+}
+
+/// Converts `count` cycles of a `hz` clock to nanoseconds without overflow.
+fn scale(count: u64, hz: u64) -> u64 {
+    if hz == 0 {
+        return 0;
+    }
+    (count / hz) * NANOS_PER_SEC + (count % hz) * NANOS_PER_SEC / hz
+}
+
+/// Arms a one-shot deadline at `at` nanoseconds on the monotonic clock,
+/// replacing any pending one.
+pub fn set_deadline(at: u64, handler: DeadlineHandler) {
+    cpu::without_interrupts(|| {
+        *DEADLINE.lock() = Some(Deadline {
+            at: at,
+            handler: handler,
+        });
+        arm(at);
+    });
+}
+
+/// Cancels the pending deadline.
+pub fn cancel_deadline() {
+    cpu::without_interrupts(|| {
+        *DEADLINE.lock() = None;
+        if let Some(lapic) = apic::local() {
+            lapic.stop_timer();
+        }
+    });
+}
+
+/// Programs the LAPIC timer for a deadline, if it is calibrated.
+///
+/// Without a LAPIC timer, deadlines are polled on every PIT tick instead.
+fn arm(at: u64) {
+    let hz = LAPIC_TIMER_HZ.load(Ordering::Relaxed) as u64;
+    let lapic = match apic::local() {
+        Some(lapic) if hz != 0 => lapic,
+        _ => return,
+    };
+    let delta = at.saturating_sub(now());
+    let delta = if delta > MAX_ARM_NS { MAX_ARM_NS } else { delta };
+    let count = delta * hz / NANOS_PER_SEC;
+    let count = if count == 0 {
+        1
+    } else if count > 0xFFFFFFFF {
+        0xFFFFFFFF
+    } else {
+        count
+    };
+    lapic.start_oneshot(count as u32);
+}
+
+/// Fires the pending deadline if it has expired, re-arming it otherwise.
+fn expire() {
+    let deadline = match *DEADLINE.lock() {
+        Some(deadline) => deadline,
+        None => return,
+    };
+    if now() >= deadline.at {
+        *DEADLINE.lock() = None;
+        (deadline.handler)();
+    } else {
+        arm(deadline.at);
+    }
+}
+
+/// LAPIC timer interrupt handler.
+pub fn timer_interrupt() {
+    expire();
+}
+
+/// PIT tick hook, used when there is no LAPIC timer.
+pub fn tick() {
+    if LAPIC_TIMER_HZ.load(Ordering::Relaxed) == 0 {
+        expire();
+    }
+}
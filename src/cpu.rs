@@ -0,0 +1,142 @@
+#![allow(dead_code)]
+
+/// Disables maskable interrupts.
+#[inline(always)]
+pub unsafe fn cli() {
+    asm!("cli" :::: "volatile");
+}
+
+/// Enables maskable interrupts.
+#[inline(always)]
+pub unsafe fn sti() {
+    asm!("sti" :::: "volatile");
+}
+
+/// Interrupt enable flag in RFLAGS.
+const RFLAGS_IF: usize = 1 << 9;
+
+/// Reads the RFLAGS register.
+#[inline(always)]
+pub fn read_rflags() -> usize {
+    let value: usize;
+    unsafe {
+        asm!("pushfq; popq $0" : "=r"(value) :: "memory" : "volatile");
+    }
+    value
+}
+
+/// Checks whether maskable interrupts are enabled.
+#[inline(always)]
+pub fn interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_IF != 0
+}
+
+/// Runs `f` with maskable interrupts disabled, restoring the previous state.
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    let enabled = interrupts_enabled();
+    unsafe {
+        cli();
+    }
+    let result = f();
+    if enabled {
+        unsafe {
+            sti();
+        }
+    }
+    result
+}
+
+/// Halts the CPU until the next interrupt arrives.
+#[inline(always)]
+pub fn hlt() {
+    unsafe {
+        asm!("hlt" :::: "volatile");
+    }
+}
+
+/// Halts the CPU forever.
+pub fn halt_forever() -> ! {
+    loop {
+        unsafe {
+            cli();
+        }
+        hlt();
+    }
+}
+
+/// Loads the interrupt descriptor table register.
+#[inline(always)]
+pub unsafe fn lidt(ptr: usize) {
+    asm!("lidt ($0)" :: "r"(ptr) : "memory" : "volatile");
+}
+
+/// Reads the CR2 register (faulting address of the last page fault).
+#[inline(always)]
+pub fn read_cr2() -> usize {
+    let value: usize;
+    unsafe {
+        asm!("mov %cr2, $0" : "=r"(value) ::: "volatile");
+    }
+    value
+}
+
+/// Reads a model specific register.
+#[inline(always)]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+    (high as u64) << 32 | low as u64
+}
+
+/// Writes a model specific register.
+#[inline(always)]
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(low), "{edx}"(high) :: "volatile");
+}
+
+/// Result of a `cpuid` query.
+#[derive(Copy, Clone, Debug)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Executes `cpuid` for the given leaf and subleaf.
+#[inline(always)]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :: "volatile");
+    }
+    CpuidResult {
+        eax: eax,
+        ebx: ebx,
+        ecx: ecx,
+        edx: edx,
+    }
+}
+
+/// Reads the time stamp counter.
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile");
+    }
+    (high as u64) << 32 | low as u64
+}
@@ -0,0 +1,169 @@
+#![allow(dead_code)]
+
+use core::cmp;
+use spin::Mutex;
+use bufcache;
+use device::*;
+use heap;
+use ioctl::{Ioctl, IoctlReply};
+use vfs::{self, DirEntry, FileType, Inode, InodeRef, Stat, VfsError};
+
+/// Inode number of the devfs root. Device nodes follow it.
+const DEVFS_ROOT_INO: u64 = 1;
+
+/// Root directory, generated from the device registry.
+///
+/// Entries are looked up in the registry on every access, so nodes
+/// appear and disappear as devices register and unregister.
+struct DevFsDir;
+
+/// Node forwarding reads, writes and ioctls to a registered device.
+///
+/// The device is looked up by id on every access, so nodes of
+/// unregistered devices fail with `NotPresent`.
+struct DevNode {
+    id: usize,
+}
+
+static DEVFS_ROOT: DevFsDir = DevFsDir;
+
+/// Nodes handed out so far, reused across lookups.
+static NODES: Mutex<[Option<&'static DevNode>; MAX_DEVICES]> =
+    Mutex::new([None; MAX_DEVICES]);
+
+/// Gets the node of a device, creating it on first use.
+fn node(id: usize) -> Result<InodeRef, VfsError> {
+    let mut nodes = NODES.lock();
+    if let Some(node) = nodes.iter().filter_map(|node| *node).find(|node| node.id == id) {
+        return Ok(node);
+    }
+    // Slots of unregistered devices are recycled.
+    let slot = nodes.iter_mut().find(|node| {
+        node.map_or(true, |node| DeviceManager::get(node.id).is_none())
+    });
+    let slot = match slot {
+        Some(slot) => slot,
+        None => return Err(VfsError::OutOfMemory),
+    };
+    match heap::leak(DevNode { id: id }) {
+        Some(node) => {
+            *slot = Some(node);
+            Ok(node)
+        }
+        None => Err(VfsError::OutOfMemory),
+    }
+}
+
+/// Gets the file type of a registered device.
+fn file_type(dev: &DeviceHandle) -> FileType {
+    match dev.info().kind() {
+        DeviceKind::BlockDevice => FileType::BlockDevice,
+        DeviceKind::CharsDevice => FileType::CharDevice,
+    }
+}
+
+/// Gets the inode number of a registered device.
+fn ino(id: usize) -> u64 {
+    DEVFS_ROOT_INO + 1 + id as u64
+}
+
+impl Inode for DevFsDir {
+    fn stat(&self) -> Stat {
+        Stat {
+            ino: DEVFS_ROOT_INO,
+            kind: FileType::Directory,
+            mode: 0o755,
+            size: 0,
+            device: None,
+        }
+    }
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        match DeviceManager::find(name) {
+            Some(dev) => node(dev.info().id()),
+            None => Err(VfsError::NotFound),
+        }
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, VfsError> {
+        match DeviceManager::devices().nth(index) {
+            Some(dev) => {
+                DirEntry::new(ino(dev.info().id()), file_type(&dev), dev.info().name()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl DevNode {
+    /// Gets the registry entry of the device.
+    fn device(&self) -> Result<DeviceHandle, VfsError> {
+        DeviceManager::get(self.id).ok_or(VfsError::Device(DeviceError::NotPresent))
+    }
+}
+
+impl Inode for DevNode {
+    fn stat(&self) -> Stat {
+        let (kind, size) = match self.device() {
+            Ok(dev) => {
+                let size = dev.block().map_or(0, |disk| {
+                    let geometry = disk.lock().proto.geometry();
+                    geometry.sector_size as u64 * geometry.sector_count
+                });
+                (file_type(&dev), size)
+            }
+            Err(_) => (FileType::CharDevice, 0),
+        };
+        Stat {
+            ino: ino(self.id),
+            kind: kind,
+            mode: if kind == FileType::BlockDevice { 0o660 } else { 0o666 },
+            size: size,
+            device: Some(self.id),
+        }
+    }
+    /// Reads from a character device, ignoring the offset, or from a
+    /// block device through the buffer cache.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let dev = self.device()?;
+        if let Some(disk) = dev.block() {
+            let size = self.stat().size;
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = cmp::min(buf.len() as u64, size - offset) as usize;
+            bufcache::read_at(disk, offset, &mut buf[..len])?;
+            return Ok(len);
+        }
+        let dev = match dev.read() {
+            Some(dev) => dev,
+            None => return Err(VfsError::Unsupported),
+        };
+        read_blocking(dev, buf).map_err(VfsError::from)
+    }
+    /// Writes to a character device, ignoring the offset, or to a block
+    /// device through the buffer cache.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let dev = self.device()?;
+        if let Some(disk) = dev.block() {
+            let size = self.stat().size;
+            if offset >= size {
+                return if buf.is_empty() { Ok(0) } else { Err(VfsError::NoSpace) };
+            }
+            let len = cmp::min(buf.len() as u64, size - offset) as usize;
+            bufcache::write_at(disk, offset, &buf[..len])?;
+            return Ok(len);
+        }
+        let dev = match dev.write() {
+            Some(dev) => dev,
+            None => return Err(VfsError::Unsupported),
+        };
+        write_blocking(dev, buf).map_err(VfsError::from)
+    }
+    fn ioctl(&self, cmd: Ioctl) -> Result<IoctlReply, VfsError> {
+        self.device()?.run_ioctl(cmd).map_err(VfsError::from)
+    }
+}
+
+/// Mounts devfs at `/dev`.
+pub fn init() -> Result<(), VfsError> {
+    vfs::mount("/dev", &DEVFS_ROOT, "devfs")
+}
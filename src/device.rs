@@ -2,12 +2,17 @@
 
 use core::{self, fmt};
 use spin::Mutex;
+use block::BlockDevice;
+use bufcache;
+use ioctl::{BlockIoctl, Ioctl, IoctlClass, IoctlReply};
+use cpu;
+use heap;
 
 macro_rules! device_write {
     ($dev:expr $(,$arg:expr)*) => ({
         use core::fmt::Write;
         let mut writer = $dev.lock();
-        writer.write_fmt(format_args!($($arg,)*)).unwrap();
+        let _ = writer.write_fmt(format_args!($($arg,)*));
     });
 }
 
@@ -17,15 +22,21 @@ static mut NEXT_DEVICE_ID: Mutex<usize> = Mutex::new(0_usize);
 /// Thread-safe `device::Device<T>` wrapped in a `spin::Mutex`.
 pub type ThreadSafeDevice<T> = Mutex<Device<'static, T>>;
 
+/// Maximum number of registered devices.
+pub const MAX_DEVICES: usize = 32;
+
+/// Polls of a busy device before its lock is released.
+const DEVICE_SPIN_LIMIT: usize = 10000;
+
 /// Device kind.
-#[derive(Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum DeviceKind {
     BlockDevice = 0,
     CharsDevice = 1,
 }
 
 /// Device information.
-#[derive(Clone)]
+#[derive(Copy, Clone)]
 pub struct DeviceInfo<'a> {
     id: usize,
     name: &'a str,
@@ -33,29 +44,211 @@ pub struct DeviceInfo<'a> {
 }
 
 /// Device.
-pub struct Device<'a, P> {
-    pub proto: P,
+///
+/// `proto` comes last so that a `&Mutex<Device<T>>` can be unsized
+/// into a `&Mutex<Device<Trait>>` capability.
+pub struct Device<'a, P: ?Sized> {
     pub info: DeviceInfo<'a>,
+    pub proto: P,
+}
+
+/// Read capability of a registered device.
+pub type ReadHandle = &'static Mutex<Device<'static, DeviceRead + Send>>;
+
+/// Write capability of a registered device.
+pub type WriteHandle = &'static Mutex<Device<'static, DeviceWrite + Send>>;
+
+/// Ioctl capability of a registered device.
+pub type IoctlHandle = &'static Mutex<Device<'static, DeviceIoctl + Send>>;
+
+/// Block capability of a registered device.
+pub type BlockHandle = &'static Mutex<Device<'static, BlockDevice + Send>>;
+
+/// Registry entry, describing a device and its capabilities.
+#[derive(Copy, Clone)]
+pub struct DeviceHandle {
+    info: DeviceInfo<'static>,
+    read: Option<ReadHandle>,
+    write: Option<WriteHandle>,
+    ioctl: Option<IoctlHandle>,
+    block: Option<BlockHandle>,
+}
+
+/// Error raised by the device registry.
+#[derive(Debug)]
+pub enum RegistryError {
+    Full,
+    NameTaken,
+    OutOfMemory,
+}
+
+/// Device registry.
+struct Registry {
+    devices: [Option<DeviceHandle>; MAX_DEVICES],
+}
+
+/// Registered devices.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { devices: [None; MAX_DEVICES] });
+
+/// Error raised by device operations.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DeviceError {
+    NotPresent,
+    Unsupported,
+    InvalidArgument,
+    WouldBlock,
+    Io,
 }
 
 /// Device manager.
 pub struct DeviceManager;
 
+/// Iterator over registered devices.
+pub struct Devices {
+    index: usize,
+}
+
+/// Iterator over registered devices understanding a command class.
+pub struct Supporting {
+    devices: Devices,
+    class: IoctlClass,
+}
+
 /// Provides read functionality for devices.
 pub trait DeviceRead {
-    fn read_byte(&self, dev: &DeviceInfo) -> u8;
-    fn read_chunk(&self, dev: &DeviceInfo, buf: &[u8], size: usize);
+    /// Reads into `buf` without blocking.
+    ///
+    /// Fails with `WouldBlock` if no data is available.
+    fn read(&mut self, dev: &DeviceInfo, buf: &mut [u8]) -> Result<usize, DeviceError>;
 }
 
 /// Provides write functionality for devices.
 pub trait DeviceWrite {
-    fn write_byte(&mut self, dev: &DeviceInfo, b: u8);
+    /// Writes from `buf` without blocking.
+    ///
+    /// Fails with `WouldBlock` if the device cannot take any data.
+    fn write(&mut self, dev: &DeviceInfo, buf: &[u8]) -> Result<usize, DeviceError>;
+    /// Writes all of `buf`, spinning while the device is busy.
+    ///
+    /// This runs under the device lock, e.g. for `klog!`, so it never
+    /// halts. Fails with `WouldBlock` if the device stays busy.
+    fn write_all(&mut self, dev: &DeviceInfo, mut buf: &[u8]) -> Result<(), DeviceError> {
+        let mut polls = 0;
+        while !buf.is_empty() {
+            match self.write(dev, buf) {
+                Ok(0) => return Err(DeviceError::Io),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    polls = 0;
+                }
+                Err(DeviceError::WouldBlock) if polls < DEVICE_SPIN_LIMIT => polls += 1,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Longest generated device name.
+const DEVICE_NAME_MAX: usize = 16;
+
+/// Fixed-size buffer for formatting device names.
+struct NameBuffer {
+    buf: [u8; DEVICE_NAME_MAX],
+    len: usize,
+}
+
+impl fmt::Write for NameBuffer {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let end = self.len + string.len();
+        if end > DEVICE_NAME_MAX {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(string.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Formats a device name onto the kernel heap, e.g. `ram0`.
+pub fn device_name(args: fmt::Arguments) -> Option<&'static str> {
+    use core::fmt::Write;
+    let mut name = NameBuffer {
+        buf: [0; DEVICE_NAME_MAX],
+        len: 0,
+    };
+    if name.write_fmt(args).is_err() {
+        return None;
+    }
+    match core::str::from_utf8(&name.buf[..name.len]) {
+        Ok(name) => heap::leak_str(name),
+        Err(_) => None,
+    }
+}
+
+/// Waits for a device to make progress.
+pub fn wait_for_io() {
+    if cpu::interrupts_enabled() {
+        cpu::hlt();
+    }
+}
+
+/// Reads from a device, blocking until at least one byte is available.
+///
+/// The device is only locked while polling, with interrupts off, so
+/// that waiting doesn't hold up other users such as `klog!`.
+pub fn read_blocking(dev: ReadHandle, buf: &mut [u8]) -> Result<usize, DeviceError> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        let res = cpu::without_interrupts(|| {
+            let mut dev = dev.lock();
+            let info = dev.info;
+            dev.proto.read(&info, buf)
+        });
+        match res {
+            Err(DeviceError::WouldBlock) => wait_for_io(),
+            res => return res,
+        }
+    }
+}
+
+/// Writes to a device, blocking until at least one byte is taken.
+///
+/// A busy device is polled for a while under its lock, with interrupts
+/// off, then waited on with the lock released.
+pub fn write_blocking(dev: WriteHandle, buf: &[u8]) -> Result<usize, DeviceError> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        let res = cpu::without_interrupts(|| {
+            let mut dev = dev.lock();
+            let info = dev.info;
+            let mut res = Err(DeviceError::WouldBlock);
+            for _ in 0..DEVICE_SPIN_LIMIT {
+                res = dev.proto.write(&info, buf);
+                if let Err(DeviceError::WouldBlock) = res {
+                    continue;
+                }
+                break;
+            }
+            res
+        });
+        match res {
+            Err(DeviceError::WouldBlock) => wait_for_io(),
+            res => return res,
+        }
+    }
 }
 
 /// Provides ioctl functionality for devices.
 pub trait DeviceIoctl {
-    // TODO: Rethink this.
-    fn ioctl(&self, dev: &DeviceInfo, arg1: i32, arg2: i32, arg3: i32);
+    /// Gets the command classes the device understands.
+    fn ioctl_classes(&self) -> &'static [IoctlClass];
+    /// Runs a command.
+    fn ioctl(&mut self, dev: &DeviceInfo, cmd: Ioctl) -> Result<IoctlReply, DeviceError>;
 }
 
 impl<'a> DeviceInfo<'a> {
@@ -67,6 +260,18 @@ impl<'a> DeviceInfo<'a> {
             kind: kind,
         }
     }
+    /// Gets the device id.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    /// Gets the device name.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+    /// Gets the device kind.
+    pub fn kind(&self) -> DeviceKind {
+        self.kind
+    }
     /// Gets the next device id in a thread-safe manner.
     fn get_next_id_safe() -> usize {
         unsafe {
@@ -82,25 +287,204 @@ impl<'a, P> Device<'a, P> {
     /// Constructs a new `Device`.
     pub fn new(proto: P, kind: DeviceKind, name: &'a str) -> Device<P> {
         Device {
-            proto: proto,
             info: DeviceInfo::new(kind, name),
+            proto: proto,
+        }
+    }
+}
+
+impl DeviceHandle {
+    /// Constructs a new `DeviceHandle` without any capabilities.
+    pub fn new(info: DeviceInfo<'static>) -> Self {
+        DeviceHandle {
+            info: info,
+            read: None,
+            write: None,
+            ioctl: None,
+            block: None,
+        }
+    }
+    /// Adds the read capability.
+    pub fn with_read(mut self, dev: ReadHandle) -> Self {
+        self.read = Some(dev);
+        self
+    }
+    /// Adds the write capability.
+    pub fn with_write(mut self, dev: WriteHandle) -> Self {
+        self.write = Some(dev);
+        self
+    }
+    /// Adds the ioctl capability.
+    pub fn with_ioctl(mut self, dev: IoctlHandle) -> Self {
+        self.ioctl = Some(dev);
+        self
+    }
+    /// Adds the block capability.
+    pub fn with_block(mut self, dev: BlockHandle) -> Self {
+        self.block = Some(dev);
+        self
+    }
+    /// Gets the device information.
+    pub fn info(&self) -> &DeviceInfo<'static> {
+        &self.info
+    }
+    /// Gets the read capability.
+    pub fn read(&self) -> Option<ReadHandle> {
+        self.read
+    }
+    /// Gets the write capability.
+    pub fn write(&self) -> Option<WriteHandle> {
+        self.write
+    }
+    /// Gets the ioctl capability.
+    pub fn ioctl(&self) -> Option<IoctlHandle> {
+        self.ioctl
+    }
+    /// Gets the block capability.
+    pub fn block(&self) -> Option<BlockHandle> {
+        self.block
+    }
+    /// Checks whether the device understands a command class.
+    ///
+    /// Block commands are served by the block capability.
+    pub fn supports(&self, class: IoctlClass) -> bool {
+        if class == IoctlClass::Block && self.block.is_some() {
+            return true;
+        }
+        match self.ioctl {
+            Some(dev) => {
+                cpu::without_interrupts(|| dev.lock().proto.ioctl_classes().contains(&class))
+            }
+            None => false,
+        }
+    }
+    /// Runs a command on the device.
+    ///
+    /// The device is locked with interrupts off, as it may be shared with
+    /// interrupt handlers, e.g. serial0 with `klog!`.
+    pub fn run_ioctl(&self, cmd: Ioctl) -> Result<IoctlReply, DeviceError> {
+        if let (Ioctl::Block(cmd), Some(dev)) = (cmd, self.block) {
+            return match cmd {
+                BlockIoctl::GetGeometry => Ok(IoctlReply::Geometry(dev.lock().proto.geometry())),
+                BlockIoctl::Flush => bufcache::sync(dev).map(|_| IoctlReply::Done),
+            };
+        }
+        match self.ioctl {
+            Some(dev) => {
+                cpu::without_interrupts(|| {
+                    let mut dev = dev.lock();
+                    let info = dev.info;
+                    if !dev.proto.ioctl_classes().contains(&cmd.class()) {
+                        return Err(DeviceError::Unsupported);
+                    }
+                    dev.proto.ioctl(&info, cmd)
+                })
+            }
+            None => Err(DeviceError::Unsupported),
+        }
+    }
+}
+
+impl DeviceManager {
+    /// Registers a device. Returns its id.
+    pub fn register(handle: DeviceHandle) -> Result<usize, RegistryError> {
+        let mut registry = REGISTRY.lock();
+        if registry.devices.iter().any(|dev| match *dev {
+            Some(ref dev) => dev.info.name == handle.info.name,
+            None => false,
+        }) {
+            return Err(RegistryError::NameTaken);
+        }
+        match registry.devices.iter_mut().find(|dev| dev.is_none()) {
+            Some(slot) => {
+                *slot = Some(handle);
+                Ok(handle.info.id)
+            }
+            None => Err(RegistryError::Full),
+        }
+    }
+    /// Unregisters a device by id. Returns its entry.
+    ///
+    /// Cached sectors of block devices are written back and dropped.
+    pub fn unregister(id: usize) -> Option<DeviceHandle> {
+        let handle = {
+            let mut registry = REGISTRY.lock();
+            let found = registry.devices
+                .iter_mut()
+                .find(|slot| slot.map_or(false, |dev| dev.info.id == id));
+            match found {
+                Some(slot) => slot.take(),
+                None => None,
+            }
+        };
+        if let Some(DeviceHandle { info, block: Some(_), .. }) = handle {
+            if let Err(err) = bufcache::invalidate(id) {
+                klog!("[device] Lost cached sectors of {}: {:?}", info.name, err);
+            }
+        }
+        handle
+    }
+    /// Looks up a device by id.
+    pub fn get(id: usize) -> Option<DeviceHandle> {
+        DeviceManager::devices().find(|dev| dev.info.id == id)
+    }
+    /// Looks up a device by name.
+    pub fn find(name: &str) -> Option<DeviceHandle> {
+        DeviceManager::devices().find(|dev| dev.info.name == name)
+    }
+    /// Enumerates the registered devices.
+    ///
+    /// The registry is only locked while stepping, so devices may be
+    /// registered or unregistered during the iteration.
+    pub fn devices() -> Devices {
+        Devices { index: 0 }
+    }
+    /// Enumerates the devices understanding a command class.
+    pub fn supporting(class: IoctlClass) -> Supporting {
+        Supporting {
+            devices: DeviceManager::devices(),
+            class: class,
+        }
+    }
+    /// Gets the number of registered devices.
+    pub fn count() -> usize {
+        REGISTRY.lock().devices.iter().filter(|dev| dev.is_some()).count()
+    }
+}
+
+impl Iterator for Devices {
+    type Item = DeviceHandle;
+    fn next(&mut self) -> Option<DeviceHandle> {
+        let registry = REGISTRY.lock();
+        while self.index < MAX_DEVICES {
+            let dev = registry.devices[self.index];
+            self.index += 1;
+            if dev.is_some() {
+                return dev;
+            }
         }
+        None
+    }
+}
+
+impl Iterator for Supporting {
+    type Item = DeviceHandle;
+    fn next(&mut self) -> Option<DeviceHandle> {
+        let class = self.class;
+        self.devices.find(|dev| dev.supports(class))
     }
 }
 
-impl<'a, P> fmt::Write for Device<'a, P>
+impl<'a, P: ?Sized> fmt::Write for Device<'a, P>
     where P: DeviceWrite
 {
     fn write_str(&mut self, string: &str) -> fmt::Result {
-        let info: DeviceInfo = self.info.clone();
-        for b in string.bytes() {
-            DeviceWrite::write_byte(&mut self.proto, &info, b);
-        }
-        Ok(())
+        let info: DeviceInfo = self.info;
+        self.proto.write_all(&info, string.as_bytes()).map_err(|_| fmt::Error)
     }
 }
 
-impl<'a, P> core::ops::Deref for Device<'a, P> {
+impl<'a, P: ?Sized> core::ops::Deref for Device<'a, P> {
     type Target = P;
     fn deref(&self) -> &P {
         &self.proto
@@ -2,6 +2,7 @@
 
 use core;
 use rlibc;
+use spin::Mutex;
 
 const ALIGN: usize = 8;
 const GUARD1: u32 = 0x5EABFCD7;
@@ -19,16 +20,82 @@ pub struct Heap {
     used_top: *mut Block,
     free_top: *mut Block,
     free_addr: *mut u8,
+    end: usize,
+}
+
+unsafe impl Send for Heap {}
+
+/// Kernel heap.
+static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
+
+/// Sets up the kernel heap between the given addresses.
+/// Allocations that would reach past `end` fail.
+pub fn init(start: usize, end: usize) {
+    if end <= start {
+        klog!("[heap] No usable memory past {:#x}, every allocation will fail", start);
+    }
+    *HEAP.lock() = Some(Heap::new(start, end));
+}
+
+/// Allocates zeroed memory from the kernel heap.
+pub fn kalloc(size: usize) -> Option<*mut u8> {
+    match *HEAP.lock() {
+        Some(ref mut heap) => heap.kalloc(size),
+        None => None,
+    }
+}
+
+/// Allocates zeroed memory aligned to a power of two, e.g. for DMA.
+pub fn kalloc_aligned(size: usize, align: usize) -> Option<*mut u8> {
+    match *HEAP.lock() {
+        Some(ref mut heap) => heap.kalloc_aligned(size, align),
+        None => None,
+    }
+}
+
+/// Returns a chunk allocated by `kalloc` to the kernel heap.
+/// Returns `false` if the chunk wasn't allocated by `kalloc`.
+pub fn kfree(ptr: *mut u8) -> bool {
+    match *HEAP.lock() {
+        Some(ref mut heap) => heap.kfree(ptr),
+        None => false,
+    }
+}
+
+/// Moves a value onto the kernel heap for the rest of the kernel's life.
+pub fn leak<T>(value: T) -> Option<&'static mut T> {
+    let ptr = match kalloc(core::mem::size_of::<T>()) {
+        Some(ptr) => ptr as *mut T,
+        None => return None,
+    };
+    unsafe {
+        core::ptr::write(ptr, value);
+        ptr.as_mut()
+    }
+}
+
+/// Copies a string onto the kernel heap for the rest of the kernel's life.
+pub fn leak_str(string: &str) -> Option<&'static str> {
+    let ptr = match kalloc(string.len()) {
+        Some(ptr) => ptr,
+        None => return None,
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(string.as_ptr(), ptr, string.len());
+        let bytes = core::slice::from_raw_parts(ptr as *const u8, string.len());
+        Some(core::str::from_utf8_unchecked(bytes))
+    }
 }
 
 impl Heap {
-    pub fn new(end_of_kernel: usize) -> Self {
+    pub fn new(end_of_kernel: usize, end: usize) -> Self {
         let addr = Self::align(end_of_kernel) as *mut u8;
-        klog!("Heap pointer: {:p}", addr);
+        klog!("Heap pointer: {:p}, end: {:#x}", addr, end);
         Heap {
             used_top: core::ptr::null_mut(),
             free_top: core::ptr::null_mut(),
             free_addr: addr,
+            end: end,
         }
     }
     pub fn alloc<T>(&mut self) -> Option<&T> {
@@ -41,19 +108,30 @@ impl Heap {
             None => None,
         }
     }
-    fn kalloc(&mut self, size: usize) -> Option<*mut u8> {
+    pub fn kalloc(&mut self, size: usize) -> Option<*mut u8> {
         let new_block = {
             if let Some(block) = self.internal_get_block(size) {
                 block
             } else {
-                let block = self.internal_alloc(core::mem::size_of::<Block>()) as *mut Block;
+                let mark = self.free_addr;
+                let block = match self.internal_alloc(core::mem::size_of::<Block>()) {
+                    Some(ptr) => ptr as *mut Block,
+                    None => return None,
+                };
+                let chunk = match self.internal_alloc(size) {
+                    Some(ptr) => ptr,
+                    None => {
+                        self.free_addr = mark;
+                        return None;
+                    }
+                };
                 {
                     let mut block_ref = match unsafe { block.as_mut() } {
                         Some(val) => val,
                         None => return None,
                     };
                     block_ref.size = size;
-                    block_ref.chunk = self.internal_alloc(size);
+                    block_ref.chunk = chunk;
                 }
                 block
             }
@@ -69,14 +147,68 @@ impl Heap {
         }
         Some(block_ref.chunk as *mut _)
     }
-    fn internal_alloc(&mut self, size: usize) -> *mut u8 {
-        let guard_size = core::mem::size_of::<u32>() * 2;
+    /// Moves a chunk from the used list to the free list, so that
+    /// `kalloc` can hand it out again.
+    pub fn kfree(&mut self, ptr: *mut u8) -> bool {
+        let mut prev: *mut Block = core::ptr::null_mut();
+        let mut i = self.used_top;
+        while let Some(block) = unsafe { i.as_mut() } {
+            if block.chunk == ptr as *const u8 {
+                match unsafe { prev.as_mut() } {
+                    Some(prev) => prev.next = block.next,
+                    None => self.used_top = block.next,
+                }
+                block.next = self.free_top;
+                self.free_top = i;
+                return true;
+            }
+            prev = i;
+            i = block.next;
+        }
+        false
+    }
+    /// Allocates a chunk starting on an `align` boundary.
+    ///
+    /// Aligned chunks skip the block list, as they are never reused.
+    pub fn kalloc_aligned(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        if align == 0 || align & (align - 1) != 0 || align > self.room() {
+            return None;
+        }
+        // Chunks start one alignment unit past the free pointer.
+        let mark = self.free_addr;
+        let chunk = self.free_addr as usize + ALIGN;
+        let aligned = (chunk + align - 1) & !(align - 1);
+        self.free_addr = (aligned - ALIGN) as *mut u8;
+        let ptr = match self.internal_alloc(size) {
+            Some(ptr) => ptr,
+            None => {
+                self.free_addr = mark;
+                return None;
+            }
+        };
+        unsafe {
+            rlibc::memset(ptr, 0, size);
+        }
+        Some(ptr)
+    }
+    /// Bytes left between the free pointer and the end of the heap.
+    fn room(&self) -> usize {
+        self.end.saturating_sub(self.free_addr as usize)
+    }
+    fn internal_alloc(&mut self, size: usize) -> Option<*mut u8> {
+        // Each guard takes a full alignment unit, so chunks stay aligned.
+        let guard_size = ALIGN * 2;
+        let room = self.room();
+        if size > room || Self::align(size) + guard_size > room {
+            klog!("[kalloc] req={} exceeds the heap ({} bytes left)", size, room);
+            return None;
+        }
         let aligned = Self::align(size) + guard_size;
         {
             let sys = aligned;
             let real = aligned - guard_size;
             let sys_loss = ((sys - size) * 100) / sys;
-            let real_loss = ((real - size) * 100) / real;
+            let real_loss = if real == 0 { 0 } else { ((real - size) * 100) / real };
             klog!("[kalloc] req={} alloc=[sys={} real={}] loss=[sys={} real={}]",
                   size,
                   sys,
@@ -85,10 +217,11 @@ impl Heap {
                   real_loss);
         }
         unsafe {
-            *(self.free_addr as *mut u32) = GUARD1;
-            *(self.free_addr.offset((aligned + 4) as isize) as *mut u32) = GUARD2;
+            let chunk = self.free_addr.offset(ALIGN as isize);
+            *(chunk.offset(-4) as *mut u32) = GUARD1;
+            *(chunk.offset((aligned - guard_size) as isize) as *mut u32) = GUARD2;
             self.free_addr = self.free_addr.offset(aligned as isize);
-            self.free_addr.offset((4 - aligned) as isize)
+            Some(chunk)
         }
     }
     fn internal_get_block(&mut self, size: usize) -> Option<*mut Block> {
@@ -103,7 +236,7 @@ impl Heap {
                 Some(val) => val,
                 None => return None,
             };
-            if ival.size > size {
+            if ival.size >= size {
                 if i == p {
                     self.free_top = ival.next;
                 } else {
@@ -117,6 +250,6 @@ impl Heap {
         None
     }
     fn align(addr: usize) -> usize {
-        (addr % ALIGN) + addr
+        (addr + ALIGN - 1) & !(ALIGN - 1)
     }
 }
\ No newline at end of file
@@ -0,0 +1,249 @@
+#![allow(dead_code)]
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use acpi;
+use apic;
+use cpu;
+
+// Registers
+const HPET_CAPABILITIES: usize = 0x000;
+const HPET_CONFIG: usize = 0x010;
+const HPET_INTERRUPT_STATUS: usize = 0x020;
+const HPET_COUNTER: usize = 0x0F0;
+const HPET_TIMER_BASE: usize = 0x100;
+const HPET_TIMER_STRIDE: usize = 0x20;
+const HPET_TIMER_CONFIG: usize = 0x00;
+const HPET_TIMER_COMPARATOR: usize = 0x08;
+
+// Capabilities
+const HPET_CAP_COUNT_SHIFT: u64 = 8;
+const HPET_CAP_COUNT_MASK: u64 = 0x1F;
+const HPET_CAP_64BIT: u64 = 1 << 13;
+const HPET_CAP_PERIOD_SHIFT: u64 = 32;
+const HPET_MAX_PERIOD_FS: u64 = 100000000;
+
+// Configuration
+const HPET_CONFIG_ENABLE: u64 = 1 << 0;
+const HPET_CONFIG_LEGACY: u64 = 1 << 1;
+
+// Timer configuration
+const HPET_TN_INT_ENABLE: u64 = 1 << 2;
+const HPET_TN_PERIODIC: u64 = 1 << 3;
+const HPET_TN_PERIODIC_CAP: u64 = 1 << 4;
+const HPET_TN_VALUE_SET: u64 = 1 << 6;
+const HPET_TN_32BIT: u64 = 1 << 8;
+const HPET_TN_ROUTE_SHIFT: u64 = 9;
+const HPET_TN_ROUTE_MASK: u64 = 0x1F << 9;
+const HPET_TN_ROUTE_CAP_SHIFT: u64 = 32;
+
+// General
+const FEMTOS_PER_SEC: u64 = 1000000000000000;
+const FEMTOS_PER_NANO: u64 = 1000000;
+
+/// MMIO base address, or 0 if there is no HPET.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Counter period in femtoseconds.
+static PERIOD: AtomicUsize = AtomicUsize::new(0);
+
+/// Error returned when programming a comparator.
+#[derive(Debug)]
+pub enum HpetError {
+    NotPresent,
+    InvalidComparator,
+    PeriodicUnsupported,
+    RouteUnavailable,
+}
+
+/// Locates the HPET through ACPI and starts the main counter.
+pub fn init() -> bool {
+    let base = match acpi::hpet_address() {
+        Some(base) => base,
+        None => return false,
+    };
+    let caps = unsafe { read(base, HPET_CAPABILITIES) };
+    let period = caps >> HPET_CAP_PERIOD_SHIFT;
+    if period == 0 || period > HPET_MAX_PERIOD_FS {
+        klog!("[hpet] Invalid counter period {} fs", period);
+        return false;
+    }
+    unsafe {
+        let config = read(base, HPET_CONFIG) & !HPET_CONFIG_LEGACY;
+        write(base, HPET_CONFIG, config & !HPET_CONFIG_ENABLE);
+        write(base, HPET_COUNTER, 0);
+        // Comparators start out disabled until a driver claims them.
+        for index in 0..comparator_count(caps) {
+            let off = timer_reg(index, HPET_TIMER_CONFIG);
+            let tconfig = read(base, off);
+            write(base, off, tconfig & !(HPET_TN_INT_ENABLE | HPET_TN_PERIODIC));
+        }
+        write(base, HPET_CONFIG, config | HPET_CONFIG_ENABLE);
+    }
+    PERIOD.store(period as usize, Ordering::SeqCst);
+    BASE.store(base, Ordering::SeqCst);
+    klog!("[hpet] {} kHz, {} comparators, {}-bit counter at {:#x}",
+          frequency() / 1000,
+          comparator_count(caps),
+          if caps & HPET_CAP_64BIT != 0 { 64 } else { 32 },
+          base);
+    true
+}
+
+/// Checks whether the HPET is available.
+#[inline]
+pub fn is_present() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Checks whether the main counter is 64 bits wide.
+pub fn is_64bit() -> bool {
+    is_present() && unsafe { read(base(), HPET_CAPABILITIES) } & HPET_CAP_64BIT != 0
+}
+
+/// Gets the main counter frequency in Hz.
+pub fn frequency() -> u64 {
+    match PERIOD.load(Ordering::Relaxed) as u64 {
+        0 => 0,
+        period => FEMTOS_PER_SEC / period,
+    }
+}
+
+/// Reads the main counter.
+#[inline]
+pub fn counter() -> u64 {
+    unsafe { read(base(), HPET_COUNTER) }
+}
+
+/// Gets nanoseconds since the main counter was started.
+pub fn nanos() -> u64 {
+    let count = counter();
+    let period = PERIOD.load(Ordering::Relaxed) as u64;
+    (count / FEMTOS_PER_NANO) * period + (count % FEMTOS_PER_NANO) * period / FEMTOS_PER_NANO
+}
+
+/// Converts nanoseconds to main counter ticks.
+pub fn ticks_for(ns: u64) -> u64 {
+    match PERIOD.load(Ordering::Relaxed) as u64 {
+        0 => 0,
+        period => (ns / period) * FEMTOS_PER_NANO + (ns % period) * FEMTOS_PER_NANO / period,
+    }
+}
+
+/// Measures how far `sample` advances over `ms` milliseconds.
+pub fn calibrate<F>(ms: u32, mut sample: F) -> u64
+    where F: FnMut() -> u64
+{
+    let ticks = ticks_for(ms as u64 * 1000000);
+    cpu::without_interrupts(|| {
+        let start_count = counter();
+        let start = sample();
+        while counter().wrapping_sub(start_count) < ticks {}
+        sample().wrapping_sub(start)
+    })
+}
+
+/// Arms a comparator to fire once after `ns` nanoseconds on an ISA IRQ.
+pub fn start_oneshot(index: usize, ns: u64, irq: u8) -> Result<(), HpetError> {
+    let base = checked_base(index)?;
+    let route = route_for(base, index, irq)?;
+    unsafe {
+        let off = timer_reg(index, HPET_TIMER_CONFIG);
+        let config = read(base, off) & !(HPET_TN_PERIODIC | HPET_TN_ROUTE_MASK | HPET_TN_32BIT);
+        write(base, off, config | route);
+        write(base,
+              timer_reg(index, HPET_TIMER_COMPARATOR),
+              counter().wrapping_add(ticks_for(ns)));
+        write(base, off, config | route | HPET_TN_INT_ENABLE);
+    }
+    Ok(())
+}
+
+/// Starts a comparator firing every `ns` nanoseconds on an ISA IRQ.
+pub fn start_periodic(index: usize, ns: u64, irq: u8) -> Result<(), HpetError> {
+    let base = checked_base(index)?;
+    let off = timer_reg(index, HPET_TIMER_CONFIG);
+    if unsafe { read(base, off) } & HPET_TN_PERIODIC_CAP == 0 {
+        return Err(HpetError::PeriodicUnsupported);
+    }
+    let route = route_for(base, index, irq)?;
+    let ticks = ticks_for(ns);
+    unsafe {
+        let config = read(base, off) & !(HPET_TN_ROUTE_MASK | HPET_TN_32BIT);
+        let config = config | route | HPET_TN_PERIODIC | HPET_TN_INT_ENABLE;
+        // The main counter has to be halted while the period is set.
+        let main = read(base, HPET_CONFIG);
+        write(base, HPET_CONFIG, main & !HPET_CONFIG_ENABLE);
+        write(base, off, config | HPET_TN_VALUE_SET);
+        write(base, timer_reg(index, HPET_TIMER_COMPARATOR), counter() + ticks);
+        write(base, timer_reg(index, HPET_TIMER_COMPARATOR), ticks);
+        write(base, HPET_CONFIG, main);
+    }
+    Ok(())
+}
+
+/// Stops a comparator.
+pub fn stop(index: usize) -> Result<(), HpetError> {
+    let base = checked_base(index)?;
+    unsafe {
+        let off = timer_reg(index, HPET_TIMER_CONFIG);
+        let config = read(base, off);
+        write(base, off, config & !(HPET_TN_INT_ENABLE | HPET_TN_PERIODIC));
+    }
+    Ok(())
+}
+
+/// Validates a comparator index and gets the MMIO base.
+fn checked_base(index: usize) -> Result<usize, HpetError> {
+    if !is_present() {
+        return Err(HpetError::NotPresent);
+    }
+    let base = base();
+    if index >= comparator_count(unsafe { read(base, HPET_CAPABILITIES) }) {
+        return Err(HpetError::InvalidComparator);
+    }
+    Ok(base)
+}
+
+/// Gets the route bits for delivering a comparator on an ISA IRQ.
+///
+/// Comparators are routed to I/O APIC inputs, so this requires the APIC.
+fn route_for(base: usize, index: usize, irq: u8) -> Result<u64, HpetError> {
+    let gsi = match apic::isa_gsi(irq) {
+        Some(gsi) if apic::is_enabled() && gsi < 32 => gsi as u64,
+        _ => return Err(HpetError::RouteUnavailable),
+    };
+    let caps = unsafe { read(base, timer_reg(index, HPET_TIMER_CONFIG)) } >>
+               HPET_TN_ROUTE_CAP_SHIFT;
+    if caps & (1 << gsi) == 0 {
+        return Err(HpetError::RouteUnavailable);
+    }
+    Ok(gsi << HPET_TN_ROUTE_SHIFT)
+}
+
+/// Gets the number of comparators from the capabilities register.
+#[inline]
+fn comparator_count(caps: u64) -> usize {
+    ((caps >> HPET_CAP_COUNT_SHIFT) & HPET_CAP_COUNT_MASK) as usize + 1
+}
+
+/// Gets the offset of a comparator register.
+#[inline]
+fn timer_reg(index: usize, reg: usize) -> usize {
+    HPET_TIMER_BASE + index * HPET_TIMER_STRIDE + reg
+}
+
+#[inline]
+fn base() -> usize {
+    BASE.load(Ordering::Relaxed)
+}
+
+#[inline]
+unsafe fn read(base: usize, reg: usize) -> u64 {
+    read_volatile((base + reg) as *const u64)
+}
+
+#[inline]
+unsafe fn write(base: usize, reg: usize, val: u64) {
+    write_volatile((base + reg) as *mut u64, val);
+}
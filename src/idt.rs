@@ -0,0 +1,191 @@
+#![allow(dead_code)]
+
+use core;
+use apic;
+use clock;
+use cpu;
+use irq;
+
+// Descriptor
+const IDT_ENTRIES: usize = 256;
+const IDT_KERNEL_CODE: u16 = 0x08;
+const IDT_INTERRUPT_GATE: u8 = 0x8E;
+
+// Exceptions
+pub const EXCEPTION_COUNT: usize = 32;
+pub const EXCEPTION_PAGE_FAULT: u64 = 0x0E;
+
+// Hardware interrupts
+pub const IRQ_BASE: usize = 0x20;
+pub const IRQ_COUNT: usize = 16;
+
+// Local APIC
+pub const LAPIC_TIMER_VECTOR: usize = 0x30;
+
+// Stubs
+const STUB_COUNT: usize = LAPIC_TIMER_VECTOR + 1;
+
+/// Human readable names of the architectural exceptions.
+static EXCEPTION_NAMES: [&'static str; EXCEPTION_COUNT] = ["Divide Error",
+                                                           "Debug",
+                                                           "Non-Maskable Interrupt",
+                                                           "Breakpoint",
+                                                           "Overflow",
+                                                           "Bound Range Exceeded",
+                                                           "Invalid Opcode",
+                                                           "Device Not Available",
+                                                           "Double Fault",
+                                                           "Coprocessor Segment Overrun",
+                                                           "Invalid TSS",
+                                                           "Segment Not Present",
+                                                           "Stack-Segment Fault",
+                                                           "General Protection Fault",
+                                                           "Page Fault",
+                                                           "Reserved",
+                                                           "x87 Floating-Point Exception",
+                                                           "Alignment Check",
+                                                           "Machine Check",
+                                                           "SIMD Floating-Point Exception",
+                                                           "Virtualization Exception",
+                                                           "Control Protection Exception",
+                                                           "Reserved",
+                                                           "Reserved",
+                                                           "Reserved",
+                                                           "Reserved",
+                                                           "Reserved",
+                                                           "Reserved",
+                                                           "Hypervisor Injection Exception",
+                                                           "VMM Communication Exception",
+                                                           "Security Exception",
+                                                           "Reserved"];
+
+extern "C" {
+    /// Addresses of the interrupt stubs, defined in `asm/interrupts.asm`.
+    static isr_table: [usize; STUB_COUNT];
+}
+
+/// IDT entry.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+/// IDT pointer, as expected by `lidt`.
+#[repr(C, packed)]
+struct IdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// Register state saved by the interrupt stubs.
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+const MISSING_ENTRY: IdtEntry = IdtEntry {
+    offset_low: 0,
+    selector: 0,
+    ist: 0,
+    type_attr: 0,
+    offset_mid: 0,
+    offset_high: 0,
+    reserved: 0,
+};
+
+/// The interrupt descriptor table.
+static mut IDT: [IdtEntry; IDT_ENTRIES] = [MISSING_ENTRY; IDT_ENTRIES];
+
+impl IdtEntry {
+    /// Constructs a new interrupt gate pointing at `handler`.
+    fn new(handler: usize) -> Self {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: IDT_KERNEL_CODE,
+            ist: 0,
+            type_attr: IDT_INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+/// Installs a raw interrupt stub for the given vector.
+pub unsafe fn set_gate(vector: usize, handler: usize) {
+    IDT[vector] = IdtEntry::new(handler);
+}
+
+/// Populates the IDT with the exception, IRQ and timer stubs and loads it.
+pub fn init() {
+    unsafe {
+        for vector in 0..STUB_COUNT {
+            set_gate(vector, isr_table[vector]);
+        }
+        let ptr = IdtPointer {
+            limit: (core::mem::size_of::<[IdtEntry; IDT_ENTRIES]>() - 1) as u16,
+            base: &IDT as *const _ as u64,
+        };
+        cpu::lidt(&ptr as *const _ as usize);
+    }
+}
+
+/// Common interrupt entry point, called from `isr_common`.
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    match frame.vector as usize {
+        0...31 => exception(frame),
+        vector @ IRQ_BASE...0x2F => irq::dispatch((vector - IRQ_BASE) as u8),
+        LAPIC_TIMER_VECTOR => {
+            clock::timer_interrupt();
+            apic::eoi();
+        }
+        _ => {
+            klog!("*** Unexpected interrupt vector {:#04x}", frame.vector);
+        }
+    }
+}
+
+/// Logs an unrecoverable CPU exception and halts.
+fn exception(frame: &InterruptFrame) -> ! {
+    klog!("*** EXCEPTION {:#04x}: {}",
+          frame.vector,
+          EXCEPTION_NAMES[frame.vector as usize]);
+    klog!("    error={:#018x}", frame.error_code);
+    klog!("    rip={:#018x} rsp={:#018x} rflags={:#018x}",
+          frame.rip,
+          frame.rsp,
+          frame.rflags);
+    if frame.vector == EXCEPTION_PAGE_FAULT {
+        klog!("    cr2={:#018x}", cpu::read_cr2());
+    }
+    cpu::halt_forever();
+}
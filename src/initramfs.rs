@@ -0,0 +1,592 @@
+#![allow(dead_code)]
+
+use core::{cmp, slice, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use multiboot2::BootInformation;
+use spin::Mutex;
+use heap;
+use vfs::{self, DirEntry, FileType, Inode, InodeRef, Stat, VfsError};
+
+/// Command line of the boot module holding the initial RAM filesystem.
+pub const INITRAMFS_MODULE_TAG: &'static str = "initramfs";
+
+// Limits
+const MAX_DEPTH: usize = 32;
+const ROOT_INO: u64 = 1;
+
+/// Contents of directories and of nodes unpacked without data.
+const NO_DATA: &'static [u8] = &[];
+
+// USTAR
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_NAME: usize = 0;
+const TAR_NAME_LEN: usize = 100;
+const TAR_MODE: usize = 100;
+const TAR_MODE_LEN: usize = 8;
+const TAR_SIZE: usize = 124;
+const TAR_SIZE_LEN: usize = 12;
+const TAR_TYPE: usize = 156;
+const TAR_LINK: usize = 157;
+const TAR_LINK_LEN: usize = 100;
+const TAR_MAGIC: usize = 257;
+const TAR_PREFIX: usize = 345;
+const TAR_PREFIX_LEN: usize = 155;
+const TAR_MAGIC_USTAR: &'static [u8] = b"ustar";
+const TAR_TYPE_FILE: u8 = b'0';
+const TAR_TYPE_FILE_OLD: u8 = 0;
+const TAR_TYPE_HARD_LINK: u8 = b'1';
+const TAR_TYPE_SYMLINK: u8 = b'2';
+const TAR_TYPE_DIRECTORY: u8 = b'5';
+const TAR_TYPE_CONTIGUOUS: u8 = b'7';
+
+// newc cpio
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_MAGIC: &'static [u8] = b"070701";
+const CPIO_MAGIC_CRC: &'static [u8] = b"070702";
+const CPIO_INO: usize = 6;
+const CPIO_MODE: usize = 14;
+const CPIO_NLINK: usize = 38;
+const CPIO_FILE_SIZE: usize = 54;
+const CPIO_NAME_SIZE: usize = 94;
+const CPIO_FIELD_LEN: usize = 8;
+const CPIO_ALIGN: usize = 4;
+const CPIO_TRAILER: &'static str = "TRAILER!!!";
+
+// File modes
+const S_IFMT: usize = 0o170000;
+const S_IFDIR: usize = 0o040000;
+const S_IFREG: usize = 0o100000;
+const S_IFLNK: usize = 0o120000;
+const S_IPERM: usize = 0o7777;
+
+/// Node of the initial RAM filesystem.
+///
+/// File contents and symlink targets point into the boot module,
+/// which lies below the kernel heap and is never reused.
+struct RamNode {
+    ino: u64,
+    kind: FileType,
+    mode: AtomicUsize,
+    data: &'static [u8],
+    /// Directory entries, newest first.
+    children: Mutex<Option<&'static RamEntry>>,
+}
+
+/// Directory entry.
+struct RamEntry {
+    name: &'static str,
+    node: &'static RamNode,
+    next: Option<&'static RamEntry>,
+}
+
+/// Iterator over the entries of a directory, newest first.
+struct Entries {
+    next: Option<&'static RamEntry>,
+}
+
+/// Entry of a newc cpio archive.
+struct CpioEntry {
+    ino: usize,
+    mode: usize,
+    nlink: usize,
+    name: &'static str,
+    data: &'static [u8],
+    /// Offset of the next header.
+    next: usize,
+}
+
+/// Archive format of the module.
+#[derive(Copy, Clone, Debug)]
+enum Format {
+    Ustar,
+    Cpio,
+}
+
+/// Builds the tree while unpacking an archive.
+struct Loader {
+    root: &'static RamNode,
+    next_ino: u64,
+    count: usize,
+}
+
+impl Iterator for Entries {
+    type Item = &'static RamEntry;
+    fn next(&mut self) -> Option<&'static RamEntry> {
+        let entry = self.next;
+        self.next = entry.and_then(|entry| entry.next);
+        entry
+    }
+}
+
+impl RamNode {
+    /// Enumerates the directory entries, newest first.
+    fn entries(&self) -> Entries {
+        Entries { next: *self.children.lock() }
+    }
+    /// Looks up a directory entry.
+    fn child(&self, name: &str) -> Option<&'static RamNode> {
+        self.entries().find(|entry| entry.name == name).map(|entry| entry.node)
+    }
+    /// Adds a directory entry.
+    fn link(&self, name: &'static str, node: &'static RamNode) -> Result<(), VfsError> {
+        let mut children = self.children.lock();
+        let entry = RamEntry {
+            name: name,
+            node: node,
+            next: *children,
+        };
+        match heap::leak(entry) {
+            Some(entry) => {
+                *children = Some(entry);
+                Ok(())
+            }
+            None => Err(VfsError::OutOfMemory),
+        }
+    }
+}
+
+impl Inode for RamNode {
+    fn stat(&self) -> Stat {
+        Stat {
+            ino: self.ino,
+            kind: self.kind,
+            mode: self.mode.load(Ordering::SeqCst) as u16,
+            size: if self.kind == FileType::Directory { 0 } else { self.data.len() as u64 },
+            device: None,
+        }
+    }
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if self.kind != FileType::Regular {
+            return Err(VfsError::InvalidArgument);
+        }
+        if offset >= self.data.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let len = cmp::min(buf.len(), self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(len)
+    }
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        if self.kind != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        match self.child(name) {
+            Some(node) => Ok(node),
+            None => Err(VfsError::NotFound),
+        }
+    }
+    /// Lists entries in archive order.
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, VfsError> {
+        if self.kind != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        let count = self.entries().count();
+        if index >= count {
+            return Ok(None);
+        }
+        match self.entries().nth(count - 1 - index) {
+            Some(entry) => DirEntry::new(entry.node.ino, entry.node.kind, entry.name).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn readlink(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        if self.kind != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let len = cmp::min(buf.len(), self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        Ok(len)
+    }
+}
+
+/// Cuts a header field at its first NUL.
+fn field(header: &'static [u8], off: usize, len: usize) -> &'static [u8] {
+    let field = &header[off..off + len];
+    match field.iter().position(|&byte| byte == 0) {
+        Some(end) => &field[..end],
+        None => field,
+    }
+}
+
+/// Reads a header field as text.
+fn text(header: &'static [u8], off: usize, len: usize) -> Result<&'static str, VfsError> {
+    str::from_utf8(field(header, off, len)).map_err(|_| VfsError::InvalidPath)
+}
+
+/// Parses an octal number, as used by tar headers.
+fn parse_octal(digits: &[u8]) -> Option<usize> {
+    let mut val: usize = 0;
+    let mut seen = false;
+    for &digit in digits.iter().skip_while(|&&digit| digit == b' ') {
+        match digit {
+            b'0'...b'7' => {
+                val = match val.checked_mul(8) {
+                    Some(val) => val + (digit - b'0') as usize,
+                    None => return None,
+                };
+                seen = true;
+            }
+            0 | b' ' => break,
+            _ => return None,
+        }
+    }
+    if seen { Some(val) } else { None }
+}
+
+/// Parses a fixed-width hexadecimal number, as used by cpio headers.
+fn parse_hex(digits: &[u8]) -> Option<usize> {
+    let mut val: usize = 0;
+    for &digit in digits {
+        let nibble = match digit {
+            b'0'...b'9' => digit - b'0',
+            b'a'...b'f' => digit - b'a' + 10,
+            b'A'...b'F' => digit - b'A' + 10,
+            _ => return None,
+        };
+        val = val << 4 | nibble as usize;
+    }
+    Some(val)
+}
+
+/// Parses the newc cpio entry at `off`.
+fn parse_cpio(image: &'static [u8], off: usize) -> Result<CpioEntry, VfsError> {
+    if off + CPIO_HEADER_SIZE > image.len() {
+        return Err(VfsError::InvalidArgument);
+    }
+    let header = &image[off..off + CPIO_HEADER_SIZE];
+    let magic = &header[..CPIO_MAGIC.len()];
+    if magic != CPIO_MAGIC && magic != CPIO_MAGIC_CRC {
+        return Err(VfsError::InvalidArgument);
+    }
+    let hex = |at: usize| parse_hex(&header[at..at + CPIO_FIELD_LEN]);
+    let fields = (hex(CPIO_INO), hex(CPIO_MODE), hex(CPIO_NLINK));
+    let (ino, mode, nlink) = match fields {
+        (Some(ino), Some(mode), Some(nlink)) => (ino, mode, nlink),
+        _ => return Err(VfsError::InvalidArgument),
+    };
+    let (size, name_size) = match (hex(CPIO_FILE_SIZE), hex(CPIO_NAME_SIZE)) {
+        (Some(size), Some(name_size)) if name_size > 0 => (size, name_size),
+        _ => return Err(VfsError::InvalidArgument),
+    };
+    // The name includes its NUL terminator.
+    let name_start = off + CPIO_HEADER_SIZE;
+    let start = align(name_start + name_size, CPIO_ALIGN);
+    let end = start + size;
+    if end > image.len() {
+        return Err(VfsError::InvalidArgument);
+    }
+    let name = match str::from_utf8(&image[name_start..name_start + name_size - 1]) {
+        Ok(name) => name,
+        Err(_) => return Err(VfsError::InvalidPath),
+    };
+    Ok(CpioEntry {
+        ino: ino,
+        mode: mode,
+        nlink: nlink,
+        name: name,
+        data: &image[start..end],
+        next: align(end, CPIO_ALIGN),
+    })
+}
+
+impl CpioEntry {
+    /// Checks whether the entry is a hard link without data. newc archives
+    /// only store the data with the last link of a file.
+    fn is_deferred_link(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG && self.nlink > 1 && self.data.is_empty()
+    }
+}
+
+/// Rounds up to a power of two.
+fn align(val: usize, to: usize) -> usize {
+    (val + to - 1) & !(to - 1)
+}
+
+/// Detects the archive format of a module.
+fn detect(image: &[u8]) -> Option<Format> {
+    if image.len() >= CPIO_HEADER_SIZE &&
+       (&image[..CPIO_MAGIC.len()] == CPIO_MAGIC || &image[..CPIO_MAGIC.len()] == CPIO_MAGIC_CRC) {
+        return Some(Format::Cpio);
+    }
+    if image.len() >= TAR_BLOCK_SIZE &&
+       &image[TAR_MAGIC..TAR_MAGIC + TAR_MAGIC_USTAR.len()] == TAR_MAGIC_USTAR {
+        return Some(Format::Ustar);
+    }
+    None
+}
+
+impl Loader {
+    /// Constructs a loader with an empty root directory.
+    fn new() -> Option<Self> {
+        let root = RamNode {
+            ino: ROOT_INO,
+            kind: FileType::Directory,
+            mode: AtomicUsize::new(0o755),
+            data: NO_DATA,
+            children: Mutex::new(None),
+        };
+        heap::leak(root).map(|root| {
+            Loader {
+                root: root,
+                next_ino: ROOT_INO + 1,
+                count: 0,
+            }
+        })
+    }
+    /// Allocates a node.
+    fn node(&mut self, kind: FileType, mode: usize, data: &'static [u8])
+            -> Result<&'static RamNode, VfsError> {
+        let node = RamNode {
+            ino: self.next_ino,
+            kind: kind,
+            mode: AtomicUsize::new(mode & S_IPERM),
+            data: data,
+            children: Mutex::new(None),
+        };
+        self.next_ino += 1;
+        match heap::leak(node) {
+            Some(node) => Ok(node),
+            None => Err(VfsError::OutOfMemory),
+        }
+    }
+    /// Finds a node by its path in the archive.
+    fn find(&self, path: &str) -> Option<&'static RamNode> {
+        path.split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .fold(Some(self.root), |dir, name| dir.and_then(|dir| dir.child(name)))
+    }
+    /// Adds a node at `prefix/name`, creating missing parent directories.
+    /// Returns the node.
+    ///
+    /// A directory that already exists only takes the new mode, so that
+    /// archives may list directories after their contents.
+    fn add(&mut self,
+           prefix: &'static str,
+           name: &'static str,
+           kind: FileType,
+           mode: usize,
+           data: &'static [u8],
+           target: Option<&'static RamNode>)
+           -> Result<&'static RamNode, VfsError> {
+        let mut names = [""; MAX_DEPTH];
+        let mut depth = 0;
+        for part in prefix.split('/').chain(name.split('/')) {
+            match part {
+                "" | "." => continue,
+                ".." => return Err(VfsError::InvalidPath),
+                _ => {}
+            }
+            if depth == MAX_DEPTH {
+                return Err(VfsError::NameTooLong);
+            }
+            names[depth] = part;
+            depth += 1;
+        }
+        if depth == 0 {
+            // The archive root, e.g. `.` in cpio archives.
+            if kind == FileType::Directory {
+                self.root.mode.store(mode & S_IPERM, Ordering::SeqCst);
+            }
+            return Ok(self.root);
+        }
+        let mut dir = self.root;
+        for &part in &names[..depth - 1] {
+            dir = match dir.child(part) {
+                Some(node) if node.kind == FileType::Directory => node,
+                Some(_) => return Err(VfsError::NotDirectory),
+                None => {
+                    let node = self.node(FileType::Directory, 0o755, NO_DATA)?;
+                    dir.link(part, node)?;
+                    node
+                }
+            };
+        }
+        let last = names[depth - 1];
+        match dir.child(last) {
+            Some(node) if node.kind == FileType::Directory && kind == FileType::Directory => {
+                node.mode.store(mode & S_IPERM, Ordering::SeqCst);
+                return Ok(node);
+            }
+            Some(node) if target.map_or(false, |target| target.ino == node.ino) => {
+                return Ok(node);
+            }
+            Some(_) => return Err(VfsError::Busy),
+            None => {}
+        }
+        let node = match target {
+            Some(node) => node,
+            None => self.node(kind, mode, data)?,
+        };
+        dir.link(last, node)?;
+        self.count += 1;
+        Ok(node)
+    }
+    /// Unpacks a USTAR archive.
+    fn unpack_ustar(&mut self, image: &'static [u8]) -> Result<(), VfsError> {
+        let mut off = 0;
+        while off + TAR_BLOCK_SIZE <= image.len() {
+            let header = &image[off..off + TAR_BLOCK_SIZE];
+            // The archive ends with zero blocks.
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            let size = match parse_octal(&header[TAR_SIZE..TAR_SIZE + TAR_SIZE_LEN]) {
+                Some(size) => size,
+                None => return Err(VfsError::InvalidArgument),
+            };
+            let start = off + TAR_BLOCK_SIZE;
+            let end = match start.checked_add(size) {
+                Some(end) if end <= image.len() => end,
+                _ => return Err(VfsError::InvalidArgument),
+            };
+            let name = text(header, TAR_NAME, TAR_NAME_LEN)?;
+            let magic = &header[TAR_MAGIC..TAR_MAGIC + TAR_MAGIC_USTAR.len()];
+            let prefix = if magic == TAR_MAGIC_USTAR {
+                text(header, TAR_PREFIX, TAR_PREFIX_LEN)?
+            } else {
+                ""
+            };
+            let mode = parse_octal(&header[TAR_MODE..TAR_MODE + TAR_MODE_LEN]).unwrap_or(0o644);
+            let link = field(header, TAR_LINK, TAR_LINK_LEN);
+            let res = match header[TAR_TYPE] {
+                TAR_TYPE_FILE | TAR_TYPE_FILE_OLD | TAR_TYPE_CONTIGUOUS => {
+                    self.add(prefix, name, FileType::Regular, mode, &image[start..end], None)
+                }
+                TAR_TYPE_DIRECTORY => {
+                    self.add(prefix, name, FileType::Directory, mode, NO_DATA, None)
+                }
+                TAR_TYPE_SYMLINK => self.add(prefix, name, FileType::Symlink, 0o777, link, None),
+                TAR_TYPE_HARD_LINK => {
+                    let target = str::from_utf8(link).ok().and_then(|link| self.find(link));
+                    match target {
+                        Some(node) => self.add(prefix, name, node.kind, mode, NO_DATA, Some(node)),
+                        None => Err(VfsError::NotFound),
+                    }
+                }
+                _ => Err(VfsError::Unsupported),
+            };
+            if let Err(err) = res {
+                let separator = if prefix.is_empty() { "" } else { "/" };
+                klog!("[initramfs] Skipping {}{}{}: {:?}", prefix, separator, name, err);
+            }
+            off = start + align(size, TAR_BLOCK_SIZE);
+        }
+        Ok(())
+    }
+    /// Unpacks a newc cpio archive.
+    ///
+    /// Hard links without data are linked to their file once the entry
+    /// holding the data shows up, or at the end for empty files.
+    fn unpack_cpio(&mut self, image: &'static [u8]) -> Result<(), VfsError> {
+        let mut off = 0;
+        loop {
+            let entry = parse_cpio(image, off)?;
+            if entry.name == CPIO_TRAILER {
+                self.link_empty_groups(image);
+                return Ok(());
+            }
+            let (name, mode, data) = (entry.name, entry.mode, entry.data);
+            let res = match mode & S_IFMT {
+                S_IFREG if entry.is_deferred_link() => Ok(()),
+                S_IFREG => {
+                    match self.add("", name, FileType::Regular, mode, data, None) {
+                        Ok(node) if entry.nlink > 1 => {
+                            self.link_group(image, entry.ino, node);
+                            Ok(())
+                        }
+                        res => res.map(|_| ()),
+                    }
+                }
+                S_IFDIR => self.add("", name, FileType::Directory, mode, NO_DATA, None).map(|_| ()),
+                S_IFLNK => self.add("", name, FileType::Symlink, mode, data, None).map(|_| ()),
+                _ => Err(VfsError::Unsupported),
+            };
+            if let Err(err) = res {
+                klog!("[initramfs] Skipping {}: {:?}", name, err);
+            }
+            off = entry.next;
+        }
+    }
+    /// Links the hard links without data of a file to its node.
+    fn link_group(&mut self, image: &'static [u8], ino: usize, node: &'static RamNode) {
+        let mut off = 0;
+        while let Ok(entry) = parse_cpio(image, off) {
+            if entry.name == CPIO_TRAILER {
+                break;
+            }
+            if entry.is_deferred_link() && entry.ino == ino {
+                let res = self.add("", entry.name, node.kind, entry.mode, NO_DATA, Some(node));
+                if let Err(err) = res {
+                    klog!("[initramfs] Skipping {}: {:?}", entry.name, err);
+                }
+            }
+            off = entry.next;
+        }
+    }
+    /// Links the hard links of files that have no data at all.
+    fn link_empty_groups(&mut self, image: &'static [u8]) {
+        let mut off = 0;
+        while let Ok(entry) = parse_cpio(image, off) {
+            if entry.name == CPIO_TRAILER {
+                break;
+            }
+            if entry.is_deferred_link() && self.find(entry.name).is_none() {
+                match self.add("", entry.name, FileType::Regular, entry.mode, NO_DATA, None) {
+                    Ok(node) => self.link_group(image, entry.ino, node),
+                    Err(err) => klog!("[initramfs] Skipping {}: {:?}", entry.name, err),
+                }
+            }
+            off = entry.next;
+        }
+    }
+}
+
+/// Checks whether a module command line selects the initial RAM filesystem.
+fn is_initramfs(cmdline: &str) -> bool {
+    cmdline.split(' ').next() == Some(INITRAMFS_MODULE_TAG)
+}
+
+/// Unpacks the first boot module tagged as initramfs and mounts it at `/`.
+/// Returns `false` if there is none or it can't be unpacked.
+pub fn init(boot_info: &BootInformation) -> bool {
+    let module = match boot_info.module_tags().find(|module| is_initramfs(module.name())) {
+        Some(module) => module,
+        None => return false,
+    };
+    let start = module.start_address() as usize;
+    let end = module.end_address() as usize;
+    let image: &'static [u8] = unsafe { slice::from_raw_parts(start as *const u8, end - start) };
+    let format = match detect(image) {
+        Some(format) => format,
+        None => {
+            klog!("[initramfs] Module at {:#x} is neither USTAR nor newc cpio", start);
+            return false;
+        }
+    };
+    let mut loader = match Loader::new() {
+        Some(loader) => loader,
+        None => return false,
+    };
+    let res = match format {
+        Format::Ustar => loader.unpack_ustar(image),
+        Format::Cpio => loader.unpack_cpio(image),
+    };
+    if let Err(err) = res {
+        klog!("[initramfs] Truncated {:?} archive: {:?}", format, err);
+    }
+    klog!("[initramfs] Unpacked {} entries from {:?} archive ({} KiB)",
+          loader.count,
+          format,
+          image.len() / 1024);
+    match vfs::mount("/", loader.root, "initramfs") {
+        Ok(()) => true,
+        Err(err) => {
+            klog!("[initramfs] Unable to mount: {:?}", err);
+            false
+        }
+    }
+}
@@ -0,0 +1,87 @@
+#![allow(dead_code)]
+
+/// Ioctl command class, one per device class.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum IoctlClass {
+    Terminal,
+    Serial,
+    Block,
+}
+
+/// Terminal commands.
+#[derive(Copy, Clone, Debug)]
+pub enum TerminalIoctl {
+    GetCursor,
+    SetCursor { x: usize, y: usize },
+    GetColor,
+    SetColor(u8),
+    GetSize,
+    Clear,
+}
+
+/// Serial port commands.
+#[derive(Copy, Clone, Debug)]
+pub enum SerialIoctl {
+    GetBaudRate,
+    SetBaudRate(u32),
+    GetModemLines,
+    SetModemLines(ModemLines),
+}
+
+/// Block device commands.
+#[derive(Copy, Clone, Debug)]
+pub enum BlockIoctl {
+    GetGeometry,
+    Flush,
+}
+
+/// Typed ioctl request.
+#[derive(Copy, Clone, Debug)]
+pub enum Ioctl {
+    Terminal(TerminalIoctl),
+    Serial(SerialIoctl),
+    Block(BlockIoctl),
+}
+
+/// Ioctl response.
+#[derive(Copy, Clone, Debug)]
+pub enum IoctlReply {
+    Done,
+    Cursor { x: usize, y: usize },
+    Color(u8),
+    Size { width: usize, height: usize },
+    BaudRate(u32),
+    ModemLines(ModemLines),
+    Geometry(BlockGeometry),
+}
+
+/// Modem control and status lines.
+///
+/// Only `dtr` and `rts` are honored when setting the lines.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ModemLines {
+    pub dtr: bool,
+    pub rts: bool,
+    pub cts: bool,
+    pub dsr: bool,
+    pub ri: bool,
+    pub dcd: bool,
+}
+
+/// Block device geometry.
+#[derive(Copy, Clone, Debug)]
+pub struct BlockGeometry {
+    pub sector_size: usize,
+    pub sector_count: u64,
+}
+
+impl Ioctl {
+    /// Gets the command class.
+    pub fn class(&self) -> IoctlClass {
+        match *self {
+            Ioctl::Terminal(_) => IoctlClass::Terminal,
+            Ioctl::Serial(_) => IoctlClass::Serial,
+            Ioctl::Block(_) => IoctlClass::Block,
+        }
+    }
+}
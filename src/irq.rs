@@ -0,0 +1,112 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use apic;
+use cpu;
+use idt::IRQ_COUNT;
+use pic::PIC;
+
+/// IRQ handler, called with the number of the IRQ line that fired.
+pub type IrqHandler = fn(irq: u8);
+
+/// Registered handlers, indexed by IRQ line.
+static HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Lines for which an unhandled IRQ has already been reported.
+static UNHANDLED_REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Error returned when registering an IRQ handler.
+#[derive(Debug)]
+pub enum IrqError {
+    InvalidLine,
+    AlreadyClaimed,
+}
+
+/// Claims an IRQ line for the given handler.
+pub fn register(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidLine);
+    }
+    cpu::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        match handlers[irq as usize] {
+            Some(_) => Err(IrqError::AlreadyClaimed),
+            None => {
+                handlers[irq as usize] = Some(handler);
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Masks and releases an IRQ line.
+pub fn unregister(irq: u8) {
+    if irq as usize >= IRQ_COUNT {
+        return;
+    }
+    cpu::without_interrupts(|| {
+        mask(irq);
+        HANDLERS.lock()[irq as usize] = None;
+    });
+}
+
+/// Unmasks an IRQ line, once its driver is ready to handle it.
+pub fn enable(irq: u8) {
+    if irq as usize >= IRQ_COUNT {
+        return;
+    }
+    cpu::without_interrupts(|| unmask(irq));
+}
+
+/// Masks an IRQ line.
+pub fn disable(irq: u8) {
+    if irq as usize >= IRQ_COUNT {
+        return;
+    }
+    cpu::without_interrupts(|| mask(irq));
+}
+
+/// Unmasks an IRQ line at the active interrupt controller.
+fn unmask(irq: u8) {
+    if apic::is_enabled() {
+        apic::unmask_isa(irq);
+    } else {
+        PIC::unmask(irq);
+    }
+}
+
+/// Masks an IRQ line at the active interrupt controller.
+fn mask(irq: u8) {
+    if apic::is_enabled() {
+        apic::mask_isa(irq);
+    } else {
+        PIC::mask(irq);
+    }
+}
+
+/// Dispatches a hardware IRQ to its registered handler.
+pub fn dispatch(irq: u8) {
+    let use_apic = apic::is_enabled();
+    if !use_apic && PIC::is_spurious(irq) {
+        return;
+    }
+    let handler = HANDLERS.lock()[irq as usize];
+    match handler {
+        Some(handler) => handler(irq),
+        None => report_unhandled(irq),
+    }
+    if use_apic {
+        apic::eoi();
+    } else {
+        PIC::eoi(irq);
+    }
+}
+
+/// Logs an unhandled IRQ, once per line.
+fn report_unhandled(irq: u8) {
+    let bit = 1 << irq;
+    if UNHANDLED_REPORTED.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+        klog!("*** Unhandled IRQ {}", irq);
+    }
+}
@@ -0,0 +1,390 @@
+#![allow(dead_code)]
+
+use core::mem;
+use spin::Mutex;
+use device::*;
+use keymap::{self, Keymap, KEYPAD};
+use ps2::{self, PS2_DEVICE_ACK, PS2_DEVICE_RESEND};
+use ringbuf::RingBuffer;
+use cpu;
+use irq;
+
+// General
+const KBD_IRQ: u8 = 1;
+const KBD_SET_LEDS: u8 = 0xED;
+
+// LEDs
+const KBD_LED_SCROLL_LOCK: u8 = 0x01;
+const KBD_LED_NUM_LOCK: u8 = 0x02;
+const KBD_LED_CAPS_LOCK: u8 = 0x04;
+
+// Prefixes
+const SC_EXTENDED: u8 = 0xE0;
+const SC_PAUSE: u8 = 0xE1;
+const SC_PAUSE_LENGTH: u8 = 5;
+const SC_RELEASE: u8 = 0x80;
+
+// Modifiers and locks
+const SC_CTRL: u8 = 0x1D;
+const SC_LSHIFT: u8 = 0x2A;
+const SC_RSHIFT: u8 = 0x36;
+const SC_ALT: u8 = 0x38;
+const SC_CAPS_LOCK: u8 = 0x3A;
+const SC_NUM_LOCK: u8 = 0x45;
+const SC_SCROLL_LOCK: u8 = 0x46;
+
+// Keypad
+const SC_KEYPAD_FIRST: u8 = 0x47;
+const SC_KEYPAD_LAST: u8 = 0x53;
+
+// Navigation (extended, or keypad without num lock)
+const SC_HOME: u8 = 0x47;
+const SC_UP: u8 = 0x48;
+const SC_PAGE_UP: u8 = 0x49;
+const SC_LEFT: u8 = 0x4B;
+const SC_RIGHT: u8 = 0x4D;
+const SC_END: u8 = 0x4F;
+const SC_DOWN: u8 = 0x50;
+const SC_PAGE_DOWN: u8 = 0x51;
+const SC_INSERT: u8 = 0x52;
+const SC_DELETE: u8 = 0x53;
+
+// Extended only
+const SC_KEYPAD_ENTER: u8 = 0x1C;
+const SC_KEYPAD_SLASH: u8 = 0x35;
+
+/// Modifier and lock state.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub altgr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// Decoded key press or release.
+#[derive(Copy, Clone, Debug)]
+pub struct KeyEvent {
+    pub scancode: u8,
+    pub extended: bool,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+}
+
+/// Key event callback, invoked from interrupt context.
+pub type KeyEventHandler = fn(KeyEvent);
+
+/// Progress of an LED update, which takes two bytes each acknowledged
+/// by the keyboard.
+#[derive(Copy, Clone, PartialEq)]
+enum LedState {
+    Idle,
+    /// `KBD_SET_LEDS` was sent, the LED bits follow its ACK.
+    Command,
+    /// The LED bits were sent and wait for their ACK.
+    Data,
+}
+
+/// Scan code set 1 decoder.
+struct Decoder {
+    extended: bool,
+    skip: u8,
+    lshift: bool,
+    rshift: bool,
+    lctrl: bool,
+    rctrl: bool,
+    modifiers: Modifiers,
+    layout: &'static Keymap,
+    handler: Option<KeyEventHandler>,
+    led_state: LedState,
+    leds_dirty: bool,
+}
+
+/// Keyboard device.
+pub struct KeyboardDevice;
+
+/// Decoder state, only touched from IRQ1 or with interrupts disabled.
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder {
+    extended: false,
+    skip: 0,
+    lshift: false,
+    rshift: false,
+    lctrl: false,
+    rctrl: false,
+    modifiers: Modifiers {
+        shift: false,
+        ctrl: false,
+        alt: false,
+        altgr: false,
+        caps_lock: false,
+        num_lock: false,
+        scroll_lock: false,
+    },
+    layout: &keymap::US_QWERTY,
+    handler: None,
+    led_state: LedState::Idle,
+    leds_dirty: false,
+});
+
+/// Translated input, between IRQ1 and readers.
+static INPUT: RingBuffer = RingBuffer::new();
+
+impl Decoder {
+    /// Feeds a byte from the controller, yielding an event once a
+    /// complete scan code has been received.
+    fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            PS2_DEVICE_ACK => {
+                self.acknowledge();
+                return None;
+            }
+            // The update is dropped, the next lock toggle sends it again.
+            PS2_DEVICE_RESEND if self.led_state != LedState::Idle => {
+                self.led_state = LedState::Idle;
+                return None;
+            }
+            SC_EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            SC_PAUSE => {
+                self.skip = SC_PAUSE_LENGTH;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = mem::replace(&mut self.extended, false);
+        let pressed = byte & SC_RELEASE == 0;
+        let scancode = byte & !SC_RELEASE;
+        // Fake shifts wrapped around extended keys by some keyboards.
+        if extended && (scancode == SC_LSHIFT || scancode == SC_RSHIFT) {
+            return None;
+        }
+        Some(KeyEvent {
+            scancode: scancode,
+            extended: extended,
+            pressed: pressed,
+            modifiers: self.modifiers,
+        })
+    }
+    /// Updates the modifier state. Returns `true` if a lock toggled.
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        let pressed = event.pressed;
+        match (event.scancode, event.extended) {
+            (SC_LSHIFT, false) => self.lshift = pressed,
+            (SC_RSHIFT, false) => self.rshift = pressed,
+            (SC_CTRL, false) => self.lctrl = pressed,
+            (SC_CTRL, true) => self.rctrl = pressed,
+            (SC_ALT, false) => self.modifiers.alt = pressed,
+            (SC_ALT, true) => self.modifiers.altgr = pressed,
+            (SC_CAPS_LOCK, false) if pressed => {
+                self.modifiers.caps_lock = !self.modifiers.caps_lock;
+                return true;
+            }
+            (SC_NUM_LOCK, false) if pressed => {
+                self.modifiers.num_lock = !self.modifiers.num_lock;
+                return true;
+            }
+            (SC_SCROLL_LOCK, false) if pressed => {
+                self.modifiers.scroll_lock = !self.modifiers.scroll_lock;
+                return true;
+            }
+            _ => {}
+        }
+        self.modifiers.shift = self.lshift || self.rshift;
+        self.modifiers.ctrl = self.lctrl || self.rctrl;
+        false
+    }
+    /// Translates a key press to input bytes.
+    fn emit(&self, event: &KeyEvent) {
+        if !event.pressed {
+            return;
+        }
+        let mods = event.modifiers;
+        let scancode = event.scancode;
+        let keypad = scancode >= SC_KEYPAD_FIRST && scancode <= SC_KEYPAD_LAST;
+        if event.extended || (is_keypad_navigation(scancode) && !mods.num_lock) {
+            push_all(escape_sequence(scancode, event.extended));
+            return;
+        }
+        if keypad {
+            INPUT.push(KEYPAD[(scancode - SC_KEYPAD_FIRST) as usize]);
+            return;
+        }
+        let mut c = self.layout.translate(scancode, mods.shift);
+        if c == 0 {
+            return;
+        }
+        if is_alphabetic(c) {
+            if mods.caps_lock {
+                c ^= 0x20;
+            }
+            if mods.ctrl {
+                c &= 0x1F;
+            }
+        }
+        INPUT.push(c);
+    }
+    /// Sends the LED bits, or queues them behind the update in flight.
+    fn update_leds(&mut self) {
+        if self.led_state == LedState::Idle {
+            self.send_leds_command();
+        } else {
+            self.leds_dirty = true;
+        }
+    }
+    fn send_leds_command(&mut self) {
+        self.leds_dirty = false;
+        self.led_state = match ps2::write_data(KBD_SET_LEDS) {
+            Ok(()) => LedState::Command,
+            Err(_) => LedState::Idle,
+        };
+    }
+    /// Advances the LED update on an ACK from the keyboard.
+    fn acknowledge(&mut self) {
+        match self.led_state {
+            LedState::Command => {
+                self.leds_dirty = false;
+                let leds = self.leds();
+                self.led_state = match ps2::write_data(leds) {
+                    Ok(()) => LedState::Data,
+                    Err(_) => LedState::Idle,
+                };
+            }
+            LedState::Data if self.leds_dirty => self.send_leds_command(),
+            _ => self.led_state = LedState::Idle,
+        }
+    }
+    /// Gets the LED bits for the current lock state.
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.modifiers.scroll_lock {
+            leds |= KBD_LED_SCROLL_LOCK;
+        }
+        if self.modifiers.num_lock {
+            leds |= KBD_LED_NUM_LOCK;
+        }
+        if self.modifiers.caps_lock {
+            leds |= KBD_LED_CAPS_LOCK;
+        }
+        leds
+    }
+}
+
+/// Gets the ANSI escape sequence for a navigation key.
+fn escape_sequence(scancode: u8, extended: bool) -> &'static [u8] {
+    match (scancode, extended) {
+        (SC_UP, _) => b"\x1B[A",
+        (SC_DOWN, _) => b"\x1B[B",
+        (SC_RIGHT, _) => b"\x1B[C",
+        (SC_LEFT, _) => b"\x1B[D",
+        (SC_HOME, _) => b"\x1B[H",
+        (SC_END, _) => b"\x1B[F",
+        (SC_INSERT, _) => b"\x1B[2~",
+        (SC_DELETE, _) => b"\x1B[3~",
+        (SC_PAGE_UP, _) => b"\x1B[5~",
+        (SC_PAGE_DOWN, _) => b"\x1B[6~",
+        (SC_KEYPAD_ENTER, true) => b"\n",
+        (SC_KEYPAD_SLASH, true) => b"/",
+        _ => b"",
+    }
+}
+
+/// Checks whether a keypad key doubles as a navigation key. The
+/// operators and the middle key always type their character.
+#[inline]
+fn is_keypad_navigation(scancode: u8) -> bool {
+    match scancode {
+        SC_HOME...SC_PAGE_UP | SC_LEFT | SC_RIGHT | SC_END...SC_DELETE => true,
+        _ => false,
+    }
+}
+
+#[inline]
+fn is_alphabetic(c: u8) -> bool {
+    (c >= b'a' && c <= b'z') || (c >= b'A' && c <= b'Z')
+}
+
+fn push_all(bytes: &[u8]) {
+    for b in bytes {
+        INPUT.push(*b);
+    }
+}
+
+/// Initializes the PS/2 controller and starts listening on IRQ1.
+pub fn init() -> bool {
+    if let Err(err) = ps2::init() {
+        klog!("[kbd] PS/2 controller unavailable: {:?}", err);
+        return false;
+    }
+    if irq::register(KBD_IRQ, interrupt).is_err() {
+        return false;
+    }
+    irq::enable(KBD_IRQ);
+    klog!("[kbd] PS/2 keyboard ready ({})", DECODER.lock().layout.name);
+    true
+}
+
+/// Selects a keyboard layout by name.
+pub fn set_layout(name: &str) -> bool {
+    for layout in keymap::LAYOUTS.iter() {
+        if layout.name == name {
+            cpu::without_interrupts(|| DECODER.lock().layout = *layout);
+            return true;
+        }
+    }
+    false
+}
+
+/// Installs a callback for raw key events.
+pub fn set_event_handler(handler: Option<KeyEventHandler>) {
+    cpu::without_interrupts(|| DECODER.lock().handler = handler);
+}
+
+/// IRQ1 handler.
+fn interrupt(_: u8) {
+    let byte = ps2::read_data_now();
+    let mut decoder = DECODER.lock();
+    let event = match decoder.feed(byte) {
+        Some(event) => event,
+        None => return,
+    };
+    if decoder.update(&event) {
+        decoder.update_leds();
+    }
+    decoder.emit(&event);
+    if let Some(handler) = decoder.handler {
+        handler(event);
+    }
+}
+
+impl KeyboardDevice {
+    /// Constructs a new keyboard device.
+    pub fn new() -> Self {
+        KeyboardDevice
+    }
+}
+
+impl DeviceRead for KeyboardDevice {
+    fn read(&mut self, _: &DeviceInfo, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        let mut count = 0;
+        while count < buf.len() {
+            match INPUT.pop() {
+                Some(b) => buf[count] = b,
+                None => break,
+            }
+            count += 1;
+        }
+        if count == 0 && !buf.is_empty() {
+            return Err(DeviceError::WouldBlock);
+        }
+        Ok(count)
+    }
+}
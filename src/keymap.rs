@@ -0,0 +1,42 @@
+#![allow(dead_code)]
+
+/// Number of scan codes covered by a keymap (the main key block).
+pub const KEYMAP_SIZE: usize = 0x3A;
+
+/// Keypad scan codes 0x47-0x53 with num lock on.
+pub static KEYPAD: &'static [u8; 13] = b"789-456+1230.";
+
+/// Keyboard layout, mapping set 1 make codes to ASCII.
+pub struct Keymap {
+    pub name: &'static str,
+    pub normal: &'static [u8; KEYMAP_SIZE],
+    pub shift: &'static [u8; KEYMAP_SIZE],
+}
+
+/// US QWERTY.
+pub static US_QWERTY: Keymap = Keymap {
+    name: "us",
+    normal: b"\0\x1B1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+    shift: b"\0\x1B!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+};
+
+/// US Dvorak.
+pub static US_DVORAK: Keymap = Keymap {
+    name: "dvorak",
+    normal: b"\0\x1B1234567890[]\x08\t',.pyfgcrl/=\n\0aoeuidhtns-`\0\\;qjkxbmwvz\0*\0 ",
+    shift: b"\0\x1B!@#$%^&*(){}\x08\t\"<>PYFGCRL?+\n\0AOEUIDHTNS_~\0|:QJKXBMWVZ\0*\0 ",
+};
+
+/// All available layouts.
+pub static LAYOUTS: [&'static Keymap; 2] = [&US_QWERTY, &US_DVORAK];
+
+impl Keymap {
+    /// Translates a make code to ASCII, or 0 if the key has no character.
+    pub fn translate(&self, scancode: u8, shift: bool) -> u8 {
+        let map = if shift { self.shift } else { self.normal };
+        match map.get(scancode as usize) {
+            Some(&c) => c,
+            None => 0,
+        }
+    }
+}
@@ -4,6 +4,7 @@
 // #![feature(core_intrinsics)]
 #![feature(static_in_const)]
 #![feature(lang_items)]
+#![feature(asm)]
 #![feature(const_fn)]
 #![feature(unique)]
 #![no_std]
@@ -17,15 +18,21 @@ extern crate lazy_static;
 #[macro_use]
 extern crate rcstring;
 
+// Interrupts are disabled while a device is locked for logging,
+// so that IRQ handlers can log without deadlocking.
 macro_rules! klog {
     ($f:expr $(,$arg:expr)*) => {
-        device_write!($crate::serial0, concat!($f, "\r\n") $(,$arg)*);
+        $crate::cpu::without_interrupts(|| {
+            device_write!($crate::serial0, concat!($f, "\r\n") $(,$arg)*);
+        });
     };
 }
 
 macro_rules! print {
     ($f:expr $(,$arg:expr)*) => {
-        device_write!($crate::ktty0, $f $(,$arg)*);
+        $crate::cpu::without_interrupts(|| {
+            device_write!($crate::ktty0, $f $(,$arg)*);
+        });
     };
 }
 
@@ -37,10 +44,44 @@ macro_rules! println {
 
 #[macro_use]
 mod device;
+mod acpi;
+mod ahci;
+mod apic;
+mod ata;
+mod block;
+mod bufcache;
+mod bytes;
+mod clock;
+mod cpu;
+mod devfs;
 mod heap;
+mod hpet;
+mod idt;
+mod initramfs;
+mod ioctl;
+mod irq;
+mod keyboard;
+mod keymap;
+mod partition;
+mod pci;
 mod pic;
+mod pit;
+mod ps2;
+mod ramdisk;
+mod ringbuf;
+mod rtc;
 mod serial;
 mod terminal;
+mod tmpfs;
+mod vfs;
+mod virtio;
+mod virtio_blk;
+
+use core::cmp;
+use device::{DeviceHandle, DeviceManager};
+
+/// End of the memory identity-mapped by the boot code.
+const IDENTITY_LIMIT: u64 = 0x100000000;
 
 /// Macro for constructing thread-safe devices.
 macro_rules! device {
@@ -60,21 +101,120 @@ device!(serial0,
         serial::SerialDevice,
         serial::SerialDevice::new(serial::SERIAL0));
 
+// /dev/serial1
+device!(serial1,
+        CharsDevice,
+        serial::SerialDevice,
+        serial::SerialDevice::new(serial::SERIAL1));
+
+// /dev/serial2
+device!(serial2,
+        CharsDevice,
+        serial::SerialDevice,
+        serial::SerialDevice::new(serial::SERIAL2));
+
+// /dev/serial3
+device!(serial3,
+        CharsDevice,
+        serial::SerialDevice,
+        serial::SerialDevice::new(serial::SERIAL3));
+
 // /dev/ktty0
 device!(ktty0,
         CharsDevice,
         terminal::TerminalDevice,
         terminal::TerminalDevice::new(terminal::VGA_PTR));
 
+// /dev/kbd0
+device!(kbd0,
+        CharsDevice,
+        keyboard::KeyboardDevice,
+        keyboard::KeyboardDevice::new());
+
 #[no_mangle]
 pub extern "C" fn kmain(mb_addr: usize) -> ! {
     let boot_info = unsafe { multiboot2::load(mb_addr) };
-    let mut heap: heap::Heap = heap::Heap::new(boot_info.end_address());
+    // Boot modules may be loaded past the boot information, so the heap
+    // starts after whichever ends last.
+    let heap_start = boot_info.module_tags()
+        .map(|module| module.end_address() as usize)
+        .fold(boot_info.end_address(), cmp::max);
+    // The heap grows over the available RAM area it starts in, up to the
+    // end of the identity-mapped low 4 GiB. It is left empty if no area
+    // holds its start.
+    let start = heap_start as u64;
+    let heap_end = boot_info.memory_map_tag()
+        .and_then(|map| {
+            map.memory_areas()
+                .find(|area| area.base_addr <= start && start < area.base_addr + area.length)
+        })
+        .map_or(heap_start,
+                |area| cmp::min(area.base_addr + area.length, IDENTITY_LIMIT) as usize);
+    heap::init(heap_start, heap_end);
+    idt::init();
     pic::PIC::remap();
+    if acpi::init() && apic::init() {
+        klog!("Interrupt controller: APIC");
+    } else {
+        klog!("Interrupt controller: 8259 PIC");
+    }
+    pit::init(pit::DEFAULT_FREQUENCY);
+    rtc::init();
+    hpet::init();
+    clock::init();
+    keyboard::init();
+    let ports: [&'static device::ThreadSafeDevice<serial::SerialDevice>; 4] =
+        [&serial0, &serial1, &serial2, &serial3];
+    for port in ports.iter() {
+        // The lock is released before logging, since serial0 is the log device.
+        let (info, base, kind) = {
+            let dev = port.lock();
+            if !dev.is_present() {
+                continue;
+            }
+            dev.enable_interrupts();
+            (dev.info, dev.port(), dev.kind())
+        };
+        register(DeviceHandle::new(info).with_read(*port).with_write(*port).with_ioctl(*port));
+        klog!("[serial] {} at {:#x}: {:?}", info.name(), base, kind);
+    }
+    let info = ktty0.lock().info;
+    register(DeviceHandle::new(info).with_write(&*ktty0).with_ioctl(&*ktty0));
+    let info = kbd0.lock().info;
+    register(DeviceHandle::new(info).with_read(&*kbd0));
+    pci::init();
+    ata::init();
+    ahci::init();
+    virtio_blk::init();
+    ramdisk::init(&boot_info);
+    if let Err(err) = ramdisk::create(ramdisk::DEFAULT_RAMDISK_SIZE) {
+        klog!("[ramdisk] Unable to create heap RAM disk: {:?}", err);
+    }
+    partition::init();
+    initramfs::init(&boot_info);
+    if let Err(err) = devfs::init() {
+        klog!("[vfs] Unable to mount /dev: {:?}", err);
+    }
+    if let Err(err) = tmpfs::init() {
+        klog!("[vfs] Unable to mount /tmp: {:?}", err);
+    }
+    for dev in DeviceManager::devices() {
+        klog!("[dev] {} (id {}, {:?})", dev.info().name(), dev.info().id(), dev.info().kind());
+    }
+    unsafe {
+        cpu::sti();
+    }
     println!("Hello from Hanami!");
     loop {}
 }
 
+/// Registers a built-in device, logging failures.
+fn register(handle: DeviceHandle) {
+    if let Err(err) = DeviceManager::register(handle) {
+        klog!("[dev] Unable to register {}: {:?}", handle.info().name(), err);
+    }
+}
+
 #[lang = "eh_personality"]
 extern "C" fn rust_eh_personality() {}
 
@@ -0,0 +1,368 @@
+#![allow(dead_code)]
+
+use block::{self, BlockDevice};
+use bufcache;
+use bytes;
+use device::*;
+use ioctl::IoctlClass;
+
+// Limits
+const PARTITION_MAX_SECTOR_SIZE: usize = 4096;
+const MBR_MAX_LOGICAL: usize = 64;
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+// MBR
+const MBR_SIGNATURE: usize = 510;
+const MBR_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_ENTRY_STATUS: usize = 0;
+const MBR_ENTRY_TYPE: usize = 4;
+const MBR_ENTRY_START: usize = 8;
+const MBR_ENTRY_COUNT: usize = 12;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED_CHS: u8 = 0x05;
+const MBR_TYPE_EXTENDED_LBA: u8 = 0x0F;
+const MBR_TYPE_EXTENDED_LINUX: u8 = 0x85;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_FIRST_LOGICAL: usize = 5;
+
+// GPT header
+const GPT_SIGNATURE: &'static [u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 12;
+const GPT_HEADER_CRC: usize = 16;
+const GPT_MY_LBA: usize = 24;
+const GPT_FIRST_USABLE: usize = 40;
+const GPT_LAST_USABLE: usize = 48;
+const GPT_ENTRIES_LBA: usize = 72;
+const GPT_ENTRY_COUNT: usize = 80;
+const GPT_ENTRY_SIZE: usize = 84;
+const GPT_ENTRIES_CRC: usize = 88;
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+
+// GPT entries
+const GPT_ENTRY_TYPE: usize = 0;
+const GPT_ENTRY_FIRST: usize = 32;
+const GPT_ENTRY_LAST: usize = 40;
+
+/// Contiguous range of sectors on a parent block device.
+///
+/// Requests go through the buffer cache of the parent, so a sector is
+/// cached once whether it is accessed through the partition or the
+/// disk.
+pub struct Partition {
+    parent: BlockHandle,
+    start: u64,
+    sectors: u64,
+    sector_size: usize,
+}
+
+/// Validated GPT header.
+#[derive(Copy, Clone)]
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Whole disk being scanned.
+struct Disk {
+    dev: BlockHandle,
+    name: &'static str,
+    sector_size: usize,
+    sectors: u64,
+    count: usize,
+}
+
+impl Partition {
+    /// Constructs a partition over `sectors` sectors of `parent`,
+    /// starting at `start`.
+    pub fn new(parent: BlockHandle, start: u64, sectors: u64) -> Self {
+        let sector_size = parent.lock().proto.sector_size();
+        Partition {
+            parent: parent,
+            start: start,
+            sectors: sectors,
+            sector_size: sector_size,
+        }
+    }
+    /// Gets the first sector on the parent device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        bufcache::read(self.parent, self.start + lba, buf)
+    }
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        bufcache::write(self.parent, self.start + lba, buf)
+    }
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        bufcache::sync(self.parent)
+    }
+    fn backing(&self) -> Option<(BlockHandle, u64)> {
+        Some((self.parent, self.start))
+    }
+}
+
+/// Updates a running CRC-32 (IEEE 802.3) with `data`.
+///
+/// Start with `!0` and invert the result.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Checks whether an MBR partition type marks an extended partition.
+fn is_extended(kind: u8) -> bool {
+    kind == MBR_TYPE_EXTENDED_CHS || kind == MBR_TYPE_EXTENDED_LBA ||
+    kind == MBR_TYPE_EXTENDED_LINUX
+}
+
+impl Disk {
+    /// Reads a sector into the start of `buf`.
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        bufcache::read(self.dev, lba, &mut buf[..self.sector_size])
+    }
+    /// Checks whether a sector range lies on the disk.
+    fn contains(&self, start: u64, sectors: u64) -> bool {
+        sectors != 0 && start.checked_add(sectors).map_or(false, |end| end <= self.sectors)
+    }
+    /// Registers a partition as `<disk>p<number>`.
+    fn add(&mut self, number: usize, start: u64, sectors: u64) {
+        if !self.contains(start, sectors) {
+            klog!("[part] {}: partition {} lies outside the disk", self.name, number);
+            return;
+        }
+        let name = match device_name(format_args!("{}p{}", self.name, number)) {
+            Some(name) => name,
+            None => return,
+        };
+        match block::register(Partition::new(self.dev, start, sectors), name) {
+            Ok(_) => {
+                self.count += 1;
+                klog!("[part] {}: start {}, {} KiB",
+                      name,
+                      start,
+                      sectors * self.sector_size as u64 / 1024);
+            }
+            Err(err) => klog!("[part] Unable to register {}: {:?}", name, err),
+        }
+    }
+    /// Reads the MBR. Returns `false` if the disk has no MBR.
+    fn scan_mbr(&mut self, buf: &mut [u8]) -> bool {
+        if self.read(0, buf).is_err() || bytes::read_u16(buf, MBR_SIGNATURE) != 0xAA55 {
+            return false;
+        }
+        let mut entries = [(0u8, 0u64, 0u64); 4];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let off = MBR_TABLE + i * MBR_ENTRY_SIZE;
+            // Boot sectors without a partition table carry code here.
+            if buf[off + MBR_ENTRY_STATUS] & 0x7F != 0 {
+                return false;
+            }
+            *entry = (buf[off + MBR_ENTRY_TYPE],
+                      bytes::read_u32(buf, off + MBR_ENTRY_START) as u64,
+                      bytes::read_u32(buf, off + MBR_ENTRY_COUNT) as u64);
+        }
+        if entries.iter().any(|entry| entry.0 == MBR_TYPE_GPT_PROTECTIVE) {
+            if !self.scan_gpt(buf) {
+                klog!("[part] {}: protective MBR without a valid GPT", self.name);
+            }
+            return true;
+        }
+        for (i, &(kind, start, sectors)) in entries.iter().enumerate() {
+            if kind == MBR_TYPE_EMPTY {
+                continue;
+            }
+            if is_extended(kind) {
+                self.scan_logical(start, buf);
+            } else {
+                self.add(i + 1, start, sectors);
+            }
+        }
+        true
+    }
+    /// Follows the chain of extended boot records of an extended
+    /// partition starting at `base`.
+    fn scan_logical(&mut self, base: u64, buf: &mut [u8]) {
+        let mut ebr = base;
+        for number in MBR_FIRST_LOGICAL..MBR_FIRST_LOGICAL + MBR_MAX_LOGICAL {
+            if self.read(ebr, buf).is_err() || bytes::read_u16(buf, MBR_SIGNATURE) != 0xAA55 {
+                klog!("[part] {}: invalid extended boot record at {}", self.name, ebr);
+                return;
+            }
+            let first = MBR_TABLE;
+            let next = MBR_TABLE + MBR_ENTRY_SIZE;
+            let kind = buf[first + MBR_ENTRY_TYPE];
+            let start = bytes::read_u32(buf, first + MBR_ENTRY_START) as u64;
+            let sectors = bytes::read_u32(buf, first + MBR_ENTRY_COUNT) as u64;
+            let next_kind = buf[next + MBR_ENTRY_TYPE];
+            let next_start = bytes::read_u32(buf, next + MBR_ENTRY_START) as u64;
+            if kind != MBR_TYPE_EMPTY {
+                self.add(number, ebr + start, sectors);
+            }
+            // Logical partitions are relative to their record, the next
+            // record is relative to the extended partition.
+            if !is_extended(next_kind) || next_start == 0 {
+                return;
+            }
+            ebr = base + next_start;
+        }
+    }
+    /// Reads and validates the GPT header at `lba`, including the CRC of
+    /// its entry array.
+    fn gpt_header(&self, lba: u64, buf: &mut [u8]) -> Option<GptHeader> {
+        if self.read(lba, buf).is_err() || &buf[..GPT_SIGNATURE.len()] != GPT_SIGNATURE {
+            return None;
+        }
+        let size = bytes::read_u32(buf, GPT_HEADER_SIZE) as usize;
+        if size < GPT_MIN_HEADER_SIZE || size > self.sector_size {
+            return None;
+        }
+        // The CRC covers the header with its own CRC field zeroed.
+        let mut crc = crc32_update(!0, &buf[..GPT_HEADER_CRC]);
+        crc = crc32_update(crc, &[0; 4]);
+        crc = !crc32_update(crc, &buf[GPT_HEADER_CRC + 4..size]);
+        if crc != bytes::read_u32(buf, GPT_HEADER_CRC) || bytes::read_u64(buf, GPT_MY_LBA) != lba {
+            return None;
+        }
+        let header = GptHeader {
+            first_usable: bytes::read_u64(buf, GPT_FIRST_USABLE),
+            last_usable: bytes::read_u64(buf, GPT_LAST_USABLE),
+            entries_lba: bytes::read_u64(buf, GPT_ENTRIES_LBA),
+            entry_count: bytes::read_u32(buf, GPT_ENTRY_COUNT),
+            entry_size: bytes::read_u32(buf, GPT_ENTRY_SIZE) as usize,
+            entries_crc: bytes::read_u32(buf, GPT_ENTRIES_CRC),
+        };
+        if header.entry_size < GPT_MIN_ENTRY_SIZE || self.sector_size % header.entry_size != 0 ||
+           header.entry_count > GPT_MAX_ENTRIES {
+            return None;
+        }
+        let mut crc = !0;
+        let valid = self.gpt_entries(&header, buf, |_, entry| crc = crc32_update(crc, entry));
+        if !valid || !crc != header.entries_crc {
+            return None;
+        }
+        Some(header)
+    }
+    /// Calls `f` with the index and bytes of every GPT entry.
+    /// Returns `false` if the entry array can't be read.
+    fn gpt_entries<F>(&self, header: &GptHeader, buf: &mut [u8], mut f: F) -> bool
+        where F: FnMut(usize, &[u8])
+    {
+        let per_sector = self.sector_size / header.entry_size;
+        let count = header.entry_count as usize;
+        let sectors = (count + per_sector - 1) / per_sector;
+        for sector in 0..sectors {
+            if self.read(header.entries_lba + sector as u64, buf).is_err() {
+                return false;
+            }
+            for slot in 0..per_sector {
+                let index = sector * per_sector + slot;
+                if index >= count {
+                    break;
+                }
+                let off = slot * header.entry_size;
+                f(index, &buf[off..off + header.entry_size]);
+            }
+        }
+        true
+    }
+    /// Reads the GPT, falling back to the backup header at the end of
+    /// the disk. Returns `false` if neither copy is valid.
+    fn scan_gpt(&mut self, buf: &mut [u8]) -> bool {
+        let header = match self.gpt_header(1, buf) {
+            Some(header) => header,
+            None => {
+                let backup = self.sectors - 1;
+                match self.gpt_header(backup, buf) {
+                    Some(header) => {
+                        klog!("[part] {}: primary GPT header invalid, using backup", self.name);
+                        header
+                    }
+                    None => return false,
+                }
+            }
+        };
+        let mut found = [(0u64, 0u64); GPT_MAX_ENTRIES as usize / 8];
+        let mut used = 0;
+        let mut numbers = [0usize; GPT_MAX_ENTRIES as usize / 8];
+        self.gpt_entries(&header, buf, |index, entry| {
+            if entry[GPT_ENTRY_TYPE..GPT_ENTRY_TYPE + 16].iter().all(|&byte| byte == 0) ||
+               used == found.len() {
+                return;
+            }
+            let first = bytes::read_u64(entry, GPT_ENTRY_FIRST);
+            let last = bytes::read_u64(entry, GPT_ENTRY_LAST);
+            if first < header.first_usable || last > header.last_usable || last < first {
+                return;
+            }
+            found[used] = (first, last - first + 1);
+            numbers[used] = index + 1;
+            used += 1;
+        });
+        for i in 0..used {
+            self.add(numbers[i], found[i].0, found[i].1);
+        }
+        true
+    }
+}
+
+/// Scans a disk for an MBR or GPT and registers its partitions as
+/// child block devices. Returns the number of partitions.
+pub fn scan(dev: BlockHandle) -> usize {
+    let (name, sector_size, sectors) = {
+        let dev = dev.lock();
+        (dev.info.name(), dev.proto.sector_size(), dev.proto.sector_count())
+    };
+    if sector_size < MBR_SIGNATURE + 2 || sector_size > PARTITION_MAX_SECTOR_SIZE || sectors < 2 {
+        return 0;
+    }
+    let mut disk = Disk {
+        dev: dev,
+        name: name,
+        sector_size: sector_size,
+        sectors: sectors,
+        count: 0,
+    };
+    let mut buf = [0u8; PARTITION_MAX_SECTOR_SIZE];
+    // GPT disks normally carry a protective MBR, but check anyway.
+    if !disk.scan_mbr(&mut buf) && !disk.scan_gpt(&mut buf) {
+        return 0;
+    }
+    disk.count
+}
+
+/// Scans every registered block device. Returns the number of partitions.
+pub fn init() -> usize {
+    // Take a snapshot first, so that new partitions aren't scanned.
+    let mut disks = [None; MAX_DEVICES];
+    for (slot, dev) in disks.iter_mut().zip(DeviceManager::supporting(IoctlClass::Block)) {
+        *slot = dev.block();
+    }
+    disks.iter().filter_map(|dev| *dev).map(scan).sum()
+}
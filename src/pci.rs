@@ -0,0 +1,553 @@
+#![allow(dead_code)]
+
+use core::fmt;
+use core::ptr;
+use spin::Mutex;
+use cpuio::{inl, outl};
+use acpi;
+
+// Configuration mechanism #1
+const PCI_CONFIG_ADDRESS: u16 = 0x0CF8;
+const PCI_CONFIG_DATA: u16 = 0x0CFC;
+const PCI_CONFIG_ENABLE: u32 = 0x80000000;
+
+// Limits
+const PCI_BUSES: usize = 256;
+const PCI_DEVICES_PER_BUS: u8 = 32;
+const PCI_FUNCTIONS_PER_DEVICE: u8 = 8;
+pub const PCI_MAX_DEVICES: usize = 64;
+pub const PCI_MAX_DRIVERS: usize = 16;
+const ECAM_LIMIT: u64 = 0x100000000;
+
+// Configuration header
+const PCI_VENDOR_ID: u8 = 0x00;
+const PCI_DEVICE_ID: u8 = 0x02;
+const PCI_COMMAND: u8 = 0x04;
+const PCI_STATUS: u8 = 0x06;
+const PCI_REVISION: u8 = 0x08;
+const PCI_PROG_IF: u8 = 0x09;
+const PCI_SUBCLASS: u8 = 0x0A;
+const PCI_CLASS: u8 = 0x0B;
+const PCI_HEADER_TYPE: u8 = 0x0E;
+const PCI_BAR0: u8 = 0x10;
+const PCI_SUBSYSTEM_VENDOR_ID: u8 = 0x2C;
+const PCI_SUBSYSTEM_ID: u8 = 0x2E;
+const PCI_CAPABILITIES: u8 = 0x34;
+const PCI_INTERRUPT_LINE: u8 = 0x3C;
+const PCI_INTERRUPT_PIN: u8 = 0x3D;
+const PCI_SECONDARY_BUS: u8 = 0x19;
+
+// Header types
+const PCI_HEADER_TYPE_MASK: u8 = 0x7F;
+const PCI_HEADER_MULTIFUNCTION: u8 = 0x80;
+const PCI_HEADER_GENERAL: u8 = 0x00;
+const PCI_HEADER_BRIDGE: u8 = 0x01;
+
+// Command
+pub const PCI_COMMAND_IO: u16 = 0x0001;
+pub const PCI_COMMAND_MEMORY: u16 = 0x0002;
+pub const PCI_COMMAND_BUS_MASTER: u16 = 0x0004;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 0x0400;
+
+// Status
+const PCI_STATUS_CAPABILITIES: u16 = 0x0010;
+
+// BARs
+const PCI_BAR_IO: u32 = 0x01;
+const PCI_BAR_TYPE_MASK: u32 = 0x06;
+const PCI_BAR_TYPE_64: u32 = 0x04;
+const PCI_BAR_PREFETCHABLE: u32 = 0x08;
+const PCI_BAR_IO_MASK: u32 = 0xFFFFFFFC;
+const PCI_BAR_MEMORY_MASK: u32 = 0xFFFFFFF0;
+
+// Vendors
+const PCI_VENDOR_NONE: u16 = 0xFFFF;
+
+/// Configuration space access method.
+#[derive(Copy, Clone)]
+enum ConfigAccess {
+    Port,
+    Ecam { base: usize, start_bus: u8, end_bus: u8 },
+}
+
+/// Bus, device and function of a PCI function.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// Decoded base address register.
+#[derive(Copy, Clone, Debug)]
+pub enum Bar {
+    None,
+    Io { port: u32, size: u32 },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        wide: bool,
+    },
+}
+
+/// PCI function found during enumeration.
+#[derive(Copy, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Bar; 6],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+}
+
+/// Device match. `None` fields match anything.
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+/// PCI driver, probed for every matching device.
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    /// Returns `true` if the driver took the device.
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+/// Enumerated device and its owning driver.
+#[derive(Copy, Clone)]
+struct Slot {
+    device: PciDevice,
+    driver: Option<&'static str>,
+}
+
+/// Enumerated devices and registered drivers.
+struct Bus {
+    devices: [Option<Slot>; PCI_MAX_DEVICES],
+    drivers: [Option<&'static PciDriver>; PCI_MAX_DRIVERS],
+}
+
+/// Active configuration access method.
+static ACCESS: Mutex<ConfigAccess> = Mutex::new(ConfigAccess::Port);
+
+static BUS: Mutex<Bus> = Mutex::new(Bus {
+    devices: [None; PCI_MAX_DEVICES],
+    drivers: [None; PCI_MAX_DRIVERS],
+});
+
+impl PciAddress {
+    /// Constructs a new `PciAddress`.
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus: bus,
+            device: device,
+            function: function,
+        }
+    }
+    /// Reads a configuration dword. The offset is rounded down.
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let offset = offset & 0xFC;
+        let access = ACCESS.lock();
+        match *access {
+            ConfigAccess::Ecam { base, start_bus, end_bus } if self.bus >= start_bus &&
+                                                               self.bus <= end_bus => unsafe {
+                ptr::read_volatile(self.ecam_address(base, start_bus, offset) as *const u32)
+            },
+            _ => unsafe {
+                outl(self.port_address(offset), PCI_CONFIG_ADDRESS);
+                inl(PCI_CONFIG_DATA)
+            },
+        }
+    }
+    /// Writes a configuration dword. The offset is rounded down.
+    pub fn write_u32(&self, offset: u8, val: u32) {
+        let offset = offset & 0xFC;
+        let access = ACCESS.lock();
+        match *access {
+            ConfigAccess::Ecam { base, start_bus, end_bus } if self.bus >= start_bus &&
+                                                               self.bus <= end_bus => unsafe {
+                ptr::write_volatile(self.ecam_address(base, start_bus, offset) as *mut u32,
+                                    val)
+            },
+            _ => unsafe {
+                outl(self.port_address(offset), PCI_CONFIG_ADDRESS);
+                outl(val, PCI_CONFIG_DATA);
+            },
+        }
+    }
+    /// Reads a configuration word.
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 0x02) * 8)) as u16
+    }
+    /// Reads a configuration byte.
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 0x03) * 8)) as u8
+    }
+    /// Writes a configuration word.
+    pub fn write_u16(&self, offset: u8, val: u16) {
+        let shift = (offset & 0x02) * 8;
+        let dword = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, dword | (val as u32) << shift);
+    }
+    /// Gets the address for mechanism #1.
+    fn port_address(&self, offset: u8) -> u32 {
+        PCI_CONFIG_ENABLE | (self.bus as u32) << 16 | (self.device as u32) << 11 |
+        (self.function as u32) << 8 | offset as u32
+    }
+    /// Gets the memory-mapped address in an ECAM region.
+    fn ecam_address(&self, base: usize, start_bus: u8, offset: u8) -> usize {
+        base +
+        (((self.bus - start_bus) as usize) << 20 | (self.device as usize) << 15 |
+         (self.function as usize) << 12 | offset as usize)
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+impl Bar {
+    /// Gets the address of a memory BAR.
+    pub fn memory_address(&self) -> Option<u64> {
+        match *self {
+            Bar::Memory { address, .. } => Some(address),
+            _ => None,
+        }
+    }
+    /// Gets the port of an I/O BAR.
+    pub fn io_port(&self) -> Option<u16> {
+        match *self {
+            Bar::Io { port, .. } => Some(port as u16),
+            _ => None,
+        }
+    }
+}
+
+impl PciDevice {
+    /// Reads and decodes the header of a present function.
+    fn read(address: PciAddress) -> Option<Self> {
+        let vendor_id = address.read_u16(PCI_VENDOR_ID);
+        if vendor_id == PCI_VENDOR_NONE {
+            return None;
+        }
+        let header_type = address.read_u8(PCI_HEADER_TYPE);
+        let mut device = PciDevice {
+            address: address,
+            vendor_id: vendor_id,
+            device_id: address.read_u16(PCI_DEVICE_ID),
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            class: address.read_u8(PCI_CLASS),
+            subclass: address.read_u8(PCI_SUBCLASS),
+            prog_if: address.read_u8(PCI_PROG_IF),
+            revision: address.read_u8(PCI_REVISION),
+            header_type: header_type,
+            bars: [Bar::None; 6],
+            interrupt_line: address.read_u8(PCI_INTERRUPT_LINE),
+            interrupt_pin: address.read_u8(PCI_INTERRUPT_PIN),
+        };
+        let bar_count = match header_type & PCI_HEADER_TYPE_MASK {
+            PCI_HEADER_GENERAL => {
+                device.subsystem_vendor_id = address.read_u16(PCI_SUBSYSTEM_VENDOR_ID);
+                device.subsystem_id = address.read_u16(PCI_SUBSYSTEM_ID);
+                6
+            }
+            PCI_HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+        device.decode_bars(bar_count);
+        Some(device)
+    }
+    /// Decodes and sizes the BARs, with decoding disabled meanwhile.
+    fn decode_bars(&mut self, count: usize) {
+        let address = self.address;
+        let command = address.read_u16(PCI_COMMAND);
+        address.write_u16(PCI_COMMAND,
+                          command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY));
+        let mut index = 0;
+        while index < count {
+            let offset = PCI_BAR0 + index as u8 * 4;
+            let bar = address.read_u32(offset);
+            address.write_u32(offset, 0xFFFFFFFF);
+            let mask = address.read_u32(offset);
+            address.write_u32(offset, bar);
+            if mask == 0 {
+                index += 1;
+                continue;
+            }
+            if bar & PCI_BAR_IO != 0 {
+                // Some devices hardwire the upper half of I/O BARs to zero.
+                let size = (!(mask & PCI_BAR_IO_MASK | 0xFFFF0000)).wrapping_add(1);
+                self.bars[index] = Bar::Io {
+                    port: bar & PCI_BAR_IO_MASK,
+                    size: size,
+                };
+                index += 1;
+                continue;
+            }
+            let wide = bar & PCI_BAR_TYPE_MASK == PCI_BAR_TYPE_64 && index + 1 < count;
+            let mut base = (bar & PCI_BAR_MEMORY_MASK) as u64;
+            let mut size_mask = (mask & PCI_BAR_MEMORY_MASK) as u64 | 0xFFFFFFFF00000000;
+            if wide {
+                let high_offset = offset + 4;
+                let high = address.read_u32(high_offset);
+                address.write_u32(high_offset, 0xFFFFFFFF);
+                let high_mask = address.read_u32(high_offset);
+                address.write_u32(high_offset, high);
+                base |= (high as u64) << 32;
+                size_mask = (size_mask & 0xFFFFFFFF) | (high_mask as u64) << 32;
+            }
+            self.bars[index] = Bar::Memory {
+                address: base,
+                size: (!size_mask).wrapping_add(1),
+                prefetchable: bar & PCI_BAR_PREFETCHABLE != 0,
+                wide: wide,
+            };
+            index += if wide { 2 } else { 1 };
+        }
+        address.write_u16(PCI_COMMAND, command);
+    }
+    /// Reads the command register.
+    pub fn command(&self) -> u16 {
+        self.address.read_u16(PCI_COMMAND)
+    }
+    /// Sets bits in the command register.
+    pub fn enable(&self, bits: u16) {
+        let command = self.command();
+        self.address.write_u16(PCI_COMMAND, command | bits);
+    }
+    /// Clears bits in the command register.
+    pub fn disable(&self, bits: u16) {
+        let command = self.command();
+        self.address.write_u16(PCI_COMMAND, command & !bits);
+    }
+    /// Enables memory decoding and bus mastering, as needed for DMA.
+    pub fn enable_bus_master(&self) {
+        self.enable(PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER);
+    }
+    /// Gets the class and subclass name.
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+    /// Checks whether the device matches an entry.
+    fn matches(&self, entry: &PciMatch) -> bool {
+        entry.vendor_id.map_or(true, |id| id == self.vendor_id) &&
+        entry.device_id.map_or(true, |id| id == self.device_id) &&
+        entry.class.map_or(true, |class| class == self.class) &&
+        entry.subclass.map_or(true, |subclass| subclass == self.subclass)
+    }
+    /// Walks the capability list.
+    pub fn capabilities(&self) -> Capabilities {
+        let offset = if self.address.read_u16(PCI_STATUS) & PCI_STATUS_CAPABILITIES != 0 {
+            self.address.read_u8(PCI_CAPABILITIES) & 0xFC
+        } else {
+            0
+        };
+        Capabilities {
+            address: self.address,
+            offset: offset,
+        }
+    }
+}
+
+/// Capability found in configuration space.
+#[derive(Copy, Clone, Debug)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+/// Iterator over the capability list of a function.
+pub struct Capabilities {
+    address: PciAddress,
+    offset: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+    fn next(&mut self) -> Option<Capability> {
+        if self.offset == 0 {
+            return None;
+        }
+        let offset = self.offset;
+        let header = self.address.read_u16(offset);
+        self.offset = (header >> 8) as u8 & 0xFC;
+        Some(Capability {
+            id: header as u8,
+            offset: offset,
+        })
+    }
+}
+
+/// Gets a human-readable name for a class and subclass.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, 0x01) => "VGA-compatible device",
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Audio device",
+        (0x04, 0x03) => "HD Audio controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI-to-PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "Serial bus controller",
+        (0x0D, _) => "Wireless controller",
+        (0xFF, _) => "Unassigned class",
+        _ => "Unknown device",
+    }
+}
+
+/// Probes the registered drivers against a device. Returns the
+/// name of the driver that took it.
+fn probe(drivers: &[Option<&'static PciDriver>], device: &PciDevice) -> Option<&'static str> {
+    for driver in drivers.iter().filter_map(|driver| *driver) {
+        if driver.matches.iter().any(|entry| device.matches(entry)) && (driver.probe)(device) {
+            return Some(driver.name);
+        }
+    }
+    None
+}
+
+/// Selects the configuration access method and enumerates all buses.
+/// Returns the number of functions found.
+pub fn init() -> usize {
+    if let Some(entry) = acpi::mcfg_entry(0) {
+        // Only the identity-mapped low 4 GiB are reachable.
+        if entry.base_address + ((entry.end_bus as u64 + 1) << 20) <= ECAM_LIMIT {
+            *ACCESS.lock() = ConfigAccess::Ecam {
+                base: entry.base_address as usize,
+                start_bus: entry.start_bus,
+                end_bus: entry.end_bus,
+            };
+            klog!("[pci] Using ECAM at {:#x}", entry.base_address);
+        }
+    }
+    let mut count = 0;
+    for bus in 0..PCI_BUSES {
+        for device in 0..PCI_DEVICES_PER_BUS {
+            let address = PciAddress::new(bus as u8, device, 0);
+            if address.read_u16(PCI_VENDOR_ID) == PCI_VENDOR_NONE {
+                continue;
+            }
+            let functions = if address.read_u8(PCI_HEADER_TYPE) & PCI_HEADER_MULTIFUNCTION != 0 {
+                PCI_FUNCTIONS_PER_DEVICE
+            } else {
+                1
+            };
+            for function in 0..functions {
+                if let Some(dev) = PciDevice::read(PciAddress::new(bus as u8, device, function)) {
+                    add(dev);
+                    count += 1;
+                }
+            }
+        }
+    }
+    count
+}
+
+/// Records an enumerated device.
+fn add(device: PciDevice) {
+    klog!("[pci] {} {:04x}:{:04x} {}",
+          device.address,
+          device.vendor_id,
+          device.device_id,
+          device.class_name());
+    let mut bus = BUS.lock();
+    match bus.devices.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(Slot {
+                device: device,
+                driver: None,
+            })
+        }
+        None => klog!("[pci] Device table full, ignoring {}", device.address),
+    }
+}
+
+/// Registers a driver and probes it against unclaimed devices.
+/// Returns the number of devices it took.
+pub fn register_driver(driver: &'static PciDriver) -> usize {
+    {
+        let mut bus = BUS.lock();
+        match bus.drivers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(driver),
+            None => return 0,
+        }
+    }
+    let mut count = 0;
+    for index in 0..PCI_MAX_DEVICES {
+        // Probes run unlocked, so drivers may look at the device list.
+        let device = match BUS.lock().devices[index] {
+            Some(Slot { device, driver: None }) => device,
+            _ => continue,
+        };
+        if let Some(name) = probe(&[Some(driver)], &device) {
+            if let Some(ref mut slot) = BUS.lock().devices[index] {
+                slot.driver = Some(name);
+            }
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Finds the first device matching an entry.
+pub fn find(entry: &PciMatch) -> Option<PciDevice> {
+    devices().find(|device| device.matches(entry))
+}
+
+/// Enumerates the devices found by `init`.
+pub fn devices() -> Devices {
+    Devices { index: 0 }
+}
+
+/// Iterator over enumerated devices.
+pub struct Devices {
+    index: usize,
+}
+
+impl Iterator for Devices {
+    type Item = PciDevice;
+    fn next(&mut self) -> Option<PciDevice> {
+        let bus = BUS.lock();
+        while self.index < PCI_MAX_DEVICES {
+            let slot = bus.devices[self.index];
+            self.index += 1;
+            if let Some(slot) = slot {
+                return Some(slot.device);
+            }
+        }
+        None
+    }
+}
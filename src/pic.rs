@@ -16,6 +16,15 @@ const PIC_MASTER_DATA: u16 = 0x21;
 const PIC_SLAVE_COMMAND: u16 = 0xA0;
 const PIC_SLAVE_DATA: u16 = 0xA1;
 
+// OCW 3
+const PIC_OCW3_READ_IRR: u8 = 0x0A;
+const PIC_OCW3_READ_ISR: u8 = 0x0B;
+
+// Lines
+const PIC_CASCADE_IRQ: u8 = 2;
+const PIC_MASTER_SPURIOUS_IRQ: u8 = 7;
+const PIC_SLAVE_SPURIOUS_IRQ: u8 = 15;
+
 // ICW 1
 const PIC_ICW1_ICW4: u8 = 0x01;
 const PIC_ICW1_SINGLE: u8 = 0x02;
@@ -40,11 +49,92 @@ const PIC_ICW4_SFNM: u8 = 0x10;
 
 pub struct PIC;
 impl PIC {
+    /// Remaps the PICs to 0x20-0x2F and masks every line.
     pub fn remap() {
         unsafe {
             PIC::remap_master();
             PIC::remap_slave();
-            PIC::enable();
+            PIC::mask_all();
+        }
+    }
+    /// Masks every line, for when the APIC takes over.
+    pub fn disable() {
+        unsafe {
+            PIC::mask_all();
+        }
+    }
+    /// Masks a single IRQ line.
+    pub fn mask(irq: u8) {
+        let imr = PIC::read_imr() | (1 << irq);
+        PIC::write_imr(imr);
+    }
+    /// Unmasks a single IRQ line.
+    ///
+    /// Unmasking a slave line also unmasks the cascade line on the master.
+    pub fn unmask(irq: u8) {
+        let mut imr = PIC::read_imr() & !(1 << irq);
+        if irq >= 8 {
+            imr &= !(1 << PIC_CASCADE_IRQ);
+        }
+        PIC::write_imr(imr);
+    }
+    /// Checks whether a single IRQ line is masked.
+    pub fn is_masked(irq: u8) -> bool {
+        PIC::read_imr() & (1 << irq) != 0
+    }
+    /// Reads the combined interrupt mask register of both PICs.
+    pub fn read_imr() -> u16 {
+        unsafe { (cpuio::inb(PIC_SLAVE_DATA) as u16) << 8 | cpuio::inb(PIC_MASTER_DATA) as u16 }
+    }
+    /// Writes the combined interrupt mask register of both PICs.
+    pub fn write_imr(imr: u16) {
+        unsafe {
+            cpuio::outb(imr as u8, PIC_MASTER_DATA);
+            cpuio::outb((imr >> 8) as u8, PIC_SLAVE_DATA);
+        }
+    }
+    /// Reads the combined interrupt request register of both PICs.
+    pub fn read_irr() -> u16 {
+        PIC::read_register(PIC_OCW3_READ_IRR)
+    }
+    /// Signals the end of an interrupt for the given IRQ line.
+    pub fn eoi(irq: u8) {
+        unsafe {
+            if irq >= 8 {
+                cpuio::outb(PIC_EOI, PIC_SLAVE_COMMAND);
+            }
+            cpuio::outb(PIC_EOI, PIC_MASTER_COMMAND);
+        }
+    }
+    /// Checks whether the given IRQ is a spurious IRQ7 or IRQ15.
+    ///
+    /// A spurious IRQ15 still has to be acknowledged on the master,
+    /// since the master did see a real interrupt on the cascade line.
+    pub fn is_spurious(irq: u8) -> bool {
+        let isr = PIC::read_isr();
+        match irq {
+            PIC_MASTER_SPURIOUS_IRQ => isr & (1 << PIC_MASTER_SPURIOUS_IRQ) == 0,
+            PIC_SLAVE_SPURIOUS_IRQ => {
+                if isr & (1 << PIC_SLAVE_SPURIOUS_IRQ) == 0 {
+                    PIC::eoi(PIC_CASCADE_IRQ);
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+    /// Reads the combined in-service register of both PICs.
+    pub fn read_isr() -> u16 {
+        PIC::read_register(PIC_OCW3_READ_ISR)
+    }
+    /// Reads a combined OCW3 register of both PICs.
+    fn read_register(ocw3: u8) -> u16 {
+        unsafe {
+            cpuio::outb(ocw3, PIC_MASTER_COMMAND);
+            cpuio::outb(ocw3, PIC_SLAVE_COMMAND);
+            (cpuio::inb(PIC_SLAVE_COMMAND) as u16) << 8 | cpuio::inb(PIC_MASTER_COMMAND) as u16
         }
     }
     #[inline]
@@ -62,9 +152,9 @@ impl PIC {
         PIC::outb_wait(PIC_SLAVE_DATA, PIC_ICW4_8086);
     }
     #[inline]
-    unsafe fn enable() {
-        PIC::outb_wait(PIC_MASTER_DATA, PIC_UNMASK);
-        PIC::outb_wait(PIC_SLAVE_DATA, PIC_UNMASK);
+    unsafe fn mask_all() {
+        PIC::outb_wait(PIC_MASTER_DATA, PIC_MASK);
+        PIC::outb_wait(PIC_SLAVE_DATA, PIC_MASK);
     }
     #[inline(always)]
     unsafe fn outb_wait(addr: u16, val: u8) {
@@ -0,0 +1,164 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpuio::{inb, outb};
+use clock;
+use cpu;
+use irq;
+
+// General
+pub const PIT_FREQUENCY: u32 = 1193182;
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+const PIT_IRQ: u8 = 0;
+
+// Ports
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+
+// Channel 2 gate
+const PIT_GATE_ENABLE: u8 = 0x01;
+const PIT_GATE_SPEAKER: u8 = 0x02;
+const PIT_GATE_OUTPUT: u8 = 0x20;
+
+// Commands
+const PIT_SELECT_CHANNEL0: u8 = 0x00;
+const PIT_SELECT_CHANNEL2: u8 = 0x80;
+const PIT_ACCESS_LATCH: u8 = 0x00;
+const PIT_ACCESS_LOHI: u8 = 0x30;
+const PIT_MODE_ONESHOT: u8 = 0x00;
+const PIT_MODE_RATE_GENERATOR: u8 = 0x04;
+
+/// Longest interval channel 2 can time in one shot.
+pub const CALIBRATION_MAX_MS: u32 = 50;
+
+/// Ticks since the PIT was started.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Configured tick frequency in Hz.
+static FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+
+/// Configured reload value of channel 0.
+static DIVISOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Programs channel 0 to fire IRQ0 at the given frequency and starts ticking.
+/// A frequency of 0 selects the slowest rate.
+pub fn init(frequency: u32) {
+    let divisor = match PIT_FREQUENCY.checked_div(frequency).unwrap_or(0xFFFF) {
+        0 => 1,
+        d if d > 0xFFFF => 0xFFFF,
+        d => d,
+    };
+    unsafe {
+        outb(PIT_SELECT_CHANNEL0 | PIT_ACCESS_LOHI | PIT_MODE_RATE_GENERATOR,
+             PIT_COMMAND);
+        outb(divisor as u8, PIT_CHANNEL0);
+        outb((divisor >> 8) as u8, PIT_CHANNEL0);
+    }
+    DIVISOR.store(divisor as usize, Ordering::SeqCst);
+    FREQUENCY.store((PIT_FREQUENCY / divisor) as usize, Ordering::SeqCst);
+    if irq::register(PIT_IRQ, tick).is_ok() {
+        irq::enable(PIT_IRQ);
+    }
+    klog!("[pit] Ticking at {} Hz", frequency());
+}
+
+/// IRQ0 handler.
+fn tick(_: u8) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    clock::tick();
+}
+
+/// Gets the number of ticks since the PIT was started.
+#[inline]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed) as u64
+}
+
+/// Gets the tick frequency in Hz.
+#[inline]
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed) as u32
+}
+
+/// Gets the monotonic uptime in milliseconds.
+pub fn uptime() -> u64 {
+    match frequency() {
+        0 => 0,
+        freq => ticks() * 1000 / freq as u64,
+    }
+}
+
+/// Sleeps for at least the given number of milliseconds.
+///
+/// Halts between ticks when interrupts are enabled, and busy-waits on
+/// the channel 0 counter otherwise.
+pub fn sleep(ms: u64) {
+    let freq = frequency() as u64;
+    if freq == 0 {
+        return;
+    }
+    if !cpu::interrupts_enabled() {
+        return busy_wait(ms);
+    }
+    let target = ticks() + (ms * freq + 999) / 1000;
+    while ticks() < target {
+        cpu::hlt();
+    }
+}
+
+/// Measures how far `sample` advances over `ms` milliseconds.
+///
+/// Uses channel 2 in one-shot mode, so channel 0 keeps ticking undisturbed.
+pub fn calibrate<F>(ms: u32, mut sample: F) -> u64
+    where F: FnMut() -> u64
+{
+    let ms = if ms > CALIBRATION_MAX_MS {
+        CALIBRATION_MAX_MS
+    } else {
+        ms
+    };
+    let count = PIT_FREQUENCY * ms / 1000;
+    cpu::without_interrupts(|| unsafe {
+        let gate = inb(PIT_GATE) & !PIT_GATE_SPEAKER;
+        outb(gate & !PIT_GATE_ENABLE, PIT_GATE);
+        outb(PIT_SELECT_CHANNEL2 | PIT_ACCESS_LOHI | PIT_MODE_ONESHOT,
+             PIT_COMMAND);
+        outb(count as u8, PIT_CHANNEL2);
+        outb((count >> 8) as u8, PIT_CHANNEL2);
+        let start = sample();
+        outb(gate | PIT_GATE_ENABLE, PIT_GATE);
+        while inb(PIT_GATE) & PIT_GATE_OUTPUT == 0 {}
+        let end = sample();
+        outb(gate & !PIT_GATE_ENABLE, PIT_GATE);
+        end.wrapping_sub(start)
+    })
+}
+
+/// Busy-waits by counting PIT input clock cycles on channel 0.
+fn busy_wait(ms: u64) {
+    let reload = DIVISOR.load(Ordering::Relaxed) as u64;
+    let mut remaining = ms * PIT_FREQUENCY as u64 / 1000;
+    let mut last = read_count() as u64;
+    while remaining > 0 {
+        let now = read_count() as u64;
+        let elapsed = if now <= last {
+            last - now
+        } else {
+            last + reload - now
+        };
+        remaining = remaining.saturating_sub(elapsed);
+        last = now;
+    }
+}
+
+/// Latches and reads the current count of channel 0.
+fn read_count() -> u16 {
+    cpu::without_interrupts(|| unsafe {
+        outb(PIT_SELECT_CHANNEL0 | PIT_ACCESS_LATCH, PIT_COMMAND);
+        let low = inb(PIT_CHANNEL0) as u16;
+        let high = inb(PIT_CHANNEL0) as u16;
+        high << 8 | low
+    })
+}
@@ -0,0 +1,149 @@
+#![allow(dead_code)]
+
+use cpuio::{inb, outb};
+use cpu;
+
+// Ports
+const PS2_DATA: u16 = 0x60;
+const PS2_STATUS: u16 = 0x64;
+const PS2_COMMAND: u16 = 0x64;
+
+// Status
+const PS2_STATUS_OUTPUT_FULL: u8 = 0x01;
+const PS2_STATUS_INPUT_FULL: u8 = 0x02;
+
+// Controller commands
+const PS2_READ_CONFIG: u8 = 0x20;
+const PS2_WRITE_CONFIG: u8 = 0x60;
+const PS2_DISABLE_PORT2: u8 = 0xA7;
+const PS2_SELF_TEST: u8 = 0xAA;
+const PS2_TEST_PORT1: u8 = 0xAB;
+const PS2_DISABLE_PORT1: u8 = 0xAD;
+const PS2_ENABLE_PORT1: u8 = 0xAE;
+
+// Controller responses
+const PS2_SELF_TEST_OK: u8 = 0x55;
+const PS2_PORT_TEST_OK: u8 = 0x00;
+
+// Configuration byte
+const PS2_CONFIG_PORT1_IRQ: u8 = 0x01;
+const PS2_CONFIG_PORT2_IRQ: u8 = 0x02;
+const PS2_CONFIG_TRANSLATION: u8 = 0x40;
+
+// Device commands
+const PS2_DEVICE_RESET: u8 = 0xFF;
+pub const PS2_DEVICE_ACK: u8 = 0xFA;
+pub const PS2_DEVICE_RESEND: u8 = 0xFE;
+const PS2_DEVICE_RESET_OK: u8 = 0xAA;
+
+/// Polling iterations before a controller access times out.
+const PS2_TIMEOUT: usize = 100000;
+
+/// Error raised while initializing the 8042 controller.
+#[derive(Debug)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    ResetFailed(u8),
+}
+
+/// Initializes the 8042 controller and the keyboard on port 1.
+///
+/// Scan code translation stays enabled, so the keyboard is seen
+/// through scan code set 1 regardless of its native set.
+pub fn init() -> Result<(), Ps2Error> {
+    cpu::without_interrupts(|| {
+        command(PS2_DISABLE_PORT1)?;
+        command(PS2_DISABLE_PORT2)?;
+        flush();
+
+        command(PS2_READ_CONFIG)?;
+        let config = read_data()? & !(PS2_CONFIG_PORT1_IRQ | PS2_CONFIG_PORT2_IRQ);
+        command(PS2_WRITE_CONFIG)?;
+        write_data(config)?;
+
+        command(PS2_SELF_TEST)?;
+        match read_data()? {
+            PS2_SELF_TEST_OK => {}
+            res => return Err(Ps2Error::SelfTestFailed(res)),
+        }
+        // Some controllers reset their configuration during the self-test.
+        command(PS2_WRITE_CONFIG)?;
+        write_data(config)?;
+
+        command(PS2_TEST_PORT1)?;
+        match read_data()? {
+            PS2_PORT_TEST_OK => {}
+            res => return Err(Ps2Error::PortTestFailed(res)),
+        }
+
+        command(PS2_ENABLE_PORT1)?;
+        write_data(PS2_DEVICE_RESET)?;
+        match read_data()? {
+            PS2_DEVICE_ACK => {}
+            res => return Err(Ps2Error::ResetFailed(res)),
+        }
+        match read_data()? {
+            PS2_DEVICE_RESET_OK => {}
+            res => return Err(Ps2Error::ResetFailed(res)),
+        }
+
+        command(PS2_WRITE_CONFIG)?;
+        write_data(config | PS2_CONFIG_PORT1_IRQ | PS2_CONFIG_TRANSLATION)?;
+        Ok(())
+    })
+}
+
+/// Sends a command to the controller.
+fn command(cmd: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe {
+        outb(cmd, PS2_COMMAND);
+    }
+    Ok(())
+}
+
+/// Reads a byte from the data port, once one is available.
+pub fn read_data() -> Result<u8, Ps2Error> {
+    for _ in 0..PS2_TIMEOUT {
+        if unsafe { inb(PS2_STATUS) } & PS2_STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { inb(PS2_DATA) });
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Reads a byte from the data port without waiting, e.g. from IRQ1.
+#[inline]
+pub fn read_data_now() -> u8 {
+    unsafe { inb(PS2_DATA) }
+}
+
+/// Writes a byte to the device on port 1.
+pub fn write_data(b: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe {
+        outb(b, PS2_DATA);
+    }
+    Ok(())
+}
+
+/// Waits until the controller accepts input.
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..PS2_TIMEOUT {
+        if unsafe { inb(PS2_STATUS) } & PS2_STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Discards any pending output.
+fn flush() {
+    while unsafe { inb(PS2_STATUS) } & PS2_STATUS_OUTPUT_FULL != 0 {
+        unsafe {
+            inb(PS2_DATA);
+        }
+    }
+}
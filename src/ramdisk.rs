@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use multiboot2::BootInformation;
+use block::{self, BlockDevice};
+use device::*;
+use heap;
+
+/// Sector size of RAM disks.
+pub const RAMDISK_SECTOR_SIZE: usize = 512;
+
+/// Default size of the heap-backed RAM disk.
+pub const DEFAULT_RAMDISK_SIZE: usize = 1024 * 1024;
+
+/// Command line of boot modules holding disk images.
+pub const RAMDISK_MODULE_TAG: &'static str = "ramdisk";
+
+/// Next RAM disk number.
+static NEXT_RAMDISK: AtomicUsize = AtomicUsize::new(0);
+
+/// Block device backed by memory.
+pub struct RamDisk {
+    base: *mut u8,
+    sectors: u64,
+}
+
+unsafe impl Send for RamDisk {}
+
+impl RamDisk {
+    /// Constructs a RAM disk over an existing memory range.
+    ///
+    /// Trailing bytes that don't fill a whole sector are ignored.
+    pub unsafe fn new(base: usize, len: usize) -> Self {
+        RamDisk {
+            base: base as *mut u8,
+            sectors: (len / RAMDISK_SECTOR_SIZE) as u64,
+        }
+    }
+    /// Constructs a zeroed RAM disk on the kernel heap.
+    pub fn alloc(size: usize) -> Option<Self> {
+        let len = size - size % RAMDISK_SECTOR_SIZE;
+        heap::kalloc(len).map(|base| unsafe { RamDisk::new(base as usize, len) })
+    }
+    /// Gets the contents of the disk.
+    fn bytes(&mut self) -> &mut [u8] {
+        let len = self.sectors as usize * RAMDISK_SECTOR_SIZE;
+        unsafe { slice::from_raw_parts_mut(self.base, len) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        RAMDISK_SECTOR_SIZE
+    }
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        let start = lba as usize * RAMDISK_SECTOR_SIZE;
+        buf.copy_from_slice(&self.bytes()[start..start + buf.len()]);
+        Ok(())
+    }
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        let start = lba as usize * RAMDISK_SECTOR_SIZE;
+        self.bytes()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+/// Registers a RAM disk as the next `ramN` device.
+pub fn register(disk: RamDisk) -> Result<BlockHandle, RegistryError> {
+    let index = NEXT_RAMDISK.fetch_add(1, Ordering::SeqCst);
+    let name = match device_name(format_args!("ram{}", index)) {
+        Some(name) => name,
+        None => return Err(RegistryError::OutOfMemory),
+    };
+    let size = disk.sectors * RAMDISK_SECTOR_SIZE as u64;
+    let dev = block::register(disk, name)?;
+    klog!("[ramdisk] {}: {} KiB", name, size / 1024);
+    Ok(dev)
+}
+
+/// Checks whether a module command line selects a disk image.
+fn is_disk_image(cmdline: &str) -> bool {
+    cmdline.split(' ').next() == Some(RAMDISK_MODULE_TAG)
+}
+
+/// Registers a RAM disk for every boot module tagged as a disk image.
+/// Returns the number of disks.
+pub fn init(boot_info: &BootInformation) -> usize {
+    let mut count = 0;
+    for module in boot_info.module_tags() {
+        if !is_disk_image(module.name()) {
+            continue;
+        }
+        let start = module.start_address() as usize;
+        let end = module.end_address() as usize;
+        let disk = unsafe { RamDisk::new(start, end - start) };
+        match register(disk) {
+            Ok(_) => count += 1,
+            Err(err) => klog!("[ramdisk] Unable to register module at {:#x}: {:?}", start, err),
+        }
+    }
+    count
+}
+
+/// Creates and registers a writable RAM disk on the kernel heap.
+pub fn create(size: usize) -> Result<BlockHandle, RegistryError> {
+    match RamDisk::alloc(size) {
+        Some(disk) => register(disk),
+        None => Err(RegistryError::OutOfMemory),
+    }
+}
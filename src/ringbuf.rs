@@ -0,0 +1,63 @@
+#![allow(dead_code)]
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Ring buffer capacity, must be a power of two.
+pub const RING_SIZE: usize = 256;
+
+/// Lock-free single-producer, single-consumer byte ring buffer.
+///
+/// Meant to sit between an interrupt handler (the producer) and readers
+/// serialized by a device lock (the consumer), so that neither side
+/// ever has to spin on a lock the other one holds.
+pub struct RingBuffer {
+    buf: UnsafeCell<[u8; RING_SIZE]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    /// Constructs a new, empty `RingBuffer`.
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: UnsafeCell::new([0; RING_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+    /// Appends a byte. Returns `false` if the buffer is full.
+    pub fn push(&self, b: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= RING_SIZE {
+            return false;
+        }
+        unsafe {
+            (*self.buf.get())[tail & (RING_SIZE - 1)] = b;
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+    /// Removes the oldest byte.
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let b = unsafe { (*self.buf.get())[head & (RING_SIZE - 1)] };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(b)
+    }
+    /// Gets the number of buffered bytes.
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+    /// Checks whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
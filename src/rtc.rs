@@ -0,0 +1,245 @@
+#![allow(dead_code)]
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use cpuio::{inb, outb};
+use acpi;
+use cpu;
+use irq::{self, IrqError};
+
+// Ports
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// Registers
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+
+// Status A
+const RTC_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const RTC_A_RATE_MASK: u8 = 0x0F;
+
+// Status B
+const RTC_B_24_HOUR: u8 = 0x02;
+const RTC_B_BINARY: u8 = 0x04;
+const RTC_B_PERIODIC: u8 = 0x40;
+
+// Hours
+const RTC_HOUR_PM: u8 = 0x80;
+
+// Periodic interrupt
+const RTC_IRQ: u8 = 8;
+const RTC_BASE_FREQUENCY: u32 = 32768;
+pub const RTC_MIN_RATE: u8 = 3;
+pub const RTC_MAX_RATE: u8 = 15;
+
+/// Century assumed when ACPI does not provide a century register.
+const DEFAULT_CENTURY: u16 = 20;
+
+/// Wall-clock date and time.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Raw register snapshot, used to detect torn reads.
+#[derive(Copy, Clone, PartialEq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// CMOS index of the century register, if any.
+static CENTURY_REGISTER: Mutex<Option<u8>> = Mutex::new(None);
+
+/// Periodic interrupts since `enable_periodic`.
+static PERIODIC_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether IRQ8 is claimed by the periodic interrupt handler.
+static PERIODIC_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+impl DateTime {
+    /// Gets the number of seconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 +
+        self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year,
+               self.month,
+               self.day,
+               self.hour,
+               self.minute,
+               self.second)
+    }
+}
+
+/// Looks up the century register and logs the current time.
+pub fn init() {
+    *CENTURY_REGISTER.lock() = acpi::century_register();
+    klog!("[rtc] {}", now());
+}
+
+/// Reads the current wall-clock time.
+pub fn now() -> DateTime {
+    let century = *CENTURY_REGISTER.lock();
+    let (raw, status_b) = cpu::without_interrupts(|| {
+        // Read until two consecutive snapshots agree, so an update
+        // that starts midway through cannot tear the result.
+        let mut last = read_raw(century);
+        loop {
+            let current = read_raw(century);
+            if current == last {
+                break;
+            }
+            last = current;
+        }
+        (last, read_register(RTC_STATUS_B))
+    });
+    convert(raw, status_b, century.is_some())
+}
+
+/// Enables the periodic interrupt on IRQ8 at `32768 >> (rate - 1)` Hz.
+/// Returns the frequency, or an error if IRQ8 belongs to another driver.
+pub fn enable_periodic(rate: u8) -> Result<u32, IrqError> {
+    let rate = if rate < RTC_MIN_RATE {
+        RTC_MIN_RATE
+    } else if rate > RTC_MAX_RATE {
+        RTC_MAX_RATE
+    } else {
+        rate
+    };
+    if !PERIODIC_CLAIMED.swap(true, Ordering::SeqCst) {
+        if let Err(err) = irq::register(RTC_IRQ, periodic) {
+            PERIODIC_CLAIMED.store(false, Ordering::SeqCst);
+            return Err(err);
+        }
+    }
+    cpu::without_interrupts(|| {
+        let a = read_register(RTC_STATUS_A);
+        write_register(RTC_STATUS_A, (a & !RTC_A_RATE_MASK) | rate);
+        let b = read_register(RTC_STATUS_B);
+        write_register(RTC_STATUS_B, b | RTC_B_PERIODIC);
+        read_register(RTC_STATUS_C);
+    });
+    irq::enable(RTC_IRQ);
+    Ok(RTC_BASE_FREQUENCY >> (rate - 1))
+}
+
+/// Disables the periodic interrupt.
+pub fn disable_periodic() {
+    if !PERIODIC_CLAIMED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    irq::unregister(RTC_IRQ);
+    cpu::without_interrupts(|| {
+        let b = read_register(RTC_STATUS_B);
+        write_register(RTC_STATUS_B, b & !RTC_B_PERIODIC);
+    });
+}
+
+/// Gets the number of periodic interrupts received.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed) as u64
+}
+
+/// IRQ8 handler.
+fn periodic(_: u8) {
+    // Status C has to be read, or the RTC will not raise IRQ8 again.
+    read_register(RTC_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Reads all time registers once no update is in progress.
+fn read_raw(century: Option<u8>) -> RawTime {
+    while read_register(RTC_STATUS_A) & RTC_A_UPDATE_IN_PROGRESS != 0 {}
+    RawTime {
+        second: read_register(RTC_SECONDS),
+        minute: read_register(RTC_MINUTES),
+        hour: read_register(RTC_HOURS),
+        day: read_register(RTC_DAY),
+        month: read_register(RTC_MONTH),
+        year: read_register(RTC_YEAR),
+        century: century.map_or(0, read_register),
+    }
+}
+
+/// Converts a raw snapshot to binary, 24 hour `DateTime`.
+fn convert(raw: RawTime, status_b: u8, has_century: bool) -> DateTime {
+    let binary = status_b & RTC_B_BINARY != 0;
+    let decode = |v: u8| if binary { v } else { (v >> 4) * 10 + (v & 0x0F) };
+    let pm = raw.hour & RTC_HOUR_PM != 0;
+    let mut hour = decode(raw.hour & !RTC_HOUR_PM);
+    if status_b & RTC_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = if has_century {
+        decode(raw.century) as u16
+    } else {
+        DEFAULT_CENTURY
+    };
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour: hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Reads a CMOS register.
+#[inline]
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        outb(reg, CMOS_INDEX);
+        inb(CMOS_DATA)
+    }
+}
+
+/// Writes a CMOS register.
+#[inline]
+fn write_register(reg: u8, val: u8) {
+    unsafe {
+        outb(reg, CMOS_INDEX);
+        outb(val, CMOS_DATA);
+    }
+}
@@ -1,13 +1,15 @@
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use device::*;
+use ioctl::{Ioctl, IoctlClass, IoctlReply, ModemLines, SerialIoctl};
+use ringbuf::{RingBuffer, RING_SIZE};
 use cpuio::{inb, outb};
+use cpu;
+use irq;
 
-#[allow(dead_code)]
 pub const SERIAL0: u16 = 0x03F8;
-#[allow(dead_code)]
 pub const SERIAL1: u16 = 0x02F8;
-#[allow(dead_code)]
 pub const SERIAL2: u16 = 0x03E8;
-#[allow(dead_code)]
 pub const SERIAL3: u16 = 0x02E8;
 
 macro_rules! serial_ier { ($port:expr) => ($port + 0x01); }
@@ -16,53 +18,609 @@ macro_rules! serial_fifo { ($port:expr) => ($port + 0x02); }
 macro_rules! serial_line { ($port:expr) => ($port + 0x03); }
 macro_rules! serial_modem { ($port:expr) => ($port + 0x04); }
 macro_rules! serial_line_status { ($port:expr) => ($port + 0x05); }
+macro_rules! serial_modem_status { ($port:expr) => ($port + 0x06); }
+macro_rules! serial_scratch { ($port:expr) => ($port + 0x07); }
+
+// General
+const SERIAL_CLOCK: u32 = 115200;
+pub const DEFAULT_BAUD_RATE: u32 = 38400;
+
+// Line control
+const SERIAL_LCR_STOP_BITS_2: u8 = 0x04;
+const SERIAL_LCR_PARITY_ODD: u8 = 0x08;
+const SERIAL_LCR_PARITY_EVEN: u8 = 0x18;
+const SERIAL_LCR_PARITY_MARK: u8 = 0x28;
+const SERIAL_LCR_PARITY_SPACE: u8 = 0x38;
+const SERIAL_LCR_DLAB: u8 = 0x80;
+
+// FIFO control
+const SERIAL_FCR_ENABLE: u8 = 0x01;
+const SERIAL_FCR_CLEAR: u8 = 0x06;
+const SERIAL_FCR_64_BYTE: u8 = 0x20;
+const SERIAL_FCR_TRIGGER_14: u8 = 0xC0;
+
+// Interrupt identification
+const SERIAL_IIR_FIFO_MASK: u8 = 0xC0;
+const SERIAL_IIR_FIFO_ENABLED: u8 = 0xC0;
+const SERIAL_IIR_FIFO_BROKEN: u8 = 0x80;
+const SERIAL_IIR_FIFO_64_BYTE: u8 = 0x20;
+
+// Modem status
+const SERIAL_MSR_CTS: u8 = 0x10;
+const SERIAL_MSR_DSR: u8 = 0x20;
+const SERIAL_MSR_RI: u8 = 0x40;
+const SERIAL_MSR_DCD: u8 = 0x80;
+
+// Probing
+const SERIAL_PROBE_PATTERN: u8 = 0xA5;
+const SERIAL_LOOPBACK_PATTERN: u8 = 0xAE;
+
+/// Polling iterations before waiting on the UART gives up.
+const SERIAL_TIMEOUT: usize = 100000;
+
+// Interrupt enable
+const SERIAL_IER_RX_AVAILABLE: u8 = 0x01;
+const SERIAL_IER_LINE_STATUS: u8 = 0x04;
+
+// Modem control
+const SERIAL_MCR_DTR: u8 = 0x01;
+const SERIAL_MCR_RTS: u8 = 0x02;
+const SERIAL_MCR_OUT2: u8 = 0x08;
+const SERIAL_MCR_LOOPBACK: u8 = 0x10;
+
+// Line status
+const SERIAL_LSR_DATA_READY: u8 = 0x01;
+const SERIAL_LSR_OVERRUN: u8 = 0x02;
+const SERIAL_LSR_PARITY: u8 = 0x04;
+const SERIAL_LSR_FRAMING: u8 = 0x08;
+const SERIAL_LSR_BREAK: u8 = 0x10;
+const SERIAL_LSR_TX_EMPTY: u8 = 0x20;
+
+// Flow control thresholds
+const RX_HIGH_WATER: usize = RING_SIZE * 3 / 4;
+const RX_LOW_WATER: usize = RING_SIZE / 4;
+
+// IRQs
+const SERIAL_IRQ_COM1_COM3: u8 = 4;
+const SERIAL_IRQ_COM2_COM4: u8 = 3;
+
+/// Number of data bits per character.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DataBits {
+    Five = 0,
+    Six = 1,
+    Seven = 2,
+    Eight = 3,
+}
+
+/// Parity mode.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+/// Number of stop bits.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Flow control mode.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+}
+
+/// UART model, as detected at initialization.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UartKind {
+    Absent,
+    U8250,
+    U16450,
+    U16550,
+    U16550A,
+    U16750,
+}
+
+/// Serial line settings.
+#[derive(Copy, Clone, Debug)]
+pub struct SerialConfig {
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+}
+
+impl SerialConfig {
+    /// Constructs the default configuration (38400 baud, 8N1, no flow control).
+    pub fn new() -> Self {
+        SerialConfig {
+            baud_rate: DEFAULT_BAUD_RATE,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+    /// Sets the baud rate.
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+    /// Sets the number of data bits.
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+    /// Sets the parity mode.
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+    /// Sets the number of stop bits.
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+    /// Sets the flow control mode.
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+    /// Gets the baud rate divisor.
+    fn divisor(&self) -> u16 {
+        match self.baud_rate {
+            0 => 0xFFFF,
+            baud if baud >= SERIAL_CLOCK => 1,
+            baud => cmp::min(SERIAL_CLOCK / baud, 0xFFFF) as u16,
+        }
+    }
+    /// Gets the line control register value.
+    fn line_control(&self) -> u8 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => SERIAL_LCR_PARITY_ODD,
+            Parity::Even => SERIAL_LCR_PARITY_EVEN,
+            Parity::Mark => SERIAL_LCR_PARITY_MARK,
+            Parity::Space => SERIAL_LCR_PARITY_SPACE,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => SERIAL_LCR_STOP_BITS_2,
+        };
+        self.data_bits as u8 | stop | parity
+    }
+}
+
+/// Detects the UART at the given port.
+///
+/// A loopback test rules out empty ports, the scratch register tells
+/// an 8250 apart, and the FIFO probe tells the 16450/16550 variants apart.
+pub fn probe(port: u16) -> UartKind {
+    unsafe {
+        if inb(serial_line_status!(port)) == 0xFF {
+            return UartKind::Absent;
+        }
+        let mcr = inb(serial_modem!(port));
+        outb(SERIAL_MCR_LOOPBACK | SERIAL_MCR_DTR | SERIAL_MCR_RTS,
+             serial_modem!(port));
+        // Drop stale input, then give the byte one character time to
+        // loop back.
+        for _ in 0..SERIAL_TIMEOUT {
+            if inb(serial_line_status!(port)) & SERIAL_LSR_DATA_READY == 0 {
+                break;
+            }
+            inb(serial_data!(port));
+        }
+        outb(SERIAL_LOOPBACK_PATTERN, serial_data!(port));
+        let ready = (0..SERIAL_TIMEOUT).any(|_| {
+            inb(serial_line_status!(port)) & SERIAL_LSR_DATA_READY != 0
+        });
+        let echo = if ready { Some(inb(serial_data!(port))) } else { None };
+        outb(mcr, serial_modem!(port));
+        if echo != Some(SERIAL_LOOPBACK_PATTERN) {
+            return UartKind::Absent;
+        }
+        outb(SERIAL_PROBE_PATTERN, serial_scratch!(port));
+        if inb(serial_scratch!(port)) != SERIAL_PROBE_PATTERN {
+            return UartKind::U8250;
+        }
+        outb(SERIAL_FCR_ENABLE | SERIAL_FCR_64_BYTE | SERIAL_FCR_TRIGGER_14,
+             serial_fifo!(port));
+        let iir = inb(serial_fifo!(port));
+        outb(0x00, serial_fifo!(port));
+        match iir & SERIAL_IIR_FIFO_MASK {
+            SERIAL_IIR_FIFO_ENABLED if iir & SERIAL_IIR_FIFO_64_BYTE != 0 => UartKind::U16750,
+            SERIAL_IIR_FIFO_ENABLED => UartKind::U16550A,
+            SERIAL_IIR_FIFO_BROKEN => UartKind::U16550,
+            _ => UartKind::U16450,
+        }
+    }
+}
+
+/// Ioctl classes understood by the device.
+static SERIAL_IOCTLS: [IoctlClass; 1] = [IoctlClass::Serial];
+
+/// Receive state shared between the IRQ handler and readers.
+struct PortState {
+    rx: RingBuffer,
+    interrupts: AtomicBool,
+    flow_control: AtomicBool,
+    overrun: AtomicUsize,
+    parity: AtomicUsize,
+    framing: AtomicUsize,
+    breaks: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+/// Receive error counters.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SerialErrors {
+    pub overrun: usize,
+    pub parity: usize,
+    pub framing: usize,
+    pub breaks: usize,
+    pub dropped: usize,
+}
+
+/// Per-port receive state, indexed like `SERIAL0`..`SERIAL3`.
+static PORTS: [PortState; 4] = [PortState::new(),
+                                PortState::new(),
+                                PortState::new(),
+                                PortState::new()];
+
+/// Port bases, indexed like `PORTS`.
+static PORT_BASES: [u16; 4] = [SERIAL0, SERIAL1, SERIAL2, SERIAL3];
+
+impl PortState {
+    /// Constructs a new, idle `PortState`.
+    const fn new() -> Self {
+        PortState {
+            rx: RingBuffer::new(),
+            interrupts: AtomicBool::new(false),
+            flow_control: AtomicBool::new(false),
+            overrun: AtomicUsize::new(0),
+            parity: AtomicUsize::new(0),
+            framing: AtomicUsize::new(0),
+            breaks: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+    /// Counts the errors flagged in a line status value.
+    fn record_errors(&self, lsr: u8) {
+        if lsr & SERIAL_LSR_OVERRUN != 0 {
+            self.overrun.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & SERIAL_LSR_PARITY != 0 {
+            self.parity.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & SERIAL_LSR_FRAMING != 0 {
+            self.framing.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & SERIAL_LSR_BREAK != 0 {
+            self.breaks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Gets the `PORTS` index of a port base.
+fn port_index(port: u16) -> Option<usize> {
+    PORT_BASES.iter().position(|&base| base == port)
+}
+
+/// Gets the IRQ line of a port.
+fn port_irq(index: usize) -> u8 {
+    match index {
+        0 | 2 => SERIAL_IRQ_COM1_COM3,
+        _ => SERIAL_IRQ_COM2_COM4,
+    }
+}
+
+/// IRQ3/IRQ4 handler, draining every interrupt-driven port on the line.
+fn interrupt(irq: u8) {
+    for index in 0..PORTS.len() {
+        let state = &PORTS[index];
+        if port_irq(index) != irq || !state.interrupts.load(Ordering::Relaxed) {
+            continue;
+        }
+        let port = PORT_BASES[index];
+        loop {
+            let lsr = unsafe { inb(serial_line_status!(port)) };
+            state.record_errors(lsr);
+            if lsr & SERIAL_LSR_DATA_READY == 0 {
+                break;
+            }
+            let b = unsafe { inb(serial_data!(port)) };
+            if !state.rx.push(b) {
+                state.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if state.flow_control.load(Ordering::Relaxed) && state.rx.len() >= RX_HIGH_WATER {
+            set_rts(port, false);
+        }
+    }
+}
+
+/// Raises or drops RTS, keeping the other modem control bits.
+fn set_rts(port: u16, on: bool) {
+    unsafe {
+        let mcr = inb(serial_modem!(port));
+        outb(if on {
+                 mcr | SERIAL_MCR_RTS
+             } else {
+                 mcr & !SERIAL_MCR_RTS
+             },
+             serial_modem!(port));
+    }
+}
 
 /// Serial device.
 pub struct SerialDevice {
     port: u16,
+    kind: UartKind,
+    config: SerialConfig,
+    /// Set once the transmitter stayed busy for a whole timeout, e.g.
+    /// with CTS low, so that later writes fail at once.
+    stalled: bool,
 }
 
 impl SerialDevice {
-    /// Constructs a new serial device.
+    /// Constructs a new serial device with the default configuration.
     pub fn new(port: u16) -> Self {
-        let device = SerialDevice { port: port };
-        device.initialize();
+        SerialDevice::with_config(port, SerialConfig::new())
+    }
+    /// Constructs a new serial device with the given configuration.
+    pub fn with_config(port: u16, config: SerialConfig) -> Self {
+        let mut device = SerialDevice {
+            port: port,
+            kind: probe(port),
+            config: config,
+            stalled: false,
+        };
+        if device.is_present() {
+            device.configure(config);
+        }
         device
     }
-    /// Initializes the serial port.
+    /// Checks whether a UART was detected at the port.
     #[inline]
-    fn initialize(&self) {
+    pub fn is_present(&self) -> bool {
+        self.kind != UartKind::Absent
+    }
+    /// Gets the detected UART model.
+    pub fn kind(&self) -> UartKind {
+        self.kind
+    }
+    /// Gets the port base.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    /// Gets the line settings.
+    pub fn config(&self) -> SerialConfig {
+        self.config
+    }
+    /// Applies new line settings.
+    pub fn configure(&mut self, config: SerialConfig) {
+        self.config = config;
+        let divisor = config.divisor();
+        let fifo = match self.kind {
+            UartKind::U16550A | UartKind::U16750 => {
+                SERIAL_FCR_ENABLE | SERIAL_FCR_CLEAR | SERIAL_FCR_TRIGGER_14
+            }
+            _ => 0x00,
+        };
+        if let Some(index) = port_index(self.port) {
+            PORTS[index].flow_control.store(config.flow_control == FlowControl::RtsCts,
+                                            Ordering::SeqCst);
+        }
         unsafe {
+            let ier = inb(serial_ier!(self.port));
             outb(0x00, serial_ier!(self.port));
-            outb(0x80, serial_line!(self.port));
-            outb(0x03, serial_data!(self.port));
-            outb(0x00, serial_ier!(self.port));
-            outb(0x03, serial_line!(self.port));
-            outb(0xc7, serial_fifo!(self.port));
-            outb(0x03, serial_modem!(self.port));
+            outb(SERIAL_LCR_DLAB, serial_line!(self.port));
+            outb(divisor as u8, serial_data!(self.port));
+            outb((divisor >> 8) as u8, serial_ier!(self.port));
+            outb(config.line_control(), serial_line!(self.port));
+            outb(fifo, serial_fifo!(self.port));
+            outb(SERIAL_MCR_DTR | SERIAL_MCR_RTS | SERIAL_MCR_OUT2,
+                 serial_modem!(self.port));
+            outb(ier, serial_ier!(self.port));
         }
     }
-    /// Waits till the serial port is ready.
-    #[inline]
-    fn await_ready_state(&self) {
-        loop {
-            if unsafe { inb(self.port + 0x05) } & 0x20 != 0 {
-                break;
+    /// Gets the state of the modem lines.
+    pub fn modem_lines(&self) -> ModemLines {
+        let (mcr, msr) = unsafe {
+            (inb(serial_modem!(self.port)), inb(serial_modem_status!(self.port)))
+        };
+        ModemLines {
+            dtr: mcr & SERIAL_MCR_DTR != 0,
+            rts: mcr & SERIAL_MCR_RTS != 0,
+            cts: msr & SERIAL_MSR_CTS != 0,
+            dsr: msr & SERIAL_MSR_DSR != 0,
+            ri: msr & SERIAL_MSR_RI != 0,
+            dcd: msr & SERIAL_MSR_DCD != 0,
+        }
+    }
+    /// Raises or drops DTR and RTS.
+    pub fn set_modem_lines(&self, lines: ModemLines) {
+        cpu::without_interrupts(|| unsafe {
+            let mut mcr = inb(serial_modem!(self.port)) & !(SERIAL_MCR_DTR | SERIAL_MCR_RTS);
+            if lines.dtr {
+                mcr |= SERIAL_MCR_DTR;
+            }
+            if lines.rts {
+                mcr |= SERIAL_MCR_RTS;
+            }
+            outb(mcr, serial_modem!(self.port));
+        });
+    }
+    /// Switches the receive path from polling to IRQ3/IRQ4.
+    pub fn enable_interrupts(&self) -> bool {
+        if !self.is_present() {
+            return false;
+        }
+        let index = match port_index(self.port) {
+            Some(index) => index,
+            None => return false,
+        };
+        let irq = port_irq(index);
+        // COM1/COM3 and COM2/COM4 share a line, and one handler serves both.
+        if let Err(irq::IrqError::InvalidLine) = irq::register(irq, interrupt) {
+            return false;
+        }
+        PORTS[index].interrupts.store(true, Ordering::SeqCst);
+        unsafe {
+            outb(SERIAL_IER_RX_AVAILABLE | SERIAL_IER_LINE_STATUS,
+                 serial_ier!(self.port));
+        }
+        irq::enable(irq);
+        true
+    }
+    /// Gets the receive error counters.
+    pub fn errors(&self) -> SerialErrors {
+        match port_index(self.port) {
+            Some(index) => {
+                let state = &PORTS[index];
+                SerialErrors {
+                    overrun: state.overrun.load(Ordering::Relaxed),
+                    parity: state.parity.load(Ordering::Relaxed),
+                    framing: state.framing.load(Ordering::Relaxed),
+                    breaks: state.breaks.load(Ordering::Relaxed),
+                    dropped: state.dropped.load(Ordering::Relaxed),
+                }
+            }
+            None => SerialErrors::default(),
+        }
+    }
+    /// Takes a received byte without blocking.
+    fn try_read_byte(&self) -> Option<u8> {
+        let state = match port_index(self.port) {
+            Some(index) => &PORTS[index],
+            None => return self.poll_byte(),
+        };
+        if state.interrupts.load(Ordering::Relaxed) {
+            let b = state.rx.pop();
+            if state.flow_control.load(Ordering::Relaxed) && state.rx.len() <= RX_LOW_WATER {
+                cpu::without_interrupts(|| set_rts(self.port, true));
             }
+            b
+        } else {
+            self.poll_byte()
         }
     }
-    /// Writes a byte to the serial port.
+    /// Polls the data-ready bit directly, for ports without interrupts.
+    fn poll_byte(&self) -> Option<u8> {
+        let lsr = unsafe { inb(serial_line_status!(self.port)) };
+        if let Some(index) = port_index(self.port) {
+            PORTS[index].record_errors(lsr);
+        }
+        if lsr & SERIAL_LSR_DATA_READY != 0 {
+            Some(unsafe { inb(serial_data!(self.port)) })
+        } else {
+            None
+        }
+    }
+    /// Checks whether the transmitter can take a byte.
     #[inline]
-    fn write_byte(&self, b: u8) {
-        self.await_ready_state();
-        unsafe {
-            outb(b, self.port);
+    fn can_transmit(&self) -> bool {
+        if self.config.flow_control == FlowControl::RtsCts &&
+           unsafe { inb(serial_modem_status!(self.port)) } & SERIAL_MSR_CTS == 0 {
+            return false;
+        }
+        unsafe { inb(serial_line_status!(self.port)) & SERIAL_LSR_TX_EMPTY != 0 }
+    }
+}
+
+impl DeviceRead for SerialDevice {
+    fn read(&mut self, _: &DeviceInfo, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        if !self.is_present() {
+            return Err(DeviceError::NotPresent);
+        }
+        let mut count = 0;
+        while count < buf.len() {
+            match self.try_read_byte() {
+                Some(b) => buf[count] = b,
+                None => break,
+            }
+            count += 1;
+        }
+        if count == 0 && !buf.is_empty() {
+            return Err(DeviceError::WouldBlock);
+        }
+        Ok(count)
+    }
+}
+
+impl DeviceIoctl for SerialDevice {
+    fn ioctl_classes(&self) -> &'static [IoctlClass] {
+        &SERIAL_IOCTLS
+    }
+    fn ioctl(&mut self, _: &DeviceInfo, cmd: Ioctl) -> Result<IoctlReply, DeviceError> {
+        if !self.is_present() {
+            return Err(DeviceError::NotPresent);
+        }
+        let cmd = match cmd {
+            Ioctl::Serial(cmd) => cmd,
+            _ => return Err(DeviceError::Unsupported),
+        };
+        match cmd {
+            SerialIoctl::GetBaudRate => Ok(IoctlReply::BaudRate(self.config.baud_rate)),
+            SerialIoctl::SetBaudRate(baud) => {
+                if baud == 0 || baud > SERIAL_CLOCK {
+                    return Err(DeviceError::InvalidArgument);
+                }
+                let config = self.config.baud_rate(baud);
+                self.configure(config);
+                Ok(IoctlReply::Done)
+            }
+            SerialIoctl::GetModemLines => Ok(IoctlReply::ModemLines(self.modem_lines())),
+            SerialIoctl::SetModemLines(lines) => {
+                self.set_modem_lines(lines);
+                Ok(IoctlReply::Done)
+            }
         }
     }
 }
 
 impl DeviceWrite for SerialDevice {
-    fn write_byte(&mut self, _: &DeviceInfo, b: u8) {
-        SerialDevice::write_byte(self, b);
+    fn write(&mut self, _: &DeviceInfo, buf: &[u8]) -> Result<usize, DeviceError> {
+        if !self.is_present() {
+            return Err(DeviceError::NotPresent);
+        }
+        let mut count = 0;
+        while count < buf.len() && self.can_transmit() {
+            unsafe {
+                outb(buf[count], serial_data!(self.port));
+            }
+            count += 1;
+        }
+        if count == 0 && !buf.is_empty() {
+            return Err(DeviceError::WouldBlock);
+        }
+        Ok(count)
+    }
+    /// Fails with `WouldBlock` if the transmitter stays busy, e.g. held
+    /// off by CTS. Later writes then fail at once until it drains.
+    fn write_all(&mut self, dev: &DeviceInfo, mut buf: &[u8]) -> Result<(), DeviceError> {
+        while !buf.is_empty() {
+            let polls = if self.stalled { 1 } else { SERIAL_TIMEOUT };
+            let mut res = Err(DeviceError::WouldBlock);
+            for _ in 0..polls {
+                res = self.write(dev, buf);
+                if let Err(DeviceError::WouldBlock) = res {
+                    continue;
+                }
+                break;
+            }
+            self.stalled = res == Err(DeviceError::WouldBlock);
+            buf = &buf[res?..];
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,6 @@
 use core::ptr::Unique;
 use device::*;
+use ioctl::{Ioctl, IoctlClass, IoctlReply, TerminalIoctl};
 use cpuio::outb;
 
 /// The address of the framebuffer in memory.
@@ -21,6 +22,9 @@ macro_rules! offset {
     ($x:expr, $y:expr) => ($y * VGA_WIDTH + $x)
 }
 
+/// Ioctl classes understood by the device.
+static TERMINAL_IOCTLS: [IoctlClass; 1] = [IoctlClass::Terminal];
+
 type TerminalBuffer = Unique<[u16; VGA_SIZE]>;
 
 pub struct TerminalDevice {
@@ -119,8 +123,58 @@ impl TerminalDevice {
     }
 }
 
+impl DeviceIoctl for TerminalDevice {
+    fn ioctl_classes(&self) -> &'static [IoctlClass] {
+        &TERMINAL_IOCTLS
+    }
+    fn ioctl(&mut self, _: &DeviceInfo, cmd: Ioctl) -> Result<IoctlReply, DeviceError> {
+        let cmd = match cmd {
+            Ioctl::Terminal(cmd) => cmd,
+            _ => return Err(DeviceError::Unsupported),
+        };
+        match cmd {
+            TerminalIoctl::GetCursor => {
+                Ok(IoctlReply::Cursor {
+                    x: self.x,
+                    y: self.y,
+                })
+            }
+            TerminalIoctl::SetCursor { x, y } => {
+                if x >= VGA_WIDTH || y >= VGA_HEIGHT {
+                    return Err(DeviceError::InvalidArgument);
+                }
+                self.x = x;
+                self.y = y;
+                self.update_physical_cursor();
+                Ok(IoctlReply::Done)
+            }
+            TerminalIoctl::GetColor => Ok(IoctlReply::Color(self.color)),
+            TerminalIoctl::SetColor(color) => {
+                self.color = color;
+                Ok(IoctlReply::Done)
+            }
+            TerminalIoctl::GetSize => {
+                Ok(IoctlReply::Size {
+                    width: VGA_WIDTH,
+                    height: VGA_HEIGHT,
+                })
+            }
+            TerminalIoctl::Clear => {
+                self.clear();
+                self.x = 0;
+                self.y = 0;
+                self.update_physical_cursor();
+                Ok(IoctlReply::Done)
+            }
+        }
+    }
+}
+
 impl DeviceWrite for TerminalDevice {
-    fn write_byte(&mut self, _: &DeviceInfo, b: u8) {
-        self.write_byte(b);
+    fn write(&mut self, _: &DeviceInfo, buf: &[u8]) -> Result<usize, DeviceError> {
+        for b in buf {
+            self.write_byte(*b);
+        }
+        Ok(buf.len())
     }
 }
\ No newline at end of file
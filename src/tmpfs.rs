@@ -0,0 +1,737 @@
+#![allow(dead_code)]
+
+use core::{cmp, mem, ptr, slice, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use heap;
+use vfs::{self, DirEntry, FileType, Inode, InodeRef, Stat, VfsError, NAME_MAX};
+
+// Limits
+pub const TMPFS_PAGE_SIZE: usize = 4096;
+pub const TMPFS_DEFAULT_SIZE: usize = 4 * 1024 * 1024;
+pub const TMPFS_DEFAULT_INODES: usize = 1024;
+const ROOT_INO: u64 = 1;
+
+// Page map
+const DIRECT_PAGES: usize = 12;
+/// Page addresses held by an index page, with 64-bit pointers.
+const PAGE_SLOTS: usize = TMPFS_PAGE_SIZE / 8;
+const MAX_PAGES: usize = DIRECT_PAGES + PAGE_SLOTS + PAGE_SLOTS * PAGE_SLOTS;
+
+// Directories
+const MIN_CHILDREN: usize = 8;
+const NO_CHILDREN: &'static [Child] = &[];
+
+/// Writable filesystem kept on the kernel heap.
+struct TmpFs {
+    max_pages: usize,
+    max_inodes: usize,
+    pages: AtomicUsize,
+    inodes: AtomicUsize,
+    next_ino: AtomicUsize,
+    /// Serializes changes to directories, so that renames between two
+    /// directories don't race with other changes.
+    namespace: Mutex<()>,
+    /// Freed nodes, ready for reuse.
+    free: Mutex<Option<&'static TmpNode>>,
+}
+
+/// Node of a tmpfs.
+///
+/// Nodes are never returned to the heap. A node removed from its
+/// directory keeps its pages until the last descriptor on it is
+/// closed, then it goes on the free list of the filesystem.
+struct TmpNode {
+    fs: &'static TmpFs,
+    ino: u64,
+    data: Mutex<NodeData>,
+}
+
+/// Mutable state of a node.
+struct NodeData {
+    kind: FileType,
+    mode: u16,
+    size: u64,
+    /// Whether the node is still in a directory.
+    linked: bool,
+    /// Open descriptors on the node.
+    opened: usize,
+    parent: Option<&'static TmpNode>,
+    /// Next node of the free list.
+    next_free: Option<&'static TmpNode>,
+    pages: PageMap,
+    children: Children,
+}
+
+/// Sparse map from page index to page address. Missing pages read as
+/// zeros.
+///
+/// The first pages are mapped directly, further pages through an
+/// index page and then through a two-level index.
+struct PageMap {
+    direct: [usize; DIRECT_PAGES],
+    indirect: usize,
+    double: usize,
+}
+
+/// Directory entry.
+#[derive(Copy, Clone)]
+struct Child {
+    node: &'static TmpNode,
+    name: [u8; NAME_MAX],
+    len: usize,
+}
+
+/// Growable array of directory entries on the kernel heap.
+struct Children {
+    ptr: *mut Child,
+    count: usize,
+    capacity: usize,
+}
+
+unsafe impl Send for NodeData {}
+
+impl TmpFs {
+    /// Reserves and allocates a zeroed page.
+    fn alloc_page(&self) -> Result<usize, VfsError> {
+        if self.pages.fetch_add(1, Ordering::SeqCst) >= self.max_pages {
+            self.pages.fetch_sub(1, Ordering::SeqCst);
+            return Err(VfsError::NoSpace);
+        }
+        match heap::kalloc(TMPFS_PAGE_SIZE) {
+            Some(page) => Ok(page as usize),
+            None => {
+                self.pages.fetch_sub(1, Ordering::SeqCst);
+                Err(VfsError::NoSpace)
+            }
+        }
+    }
+    /// Returns a page to the kernel heap.
+    fn free_page(&self, page: usize) {
+        if heap::kfree(page as *mut u8) {
+            self.pages.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Follows a slot to the index page it points to, allocating the page
+/// if it is missing and `alloc` is set.
+fn table(fs: &TmpFs, slot: *mut usize, alloc: bool) -> Result<Option<*mut usize>, VfsError> {
+    unsafe {
+        if *slot == 0 {
+            if !alloc {
+                return Ok(None);
+            }
+            *slot = fs.alloc_page()?;
+        }
+        Ok(Some(*slot as *mut usize))
+    }
+}
+
+/// Frees the pages mapped by an index page from slot `from` on.
+/// Returns whether the whole index page was released.
+fn release_table(fs: &TmpFs, table: usize, from: usize) -> bool {
+    let slots = unsafe { slice::from_raw_parts_mut(table as *mut usize, PAGE_SLOTS) };
+    for slot in slots[from..].iter_mut().filter(|slot| **slot != 0) {
+        fs.free_page(*slot);
+        *slot = 0;
+    }
+    if from == 0 {
+        fs.free_page(table);
+    }
+    from == 0
+}
+
+impl PageMap {
+    /// Constructs an empty map.
+    fn new() -> Self {
+        PageMap {
+            direct: [0; DIRECT_PAGES],
+            indirect: 0,
+            double: 0,
+        }
+    }
+    /// Gets the slot holding the address of page `index`. Index pages
+    /// on the way are allocated if `alloc` is set.
+    fn slot(&mut self, fs: &TmpFs, index: usize, alloc: bool)
+            -> Result<Option<*mut usize>, VfsError> {
+        if index < DIRECT_PAGES {
+            return Ok(Some(&mut self.direct[index] as *mut usize));
+        }
+        let index = index - DIRECT_PAGES;
+        if index < PAGE_SLOTS {
+            return table(fs, &mut self.indirect, alloc)
+                .map(|table| table.map(|table| unsafe { table.offset(index as isize) }));
+        }
+        let index = index - PAGE_SLOTS;
+        if index >= PAGE_SLOTS * PAGE_SLOTS {
+            return Err(VfsError::FileTooLarge);
+        }
+        let outer = match table(fs, &mut self.double, alloc)? {
+            Some(outer) => outer,
+            None => return Ok(None),
+        };
+        let slot = unsafe { outer.offset((index / PAGE_SLOTS) as isize) };
+        table(fs, slot, alloc).map(|inner| {
+            inner.map(|inner| unsafe { inner.offset((index % PAGE_SLOTS) as isize) })
+        })
+    }
+    /// Gets the address of page `index`, or `None` for a hole.
+    fn page(&mut self, fs: &TmpFs, index: usize) -> Option<usize> {
+        match self.slot(fs, index, false) {
+            Ok(Some(slot)) if unsafe { *slot } != 0 => Some(unsafe { *slot }),
+            _ => None,
+        }
+    }
+    /// Gets the address of page `index`, allocating it if needed.
+    fn page_mut(&mut self, fs: &TmpFs, index: usize) -> Result<usize, VfsError> {
+        let slot = match self.slot(fs, index, true)? {
+            Some(slot) => slot,
+            None => return Err(VfsError::NoSpace),
+        };
+        unsafe {
+            if *slot == 0 {
+                *slot = fs.alloc_page()?;
+            }
+            Ok(*slot)
+        }
+    }
+    /// Frees page `first` and all pages after it.
+    fn release(&mut self, fs: &TmpFs, first: usize) {
+        for slot in self.direct.iter_mut().skip(first).filter(|slot| **slot != 0) {
+            fs.free_page(*slot);
+            *slot = 0;
+        }
+        let from = first.saturating_sub(DIRECT_PAGES);
+        if self.indirect != 0 && from < PAGE_SLOTS && release_table(fs, self.indirect, from) {
+            self.indirect = 0;
+        }
+        if self.double == 0 {
+            return;
+        }
+        let from = first.saturating_sub(DIRECT_PAGES + PAGE_SLOTS);
+        let outer = unsafe { slice::from_raw_parts_mut(self.double as *mut usize, PAGE_SLOTS) };
+        for (i, slot) in outer.iter_mut().enumerate().skip(from / PAGE_SLOTS) {
+            let start = if i == from / PAGE_SLOTS { from % PAGE_SLOTS } else { 0 };
+            if *slot != 0 && release_table(fs, *slot, start) {
+                *slot = 0;
+            }
+        }
+        if from == 0 {
+            fs.free_page(self.double);
+            self.double = 0;
+        }
+    }
+}
+
+impl Child {
+    /// Gets the entry name.
+    fn name(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.name[..self.len]) }
+    }
+}
+
+impl Children {
+    /// Constructs an empty array.
+    fn new() -> Self {
+        Children {
+            ptr: ptr::null_mut(),
+            count: 0,
+            capacity: 0,
+        }
+    }
+    /// Gets the entries.
+    fn as_slice(&self) -> &[Child] {
+        if self.ptr.is_null() {
+            return NO_CHILDREN;
+        }
+        unsafe { slice::from_raw_parts(self.ptr, self.count) }
+    }
+    /// Points an existing entry to another node.
+    fn set(&mut self, index: usize, node: &'static TmpNode) {
+        assert!(index < self.count);
+        unsafe { (*self.ptr.offset(index as isize)).node = node };
+    }
+    /// Finds an entry by name.
+    fn find(&self, name: &str) -> Option<usize> {
+        self.as_slice().iter().position(|child| child.name() == name)
+    }
+    /// Appends an entry, growing the array as needed.
+    fn push(&mut self, name: &str, node: &'static TmpNode) -> Result<(), VfsError> {
+        if name.len() > NAME_MAX {
+            return Err(VfsError::NameTooLong);
+        }
+        if self.count == self.capacity {
+            let capacity = cmp::max(MIN_CHILDREN, self.capacity * 2);
+            let ptr = match heap::kalloc(capacity * mem::size_of::<Child>()) {
+                Some(ptr) => ptr as *mut Child,
+                None => return Err(VfsError::NoSpace),
+            };
+            if !self.ptr.is_null() {
+                unsafe { ptr::copy_nonoverlapping(self.ptr, ptr, self.count) };
+                heap::kfree(self.ptr as *mut u8);
+            }
+            self.ptr = ptr;
+            self.capacity = capacity;
+        }
+        let mut child = Child {
+            node: node,
+            name: [0; NAME_MAX],
+            len: name.len(),
+        };
+        child.name[..name.len()].copy_from_slice(name.as_bytes());
+        unsafe { ptr::write(self.ptr.offset(self.count as isize), child) };
+        self.count += 1;
+        Ok(())
+    }
+    /// Removes an entry, keeping the order of the others.
+    fn remove(&mut self, index: usize) -> &'static TmpNode {
+        let node = self.as_slice()[index].node;
+        unsafe {
+            let at = self.ptr.offset(index as isize);
+            ptr::copy(at.offset(1), at, self.count - index - 1);
+        }
+        self.count -= 1;
+        node
+    }
+    /// Frees the array.
+    fn clear(&mut self) {
+        if !self.ptr.is_null() {
+            heap::kfree(self.ptr as *mut u8);
+        }
+        *self = Children::new();
+    }
+}
+
+/// Checks that a name can be a directory entry.
+fn check_name(name: &str) -> Result<(), VfsError> {
+    match name {
+        "" | "." | ".." => Err(VfsError::InvalidPath),
+        _ if name.contains('/') => Err(VfsError::InvalidPath),
+        _ if name.len() > NAME_MAX => Err(VfsError::NameTooLong),
+        _ => Ok(()),
+    }
+}
+
+impl TmpNode {
+    /// Creates a node, counting it against the inode limit. Freed nodes
+    /// are reused first.
+    fn new(fs: &'static TmpFs,
+           kind: FileType,
+           mode: u16,
+           parent: Option<&'static TmpNode>)
+           -> Result<&'static TmpNode, VfsError> {
+        if fs.inodes.fetch_add(1, Ordering::SeqCst) >= fs.max_inodes {
+            fs.inodes.fetch_sub(1, Ordering::SeqCst);
+            return Err(VfsError::NoSpace);
+        }
+        let data = NodeData {
+            kind: kind,
+            mode: mode & 0o7777,
+            size: 0,
+            linked: true,
+            opened: 0,
+            parent: parent,
+            next_free: None,
+            pages: PageMap::new(),
+            children: Children::new(),
+        };
+        let reused = {
+            let mut free = fs.free.lock();
+            let node = *free;
+            if let Some(node) = node {
+                *free = node.data.lock().next_free;
+            }
+            node
+        };
+        if let Some(node) = reused {
+            *node.data.lock() = data;
+            return Ok(node);
+        }
+        let node = TmpNode {
+            fs: fs,
+            ino: fs.next_ino.fetch_add(1, Ordering::SeqCst) as u64,
+            data: Mutex::new(data),
+        };
+        match heap::leak(node) {
+            Some(node) => Ok(node),
+            None => {
+                fs.inodes.fetch_sub(1, Ordering::SeqCst);
+                Err(VfsError::NoSpace)
+            }
+        }
+    }
+    /// Gets the `'static` reference of a node. Nodes are leaked and
+    /// never returned to the heap.
+    fn this(&self) -> &'static TmpNode {
+        unsafe { &*(self as *const TmpNode) }
+    }
+    /// Gets the file type.
+    fn kind(&self) -> FileType {
+        self.data.lock().kind
+    }
+    /// Frees the contents of a node and puts it on the free list.
+    fn free(&self, data: &mut NodeData) {
+        data.pages.release(self.fs, 0);
+        data.children.clear();
+        data.size = 0;
+        data.parent = None;
+        let mut free = self.fs.free.lock();
+        data.next_free = *free;
+        *free = Some(self.this());
+        self.fs.inodes.fetch_sub(1, Ordering::SeqCst);
+    }
+    /// Marks a node as removed from its directory. It is freed now, or
+    /// on the last close if descriptors are still open.
+    fn unlink_node(&self) {
+        let mut data = self.data.lock();
+        data.linked = false;
+        if data.opened == 0 {
+            self.free(&mut data);
+        }
+    }
+    /// Looks up an entry of a directory.
+    fn child(&self, name: &str) -> Option<&'static TmpNode> {
+        let data = self.data.lock();
+        data.children.find(name).map(|index| data.children.as_slice()[index].node)
+    }
+    /// Checks whether a directory has no entries.
+    fn is_empty(&self) -> bool {
+        self.data.lock().children.count == 0
+    }
+    /// Checks whether `self` is `dir` or lies below it.
+    fn is_within(&'static self, dir: &TmpNode) -> bool {
+        let mut node = Some(self);
+        while let Some(current) = node {
+            if current as *const TmpNode == dir as *const TmpNode {
+                return true;
+            }
+            node = current.data.lock().parent;
+        }
+        false
+    }
+    /// Writes at a byte offset, allocating pages as needed.
+    fn write_data(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= (MAX_PAGES * TMPFS_PAGE_SIZE) as u64 => {}
+            _ => return Err(VfsError::FileTooLarge),
+        }
+        let mut data = self.data.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset as usize + done;
+            let skip = pos % TMPFS_PAGE_SIZE;
+            let len = cmp::min(TMPFS_PAGE_SIZE - skip, buf.len() - done);
+            let page = match data.pages.page_mut(self.fs, pos / TMPFS_PAGE_SIZE) {
+                Ok(page) => page,
+                Err(_) if done > 0 => break,
+                Err(err) => return Err(err),
+            };
+            let page = unsafe { slice::from_raw_parts_mut(page as *mut u8, TMPFS_PAGE_SIZE) };
+            page[skip..skip + len].copy_from_slice(&buf[done..done + len]);
+            done += len;
+        }
+        let written = offset + done as u64;
+        if written > data.size {
+            data.size = written;
+        }
+        Ok(done)
+    }
+    /// Adds an entry for a new node to a directory.
+    fn add(&self, name: &str, kind: FileType, mode: u16) -> Result<&'static TmpNode, VfsError> {
+        check_name(name)?;
+        {
+            let data = self.data.lock();
+            if data.kind != FileType::Directory {
+                return Err(VfsError::NotDirectory);
+            }
+            if !data.linked {
+                return Err(VfsError::NotFound);
+            }
+        }
+        if self.child(name).is_some() {
+            return Err(VfsError::Exists);
+        }
+        let parent = if kind == FileType::Directory { Some(self.this()) } else { None };
+        let node = TmpNode::new(self.fs, kind, mode, parent)?;
+        if let Err(err) = self.data.lock().children.push(name, node) {
+            node.unlink_node();
+            return Err(err);
+        }
+        Ok(node)
+    }
+    /// Removes an entry from a directory, checking it with `check`.
+    fn remove<F>(&self, name: &str, check: F) -> Result<(), VfsError>
+        where F: Fn(&'static TmpNode) -> Result<(), VfsError>
+    {
+        if self.kind() != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        let _namespace = self.fs.namespace.lock();
+        let node = match self.child(name) {
+            Some(node) => node,
+            None => return Err(VfsError::NotFound),
+        };
+        check(node)?;
+        let mut data = self.data.lock();
+        if let Some(index) = data.children.find(name) {
+            data.children.remove(index);
+        }
+        drop(data);
+        node.unlink_node();
+        Ok(())
+    }
+}
+
+impl Inode for TmpNode {
+    fn stat(&self) -> Stat {
+        let data = self.data.lock();
+        Stat {
+            ino: self.ino,
+            kind: data.kind,
+            mode: data.mode,
+            size: if data.kind == FileType::Directory { 0 } else { data.size },
+            device: None,
+        }
+    }
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let mut data = self.data.lock();
+        if data.kind != FileType::Regular {
+            return Err(VfsError::InvalidArgument);
+        }
+        if offset >= data.size {
+            return Ok(0);
+        }
+        let total = cmp::min(buf.len() as u64, data.size - offset) as usize;
+        let mut done = 0;
+        while done < total {
+            let pos = offset as usize + done;
+            let skip = pos % TMPFS_PAGE_SIZE;
+            let len = cmp::min(TMPFS_PAGE_SIZE - skip, total - done);
+            let out = &mut buf[done..done + len];
+            match data.pages.page(self.fs, pos / TMPFS_PAGE_SIZE) {
+                Some(page) => {
+                    let page = unsafe {
+                        slice::from_raw_parts(page as *const u8, TMPFS_PAGE_SIZE)
+                    };
+                    out.copy_from_slice(&page[skip..skip + len]);
+                }
+                None => {
+                    for byte in out.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+            done += len;
+        }
+        Ok(done)
+    }
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        if self.kind() != FileType::Regular {
+            return Err(VfsError::InvalidArgument);
+        }
+        self.write_data(offset, buf)
+    }
+    /// Shrinking frees the pages past the end, growing leaves a hole.
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let mut data = self.data.lock();
+        match data.kind {
+            FileType::Regular => {}
+            FileType::Directory => return Err(VfsError::IsDirectory),
+            _ => return Err(VfsError::InvalidArgument),
+        }
+        if size > (MAX_PAGES * TMPFS_PAGE_SIZE) as u64 {
+            return Err(VfsError::FileTooLarge);
+        }
+        if size < data.size {
+            let size = size as usize;
+            let skip = size % TMPFS_PAGE_SIZE;
+            let first = (size + TMPFS_PAGE_SIZE - 1) / TMPFS_PAGE_SIZE;
+            data.pages.release(self.fs, first);
+            // Clear the tail of the last page, so growing reads zeros.
+            if skip != 0 {
+                if let Some(page) = data.pages.page(self.fs, size / TMPFS_PAGE_SIZE) {
+                    let tail = (page + skip) as *mut u8;
+                    unsafe { ptr::write_bytes(tail, 0, TMPFS_PAGE_SIZE - skip) };
+                }
+            }
+        }
+        data.size = size;
+        Ok(())
+    }
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        if self.kind() != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        match self.child(name) {
+            Some(node) => Ok(node),
+            None => Err(VfsError::NotFound),
+        }
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, VfsError> {
+        let data = self.data.lock();
+        if data.kind != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        match data.children.as_slice().get(index) {
+            Some(child) => {
+                DirEntry::new(child.node.ino, child.node.kind(), child.name()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    fn readlink(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let mut data = self.data.lock();
+        if data.kind != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let len = cmp::min(buf.len(), data.size as usize);
+        match data.pages.page(self.fs, 0) {
+            Some(page) => {
+                let page = unsafe { slice::from_raw_parts(page as *const u8, TMPFS_PAGE_SIZE) };
+                buf[..len].copy_from_slice(&page[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> Result<InodeRef, VfsError> {
+        if kind != FileType::Regular && kind != FileType::Directory {
+            return Err(VfsError::InvalidArgument);
+        }
+        let _namespace = self.fs.namespace.lock();
+        self.add(name, kind, mode).map(|node| node as InodeRef)
+    }
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, VfsError> {
+        if target.len() > TMPFS_PAGE_SIZE {
+            return Err(VfsError::NameTooLong);
+        }
+        let _namespace = self.fs.namespace.lock();
+        let node = self.add(name, FileType::Symlink, 0o777)?;
+        if let Err(err) = node.write_data(0, target.as_bytes()) {
+            let mut data = self.data.lock();
+            if let Some(index) = data.children.find(name) {
+                data.children.remove(index);
+            }
+            drop(data);
+            node.unlink_node();
+            return Err(err);
+        }
+        Ok(node)
+    }
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        self.remove(name, |node| {
+            match node.kind() {
+                FileType::Directory => Err(VfsError::IsDirectory),
+                _ => Ok(()),
+            }
+        })
+    }
+    fn rmdir(&self, name: &str) -> Result<(), VfsError> {
+        self.remove(name, |node| {
+            match node.kind() {
+                FileType::Directory if node.is_empty() => Ok(()),
+                FileType::Directory => Err(VfsError::NotEmpty),
+                _ => Err(VfsError::NotDirectory),
+            }
+        })
+    }
+    fn rename(&self, name: &str, new_dir: InodeRef, new_name: &str) -> Result<(), VfsError> {
+        if self.kind() != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        check_name(new_name)?;
+        // The VFS only renames within a filesystem, so `new_dir` is one
+        // of our nodes.
+        let new_dir = unsafe { &*(new_dir as *const (Inode + Sync) as *const TmpNode) };
+        if new_dir.kind() != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        let _namespace = self.fs.namespace.lock();
+        let node = match self.child(name) {
+            Some(node) => node,
+            None => return Err(VfsError::NotFound),
+        };
+        let kind = node.kind();
+        // A directory can't move below itself.
+        if kind == FileType::Directory && new_dir.this().is_within(node) {
+            return Err(VfsError::InvalidArgument);
+        }
+        let replaced = new_dir.child(new_name);
+        if let Some(existing) = replaced {
+            if existing as *const TmpNode == node as *const TmpNode {
+                return Ok(());
+            }
+            match (kind, existing.kind()) {
+                (FileType::Directory, FileType::Directory) if !existing.is_empty() => {
+                    return Err(VfsError::NotEmpty)
+                }
+                (FileType::Directory, FileType::Directory) => {}
+                (FileType::Directory, _) => return Err(VfsError::NotDirectory),
+                (_, FileType::Directory) => return Err(VfsError::IsDirectory),
+                _ => {}
+            }
+        }
+        {
+            let mut data = new_dir.data.lock();
+            // A replaced entry is updated in place, so only a new entry
+            // can fail to allocate.
+            match data.children.find(new_name) {
+                Some(index) => data.children.set(index, node),
+                None => data.children.push(new_name, node)?,
+            }
+        }
+        {
+            let mut data = self.data.lock();
+            let found = data.children.as_slice().iter().position(|child| {
+                child.name() == name && child.node as *const TmpNode == node as *const TmpNode
+            });
+            if let Some(index) = found {
+                data.children.remove(index);
+            }
+        }
+        if kind == FileType::Directory {
+            node.data.lock().parent = Some(new_dir.this());
+        }
+        if let Some(existing) = replaced {
+            existing.unlink_node();
+        }
+        Ok(())
+    }
+    fn open(&self) {
+        self.data.lock().opened += 1;
+    }
+    /// Frees a removed node on its last close.
+    fn close(&self) {
+        let mut data = self.data.lock();
+        data.opened -= 1;
+        if data.opened == 0 && !data.linked {
+            self.free(&mut data);
+        }
+    }
+}
+
+/// Creates a tmpfs limited to `size` bytes of file data and `inodes`
+/// nodes, and mounts it at a path.
+pub fn mount(path: &str, size: usize, inodes: usize) -> Result<(), VfsError> {
+    let fs = TmpFs {
+        max_pages: size / TMPFS_PAGE_SIZE,
+        max_inodes: inodes,
+        pages: AtomicUsize::new(0),
+        inodes: AtomicUsize::new(0),
+        next_ino: AtomicUsize::new(ROOT_INO as usize),
+        namespace: Mutex::new(()),
+        free: Mutex::new(None),
+    };
+    let fs: &'static TmpFs = match heap::leak(fs) {
+        Some(fs) => fs,
+        None => return Err(VfsError::OutOfMemory),
+    };
+    let root = TmpNode::new(fs, FileType::Directory, 0o1777, None)?;
+    vfs::mount(path, root, "tmpfs")
+}
+
+/// Mounts a scratch tmpfs at `/tmp`.
+pub fn init() -> Result<(), VfsError> {
+    mount("/tmp", TMPFS_DEFAULT_SIZE, TMPFS_DEFAULT_INODES)
+}
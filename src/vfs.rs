@@ -0,0 +1,721 @@
+#![allow(dead_code)]
+
+use core::str;
+use spin::Mutex;
+use device::DeviceError;
+use heap;
+use ioctl::{Ioctl, IoctlReply};
+
+// Limits
+pub const NAME_MAX: usize = 64;
+pub const PATH_MAX: usize = 256;
+const MAX_MOUNTS: usize = 16;
+const MAX_FILES: usize = 64;
+const MAX_DEPTH: usize = 32;
+const MAX_SYMLINKS: usize = 8;
+
+// Open flags
+pub const O_READ: u32 = 0x01;
+pub const O_WRITE: u32 = 0x02;
+pub const O_APPEND: u32 = 0x04;
+pub const O_DIRECTORY: u32 = 0x08;
+pub const O_NOFOLLOW: u32 = 0x10;
+pub const O_CREATE: u32 = 0x20;
+pub const O_EXCL: u32 = 0x40;
+pub const O_TRUNCATE: u32 = 0x80;
+
+/// Permission bits of files created by `open`.
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+
+/// File descriptor.
+pub type Fd = usize;
+
+/// Node of a mounted filesystem.
+///
+/// Nodes live for the rest of the kernel's life.
+pub type InodeRef = &'static (Inode + Sync);
+
+/// Error raised by filesystem operations.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum VfsError {
+    NotFound,
+    Exists,
+    NotDirectory,
+    IsDirectory,
+    NotEmpty,
+    InvalidPath,
+    NameTooLong,
+    SymlinkLoop,
+    BadDescriptor,
+    TooManyFiles,
+    MountTableFull,
+    OutOfMemory,
+    NoSpace,
+    FileTooLarge,
+    ReadOnly,
+    CrossDevice,
+    Busy,
+    Unsupported,
+    InvalidArgument,
+    Device(DeviceError),
+}
+
+/// Type of a node.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+/// Node attributes.
+#[derive(Copy, Clone, Debug)]
+pub struct Stat {
+    pub ino: u64,
+    pub kind: FileType,
+    /// Permission bits.
+    pub mode: u16,
+    pub size: u64,
+    /// Registry id of the device behind a device node.
+    pub device: Option<usize>,
+}
+
+/// Directory entry.
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    name: [u8; NAME_MAX],
+    len: usize,
+}
+
+/// Position for `seek`.
+#[derive(Copy, Clone, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Provides the operations of a filesystem node.
+///
+/// Operations a node doesn't support fail with `Unsupported`, or with
+/// `NotDirectory` for directory operations.
+pub trait Inode {
+    /// Gets the node attributes.
+    fn stat(&self) -> Stat;
+    /// Reads at a byte offset. Returns the number of bytes read.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Writes at a byte offset. Returns the number of bytes written.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Looks up a directory entry by name.
+    fn lookup(&self, _name: &str) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotDirectory)
+    }
+    /// Gets the directory entry at `index`, or `None` past the end.
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, VfsError> {
+        Err(VfsError::NotDirectory)
+    }
+    /// Reads the target of a symlink. Returns its length.
+    fn readlink(&self, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::InvalidArgument)
+    }
+    /// Runs a device command.
+    fn ioctl(&self, _cmd: Ioctl) -> Result<IoctlReply, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Sets the size of a file, zero-filling when it grows.
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Creates a regular file or a directory in a directory.
+    fn create(&self, _name: &str, _kind: FileType, _mode: u16) -> Result<InodeRef, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Creates a symlink in a directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Removes a non-directory entry from a directory.
+    fn unlink(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Removes an empty subdirectory from a directory.
+    fn rmdir(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Moves an entry of this directory to `new_dir`, replacing any
+    /// entry named `new_name`. `rename` only passes a `new_dir` of the
+    /// same filesystem.
+    fn rename(&self, _name: &str, _new_dir: InodeRef, _new_name: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// Notes that a descriptor was opened on the node.
+    fn open(&self) {}
+    /// Notes that a descriptor opened on the node was closed.
+    fn close(&self) {}
+}
+
+/// Mounted filesystem.
+#[derive(Copy, Clone)]
+struct Mount {
+    path: &'static str,
+    root: InodeRef,
+    fs: &'static str,
+}
+
+/// Directory and final name of a path.
+struct Parent<'a> {
+    dir: InodeRef,
+    /// Root of the filesystem holding the directory.
+    fs: InodeRef,
+    name: &'a str,
+    /// Whether a filesystem is mounted on the entry.
+    mounted: bool,
+}
+
+/// Open file.
+#[derive(Copy, Clone)]
+struct File {
+    inode: InodeRef,
+    offset: u64,
+    flags: u32,
+}
+
+/// Root directory used until a filesystem is mounted at `/`.
+/// It lists the top-level mount points.
+struct RootDir;
+
+/// State of a path walk.
+///
+/// The walk keeps the nodes it passed through, so that `..` returns to
+/// the directory it came from, even across mount points.
+struct Walk {
+    nodes: [Option<InodeRef>; MAX_DEPTH],
+    ends: [usize; MAX_DEPTH],
+    depth: usize,
+    path: [u8; PATH_MAX],
+    links: usize,
+}
+
+static ROOT_DIR: RootDir = RootDir;
+
+/// Mount table.
+static MOUNTS: Mutex<[Option<Mount>; MAX_MOUNTS]> = Mutex::new([None; MAX_MOUNTS]);
+
+/// Open files, indexed by descriptor.
+static FILES: Mutex<[Option<File>; MAX_FILES]> = Mutex::new([None; MAX_FILES]);
+
+impl From<DeviceError> for VfsError {
+    fn from(err: DeviceError) -> Self {
+        VfsError::Device(err)
+    }
+}
+
+impl DirEntry {
+    /// Constructs a directory entry.
+    pub fn new(ino: u64, kind: FileType, name: &str) -> Result<Self, VfsError> {
+        if name.len() > NAME_MAX {
+            return Err(VfsError::NameTooLong);
+        }
+        let mut entry = DirEntry {
+            ino: ino,
+            kind: kind,
+            name: [0; NAME_MAX],
+            len: name.len(),
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(entry)
+    }
+    /// Gets the entry name.
+    pub fn name(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.name[..self.len]) }
+    }
+}
+
+impl Inode for RootDir {
+    fn stat(&self) -> Stat {
+        Stat {
+            ino: 1,
+            kind: FileType::Directory,
+            mode: 0o755,
+            size: 0,
+            device: None,
+        }
+    }
+    fn lookup(&self, _name: &str) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotFound)
+    }
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, VfsError> {
+        let mounts = MOUNTS.lock();
+        let top_level = mounts.iter()
+            .filter_map(|mount| *mount)
+            .filter(|mount| mount.path.len() > 1 && !mount.path[1..].contains('/'))
+            .nth(index);
+        match top_level {
+            Some(mount) => {
+                let ino = mount.root.stat().ino;
+                DirEntry::new(ino, FileType::Directory, &mount.path[1..]).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Checks that a path is absolute and free of `.`, `..` and repeated
+/// or trailing slashes.
+fn is_canonical(path: &str) -> bool {
+    if path == "/" {
+        return true;
+    }
+    path.len() <= PATH_MAX && path.starts_with('/') &&
+    path[1..].split('/').all(|name| {
+        !name.is_empty() && name != "." && name != ".." && name.len() <= NAME_MAX
+    })
+}
+
+/// Gets the root of the filesystem mounted at a canonical path.
+fn mounted_at(path: &str) -> Option<InodeRef> {
+    MOUNTS.lock()
+        .iter()
+        .filter_map(|mount| *mount)
+        .find(|mount| mount.path == path)
+        .map(|mount| mount.root)
+}
+
+/// Gets the root directory.
+fn root() -> InodeRef {
+    mounted_at("/").unwrap_or(&ROOT_DIR)
+}
+
+impl Walk {
+    /// Starts a walk at the root directory.
+    fn new() -> Self {
+        let mut walk = Walk {
+            nodes: [None; MAX_DEPTH],
+            ends: [0; MAX_DEPTH],
+            depth: 0,
+            path: [0; PATH_MAX],
+            links: 0,
+        };
+        walk.nodes[0] = Some(root());
+        walk
+    }
+    /// Gets the node the walk stands on.
+    fn current(&self) -> InodeRef {
+        self.nodes[self.depth].unwrap_or(&ROOT_DIR)
+    }
+    /// Gets the canonical path of the current node.
+    fn path(&self) -> &str {
+        match self.ends[self.depth] {
+            0 => "/",
+            end => unsafe { str::from_utf8_unchecked(&self.path[..end]) },
+        }
+    }
+    /// Gets the root of the filesystem the walk stands in.
+    fn mount_root(&self) -> InodeRef {
+        for depth in (1..self.depth + 1).rev() {
+            let path = unsafe { str::from_utf8_unchecked(&self.path[..self.ends[depth]]) };
+            if let Some(root) = mounted_at(path) {
+                return root;
+            }
+        }
+        root()
+    }
+    /// Descends into a child node.
+    fn push(&mut self, name: &str, node: InodeRef) -> Result<(), VfsError> {
+        let start = self.ends[self.depth];
+        let end = start + 1 + name.len();
+        if self.depth + 1 == MAX_DEPTH || end > PATH_MAX {
+            return Err(VfsError::NameTooLong);
+        }
+        self.path[start] = b'/';
+        self.path[start + 1..end].copy_from_slice(name.as_bytes());
+        self.depth += 1;
+        self.ends[self.depth] = end;
+        self.nodes[self.depth] = Some(node);
+        Ok(())
+    }
+    /// Steps over one path component. Symlinks are followed if `follow`
+    /// is set.
+    fn step(&mut self, name: &str, follow: bool) -> Result<(), VfsError> {
+        match name {
+            "" | "." => return Ok(()),
+            ".." => {
+                if self.depth > 0 {
+                    self.depth -= 1;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        if name.len() > NAME_MAX {
+            return Err(VfsError::NameTooLong);
+        }
+        let parent = self.current();
+        self.push(name, parent)?;
+        // Mount points cover whatever the parent has under that name.
+        let node = match mounted_at(self.path()) {
+            Some(root) => root,
+            None => {
+                match parent.lookup(name) {
+                    Ok(node) => node,
+                    Err(err) => {
+                        self.depth -= 1;
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        if !follow || node.stat().kind != FileType::Symlink {
+            self.nodes[self.depth] = Some(node);
+            return Ok(());
+        }
+        // Link targets are relative to the directory holding the link.
+        self.depth -= 1;
+        self.links += 1;
+        if self.links > MAX_SYMLINKS {
+            return Err(VfsError::SymlinkLoop);
+        }
+        let mut target = [0u8; PATH_MAX];
+        let len = node.readlink(&mut target)?;
+        match str::from_utf8(&target[..len]) {
+            Ok(target) => self.walk(target, true),
+            Err(_) => Err(VfsError::InvalidPath),
+        }
+    }
+    /// Walks a path. Absolute paths restart at the root.
+    fn walk(&mut self, path: &str, follow_last: bool) -> Result<(), VfsError> {
+        if path.len() > PATH_MAX {
+            return Err(VfsError::NameTooLong);
+        }
+        if path.starts_with('/') {
+            self.depth = 0;
+            self.nodes[0] = Some(root());
+        }
+        let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+        while let Some(name) = names.next() {
+            let last = names.peek().is_none();
+            self.step(name, !last || follow_last)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves a path to a node.
+///
+/// Paths are resolved from the root, as there is no working directory.
+fn resolve(path: &str, follow: bool) -> Result<InodeRef, VfsError> {
+    if path.is_empty() {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut walk = Walk::new();
+    walk.walk(path, follow)?;
+    Ok(walk.current())
+}
+
+/// Resolves the directory holding the final entry of a path.
+fn resolve_parent(path: &str) -> Result<Parent, VfsError> {
+    let path = path.trim_right_matches('/');
+    let (dir, name) = match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(split) => (&path[..split], &path[split + 1..]),
+        None => ("/", path),
+    };
+    match name {
+        "" | "." | ".." => return Err(VfsError::InvalidPath),
+        _ => {}
+    }
+    if name.len() > NAME_MAX {
+        return Err(VfsError::NameTooLong);
+    }
+    let mut walk = Walk::new();
+    walk.walk(dir, true)?;
+    let dir = walk.current();
+    let fs = walk.mount_root();
+    walk.push(name, dir)?;
+    let mounted = mounted_at(walk.path()).is_some();
+    Ok(Parent {
+        dir: dir,
+        fs: fs,
+        name: name,
+        mounted: mounted,
+    })
+}
+
+/// Checks whether two references point to the same node.
+pub fn same_node(a: InodeRef, b: InodeRef) -> bool {
+    a as *const (Inode + Sync) as *const u8 == b as *const (Inode + Sync) as *const u8
+}
+
+/// Mounts a filesystem root at a canonical absolute path.
+///
+/// The mount point doesn't need to exist in the parent filesystem.
+pub fn mount(path: &str, root: InodeRef, fs: &'static str) -> Result<(), VfsError> {
+    if !is_canonical(path) {
+        return Err(VfsError::InvalidPath);
+    }
+    if root.stat().kind != FileType::Directory {
+        return Err(VfsError::NotDirectory);
+    }
+    let path = match heap::leak_str(path) {
+        Some(path) => path,
+        None => return Err(VfsError::OutOfMemory),
+    };
+    {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|mount| mount.map_or(false, |mount| mount.path == path)) {
+            return Err(VfsError::Busy);
+        }
+        match mounts.iter_mut().find(|mount| mount.is_none()) {
+            Some(slot) => {
+                *slot = Some(Mount {
+                    path: path,
+                    root: root,
+                    fs: fs,
+                })
+            }
+            None => return Err(VfsError::MountTableFull),
+        }
+    }
+    klog!("[vfs] Mounted {} at {}", fs, path);
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted at a path. Returns its root.
+pub fn unmount(path: &str) -> Result<InodeRef, VfsError> {
+    let mut mounts = MOUNTS.lock();
+    match mounts.iter_mut().find(|mount| mount.map_or(false, |mount| mount.path == path)) {
+        Some(slot) => Ok(slot.take().map(|mount| mount.root).unwrap_or(&ROOT_DIR)),
+        None => Err(VfsError::NotFound),
+    }
+}
+
+/// Resolves a path, following symlinks.
+pub fn lookup(path: &str) -> Result<InodeRef, VfsError> {
+    resolve(path, true)
+}
+
+/// Gets the attributes of the node at a path, following symlinks.
+pub fn stat(path: &str) -> Result<Stat, VfsError> {
+    resolve(path, true).map(|node| node.stat())
+}
+
+/// Gets the attributes of the node at a path, not following a final
+/// symlink.
+pub fn lstat(path: &str) -> Result<Stat, VfsError> {
+    resolve(path, false).map(|node| node.stat())
+}
+
+/// Reads the target of the symlink at a path. Returns its length.
+pub fn readlink(path: &str, buf: &mut [u8]) -> Result<usize, VfsError> {
+    resolve(path, false)?.readlink(buf)
+}
+
+/// Opens the node at a path.
+///
+/// With `O_CREATE`, a missing regular file is created with
+/// `DEFAULT_FILE_MODE`. `O_TRUNCATE` empties a file opened for writing.
+pub fn open(path: &str, flags: u32) -> Result<Fd, VfsError> {
+    let node = match resolve(path, flags & O_NOFOLLOW == 0) {
+        Ok(_) if flags & O_CREATE != 0 && flags & O_EXCL != 0 => return Err(VfsError::Exists),
+        Err(VfsError::NotFound) if flags & O_CREATE != 0 => {
+            let parent = resolve_parent(path)?;
+            parent.dir.create(parent.name, FileType::Regular, DEFAULT_FILE_MODE)?
+        }
+        res => res?,
+    };
+    let kind = node.stat().kind;
+    if flags & O_DIRECTORY != 0 && kind != FileType::Directory {
+        return Err(VfsError::NotDirectory);
+    }
+    if flags & O_WRITE != 0 && kind == FileType::Directory {
+        return Err(VfsError::IsDirectory);
+    }
+    if flags & O_WRITE != 0 && flags & O_TRUNCATE != 0 && kind == FileType::Regular {
+        node.truncate(0)?;
+    }
+    let mut files = FILES.lock();
+    match files.iter().position(|file| file.is_none()) {
+        Some(fd) => {
+            files[fd] = Some(File {
+                inode: node,
+                offset: 0,
+                flags: flags,
+            });
+            node.open();
+            Ok(fd)
+        }
+        None => Err(VfsError::TooManyFiles),
+    }
+}
+
+/// Closes a file descriptor.
+pub fn close(fd: Fd) -> Result<(), VfsError> {
+    let file = FILES.lock().get_mut(fd).and_then(|file| file.take());
+    match file {
+        Some(file) => {
+            file.inode.close();
+            Ok(())
+        }
+        None => Err(VfsError::BadDescriptor),
+    }
+}
+
+/// Gets an open file.
+fn file(fd: Fd) -> Result<File, VfsError> {
+    FILES.lock().get(fd).and_then(|file| *file).ok_or(VfsError::BadDescriptor)
+}
+
+/// Moves the offset of an open file.
+fn set_offset(fd: Fd, offset: u64) {
+    if let Some(&mut Some(ref mut file)) = FILES.lock().get_mut(fd) {
+        file.offset = offset;
+    }
+}
+
+/// Reads from an open file at its offset.
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, VfsError> {
+    let file = file(fd)?;
+    if file.flags & O_READ == 0 {
+        return Err(VfsError::BadDescriptor);
+    }
+    if file.inode.stat().kind == FileType::Directory {
+        return Err(VfsError::IsDirectory);
+    }
+    let count = file.inode.read_at(file.offset, buf)?;
+    set_offset(fd, file.offset + count as u64);
+    Ok(count)
+}
+
+/// Writes to an open file at its offset, or at its end when opened
+/// with `O_APPEND`.
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize, VfsError> {
+    let file = file(fd)?;
+    if file.flags & O_WRITE == 0 {
+        return Err(VfsError::BadDescriptor);
+    }
+    let offset = if file.flags & O_APPEND != 0 {
+        file.inode.stat().size
+    } else {
+        file.offset
+    };
+    let count = file.inode.write_at(offset, buf)?;
+    set_offset(fd, offset + count as u64);
+    Ok(count)
+}
+
+/// Moves the offset of an open file. Returns the new offset.
+pub fn seek(fd: Fd, pos: SeekFrom) -> Result<u64, VfsError> {
+    let file = file(fd)?;
+    let (base, delta) = match pos {
+        SeekFrom::Start(offset) => (offset, 0),
+        SeekFrom::Current(delta) => (file.offset, delta),
+        SeekFrom::End(delta) => (file.inode.stat().size, delta),
+    };
+    let offset = if delta < 0 {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    } else {
+        base.checked_add(delta as u64)
+    };
+    match offset {
+        Some(offset) => {
+            set_offset(fd, offset);
+            Ok(offset)
+        }
+        None => Err(VfsError::InvalidArgument),
+    }
+}
+
+/// Reads the next entry of an open directory, or `None` past the end.
+pub fn readdir(fd: Fd) -> Result<Option<DirEntry>, VfsError> {
+    let file = file(fd)?;
+    let entry = file.inode.readdir(file.offset as usize)?;
+    if entry.is_some() {
+        set_offset(fd, file.offset + 1);
+    }
+    Ok(entry)
+}
+
+/// Gets the attributes of an open file.
+pub fn fstat(fd: Fd) -> Result<Stat, VfsError> {
+    file(fd).map(|file| file.inode.stat())
+}
+
+/// Sets the size of an open file.
+pub fn ftruncate(fd: Fd, size: u64) -> Result<(), VfsError> {
+    let file = file(fd)?;
+    if file.flags & O_WRITE == 0 {
+        return Err(VfsError::BadDescriptor);
+    }
+    file.inode.truncate(size)
+}
+
+/// Sets the size of the file at a path.
+pub fn truncate(path: &str, size: u64) -> Result<(), VfsError> {
+    resolve(path, true)?.truncate(size)
+}
+
+/// Creates a directory.
+pub fn mkdir(path: &str, mode: u16) -> Result<(), VfsError> {
+    let parent = resolve_parent(path)?;
+    if parent.mounted {
+        return Err(VfsError::Exists);
+    }
+    parent.dir.create(parent.name, FileType::Directory, mode).map(|_| ())
+}
+
+/// Removes an empty directory.
+pub fn rmdir(path: &str) -> Result<(), VfsError> {
+    let parent = resolve_parent(path)?;
+    if parent.mounted {
+        return Err(VfsError::Busy);
+    }
+    parent.dir.rmdir(parent.name)
+}
+
+/// Creates a symlink at `path` pointing to `target`.
+pub fn symlink(target: &str, path: &str) -> Result<(), VfsError> {
+    if target.is_empty() || target.len() > PATH_MAX {
+        return Err(VfsError::InvalidPath);
+    }
+    let parent = resolve_parent(path)?;
+    if parent.mounted {
+        return Err(VfsError::Exists);
+    }
+    parent.dir.symlink(parent.name, target).map(|_| ())
+}
+
+/// Removes a non-directory entry. Files stay usable through open
+/// descriptors until the last one is closed.
+pub fn unlink(path: &str) -> Result<(), VfsError> {
+    let parent = resolve_parent(path)?;
+    if parent.mounted {
+        return Err(VfsError::Busy);
+    }
+    parent.dir.unlink(parent.name)
+}
+
+/// Moves an entry within a filesystem.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), VfsError> {
+    let old = resolve_parent(old_path)?;
+    let new = resolve_parent(new_path)?;
+    if old.mounted || new.mounted {
+        return Err(VfsError::Busy);
+    }
+    if !same_node(old.fs, new.fs) {
+        return Err(VfsError::CrossDevice);
+    }
+    old.dir.rename(old.name, new.dir, new.name)
+}
+
+/// Runs a device command on an open file.
+pub fn ioctl(fd: Fd, cmd: Ioctl) -> Result<IoctlReply, VfsError> {
+    file(fd)?.inode.ioctl(cmd)
+}
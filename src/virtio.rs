@@ -0,0 +1,574 @@
+#![allow(dead_code)]
+
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use spin::Mutex;
+use cpuio::{inb, inl, inw, outb, outl, outw};
+use pci::{Bar, PciDevice, PCI_COMMAND_IO, PCI_COMMAND_MEMORY};
+use apic;
+use cpu;
+use heap;
+use irq;
+
+// General
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+pub const VIRTIO_LEGACY_DEVICE_FIRST: u16 = 0x1000;
+pub const VIRTIO_LEGACY_DEVICE_LAST: u16 = 0x103F;
+pub const VIRTIO_MODERN_DEVICE_BASE: u16 = 0x1040;
+const VIRTIO_PAGE_SIZE: usize = 4096;
+const VIRTIO_MAX_QUEUE_SIZE: u16 = 128;
+const VIRTIO_MAX_INTERRUPTS: usize = 8;
+
+// Device status
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 0x01;
+pub const VIRTIO_STATUS_DRIVER: u8 = 0x02;
+pub const VIRTIO_STATUS_DRIVER_OK: u8 = 0x04;
+pub const VIRTIO_STATUS_FEATURES_OK: u8 = 0x08;
+pub const VIRTIO_STATUS_FAILED: u8 = 0x80;
+
+// Features
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Modern capabilities
+const PCI_CAP_VENDOR: u8 = 0x09;
+const VIRTIO_CAP_COMMON: u8 = 1;
+const VIRTIO_CAP_NOTIFY: u8 = 2;
+const VIRTIO_CAP_ISR: u8 = 3;
+const VIRTIO_CAP_DEVICE: u8 = 4;
+const VIRTIO_CAP_TYPE: u8 = 3;
+const VIRTIO_CAP_BAR: u8 = 4;
+const VIRTIO_CAP_OFFSET: u8 = 8;
+const VIRTIO_CAP_NOTIFY_MULTIPLIER: u8 = 16;
+
+// Modern common configuration
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Descriptor flags
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x01;
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x02;
+
+// Ring layout
+const VIRTQ_DESC_SIZE: usize = 16;
+const VIRTQ_RING_HEADER: usize = 4;
+const VIRTQ_USED_ELEM_SIZE: usize = 8;
+
+/// How a virtio device is reached over PCI.
+#[derive(Copy, Clone)]
+pub enum Transport {
+    /// Pre-1.0 devices, with all registers in I/O BAR 0.
+    Legacy { io: u16 },
+    /// 1.0 devices, with register blocks located by vendor capabilities.
+    Modern {
+        common: usize,
+        notify: usize,
+        notify_multiplier: u32,
+        isr: usize,
+        device: usize,
+    },
+}
+
+/// Buffer handed to the device.
+#[derive(Copy, Clone)]
+pub struct Buffer {
+    pub address: usize,
+    pub len: u32,
+    /// Whether the device writes into the buffer.
+    pub writable: bool,
+}
+
+/// Split virtqueue.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: usize,
+    avail: usize,
+    used: usize,
+    notify: usize,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+unsafe impl Send for Virtqueue {}
+
+/// ISR status registers polled by the shared interrupt handler.
+static INTERRUPTS: Mutex<[Option<Transport>; VIRTIO_MAX_INTERRUPTS]> =
+    Mutex::new([None; VIRTIO_MAX_INTERRUPTS]);
+
+/// IRQ lines carrying the shared interrupt handler.
+static IRQ_LINES: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn mmio_read_u8(address: usize) -> u8 {
+    unsafe { ptr::read_volatile(address as *const u8) }
+}
+
+#[inline]
+fn mmio_read_u16(address: usize) -> u16 {
+    unsafe { ptr::read_volatile(address as *const u16) }
+}
+
+#[inline]
+fn mmio_read_u32(address: usize) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+#[inline]
+fn mmio_write_u8(address: usize, val: u8) {
+    unsafe { ptr::write_volatile(address as *mut u8, val) }
+}
+
+#[inline]
+fn mmio_write_u16(address: usize, val: u16) {
+    unsafe { ptr::write_volatile(address as *mut u16, val) }
+}
+
+#[inline]
+fn mmio_write_u32(address: usize, val: u32) {
+    unsafe { ptr::write_volatile(address as *mut u32, val) }
+}
+
+#[inline]
+fn mmio_write_u64(address: usize, val: u64) {
+    mmio_write_u32(address, val as u32);
+    mmio_write_u32(address + 4, (val >> 32) as u32);
+}
+
+impl Transport {
+    /// Locates the registers of a virtio PCI function, preferring the
+    /// modern interface when the device offers both.
+    pub fn new(dev: &PciDevice) -> Option<Self> {
+        let transport = Transport::modern(dev).or_else(|| Transport::legacy(dev));
+        if transport.is_some() {
+            dev.enable_bus_master();
+        }
+        transport
+    }
+    /// Uses the legacy I/O interface.
+    fn legacy(dev: &PciDevice) -> Option<Self> {
+        if dev.device_id < VIRTIO_LEGACY_DEVICE_FIRST || dev.device_id > VIRTIO_LEGACY_DEVICE_LAST {
+            return None;
+        }
+        dev.bars[0].io_port().map(|io| {
+            dev.enable(PCI_COMMAND_IO);
+            Transport::Legacy { io: io }
+        })
+    }
+    /// Uses the modern interface described by vendor capabilities.
+    fn modern(dev: &PciDevice) -> Option<Self> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for cap in dev.capabilities().filter(|cap| cap.id == PCI_CAP_VENDOR) {
+            let address = dev.address;
+            let bar = address.read_u8(cap.offset + VIRTIO_CAP_BAR) as usize;
+            let offset = address.read_u32(cap.offset + VIRTIO_CAP_OFFSET) as u64;
+            let base = match dev.bars.get(bar) {
+                Some(&Bar::Memory { address, .. }) if address + offset < 0x100000000 => {
+                    (address + offset) as usize
+                }
+                _ => continue,
+            };
+            match address.read_u8(cap.offset + VIRTIO_CAP_TYPE) {
+                VIRTIO_CAP_COMMON if common.is_none() => common = Some(base),
+                VIRTIO_CAP_NOTIFY if notify.is_none() => {
+                    notify = Some(base);
+                    notify_multiplier = address.read_u32(cap.offset + VIRTIO_CAP_NOTIFY_MULTIPLIER);
+                }
+                VIRTIO_CAP_ISR if isr.is_none() => isr = Some(base),
+                VIRTIO_CAP_DEVICE if device.is_none() => device = Some(base),
+                _ => {}
+            }
+        }
+        match (common, notify, isr, device) {
+            (Some(common), Some(notify), Some(isr), Some(device)) => {
+                dev.enable(PCI_COMMAND_MEMORY);
+                Some(Transport::Modern {
+                    common: common,
+                    notify: notify,
+                    notify_multiplier: notify_multiplier,
+                    isr: isr,
+                    device: device,
+                })
+            }
+            _ => None,
+        }
+    }
+    /// Checks whether this is the modern interface.
+    pub fn is_modern(&self) -> bool {
+        match *self {
+            Transport::Modern { .. } => true,
+            Transport::Legacy { .. } => false,
+        }
+    }
+    /// Reads the device status.
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { inb(io + LEGACY_DEVICE_STATUS) },
+            Transport::Modern { common, .. } => mmio_read_u8(common + COMMON_DEVICE_STATUS),
+        }
+    }
+    /// Writes the device status. Zero resets the device.
+    pub fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io } => unsafe { outb(status, io + LEGACY_DEVICE_STATUS) },
+            Transport::Modern { common, .. } => {
+                mmio_write_u8(common + COMMON_DEVICE_STATUS, status)
+            }
+        }
+    }
+    /// Adds bits to the device status.
+    pub fn add_status(&self, bits: u8) {
+        let status = self.status();
+        self.set_status(status | bits);
+    }
+    /// Reads the features offered by the device.
+    pub fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { io } => unsafe { inl(io + LEGACY_DEVICE_FEATURES) as u64 },
+            Transport::Modern { common, .. } => {
+                mmio_write_u32(common + COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = mmio_read_u32(common + COMMON_DEVICE_FEATURE) as u64;
+                mmio_write_u32(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+                let high = mmio_read_u32(common + COMMON_DEVICE_FEATURE) as u64;
+                low | high << 32
+            }
+        }
+    }
+    /// Writes the features accepted by the driver.
+    pub fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                outl(features as u32, io + LEGACY_DRIVER_FEATURES)
+            },
+            Transport::Modern { common, .. } => {
+                mmio_write_u32(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+                mmio_write_u32(common + COMMON_DRIVER_FEATURE, features as u32);
+                mmio_write_u32(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+                mmio_write_u32(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+    /// Resets the device and negotiates features. `wanted` lists the
+    /// optional features the driver understands. Returns the accepted set.
+    pub fn begin_init(&self, wanted: u64) -> Option<u64> {
+        self.set_status(0);
+        self.add_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER);
+        let offered = self.device_features();
+        let mut features = offered & wanted;
+        if self.is_modern() {
+            if offered & VIRTIO_F_VERSION_1 == 0 {
+                self.add_status(VIRTIO_STATUS_FAILED);
+                return None;
+            }
+            features |= VIRTIO_F_VERSION_1;
+        }
+        self.set_driver_features(features);
+        if self.is_modern() {
+            self.add_status(VIRTIO_STATUS_FEATURES_OK);
+            if self.status() & VIRTIO_STATUS_FEATURES_OK == 0 {
+                self.add_status(VIRTIO_STATUS_FAILED);
+                return None;
+            }
+        }
+        Some(features)
+    }
+    /// Marks the driver as ready, once the queues are set up.
+    pub fn finish_init(&self) {
+        self.add_status(VIRTIO_STATUS_DRIVER_OK);
+    }
+    /// Reads a byte from the device-specific configuration.
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { inb(io + LEGACY_DEVICE_CONFIG + offset as u16) },
+            Transport::Modern { device, .. } => mmio_read_u8(device + offset),
+        }
+    }
+    /// Reads a dword from the device-specific configuration.
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match *self {
+            Transport::Legacy { io } => unsafe { inl(io + LEGACY_DEVICE_CONFIG + offset as u16) },
+            Transport::Modern { device, .. } => mmio_read_u32(device + offset),
+        }
+    }
+    /// Reads a qword from the device-specific configuration.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+    /// Reads and acknowledges the ISR status.
+    pub fn interrupt_status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { inb(io + LEGACY_ISR_STATUS) },
+            Transport::Modern { isr, .. } => mmio_read_u8(isr),
+        }
+    }
+    /// Sets up a virtqueue. Returns `None` if the queue doesn't exist.
+    pub fn setup_queue(&self, index: u16) -> Option<Virtqueue> {
+        let (size, notify) = self.select_queue(index);
+        if size == 0 {
+            return None;
+        }
+        let queue = match Virtqueue::new(index, size, notify) {
+            Some(queue) => queue,
+            None => return None,
+        };
+        self.program_queue(&queue);
+        Some(queue)
+    }
+    /// Sets up a virtqueue again after a device reset, over its existing
+    /// rings. Returns `false` if the device no longer offers the queue
+    /// with the same size.
+    pub fn restore_queue(&self, queue: &mut Virtqueue) -> bool {
+        let (size, _) = self.select_queue(queue.index);
+        if size != queue.size {
+            return false;
+        }
+        queue.clear();
+        self.program_queue(queue);
+        true
+    }
+    /// Selects a queue and settles its size. Returns the size, which is 0
+    /// if the queue doesn't exist, and the notification address.
+    fn select_queue(&self, index: u16) -> (u16, usize) {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                outw(index, io + LEGACY_QUEUE_SELECT);
+                (inw(io + LEGACY_QUEUE_SIZE), 0)
+            },
+            Transport::Modern { common, notify, notify_multiplier, .. } => {
+                mmio_write_u16(common + COMMON_QUEUE_SELECT, index);
+                let size = mmio_read_u16(common + COMMON_QUEUE_SIZE);
+                // Modern devices accept smaller queues.
+                let size = if size > VIRTIO_MAX_QUEUE_SIZE { VIRTIO_MAX_QUEUE_SIZE } else { size };
+                mmio_write_u16(common + COMMON_QUEUE_SIZE, size);
+                let offset = mmio_read_u16(common + COMMON_QUEUE_NOTIFY_OFF) as usize;
+                (size, notify + offset * notify_multiplier as usize)
+            }
+        }
+    }
+    /// Tells the device where the rings of a queue live.
+    fn program_queue(&self, queue: &Virtqueue) {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                outl((queue.desc / VIRTIO_PAGE_SIZE) as u32, io + LEGACY_QUEUE_ADDRESS);
+            },
+            Transport::Modern { common, .. } => {
+                mmio_write_u64(common + COMMON_QUEUE_DESC, queue.desc as u64);
+                mmio_write_u64(common + COMMON_QUEUE_DRIVER, queue.avail as u64);
+                mmio_write_u64(common + COMMON_QUEUE_DEVICE, queue.used as u64);
+                mmio_write_u16(common + COMMON_QUEUE_ENABLE, 1);
+            }
+        }
+    }
+    /// Tells the device that a queue has new buffers.
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        match *self {
+            Transport::Legacy { io } => unsafe { outw(queue.index, io + LEGACY_QUEUE_NOTIFY) },
+            Transport::Modern { .. } => mmio_write_u16(queue.notify, queue.index),
+        }
+    }
+}
+
+/// Rounds up to the virtio page size.
+fn page_align(size: usize) -> usize {
+    (size + VIRTIO_PAGE_SIZE - 1) & !(VIRTIO_PAGE_SIZE - 1)
+}
+
+/// Lays out the rings of a queue in the legacy layout, which also suits
+/// modern devices. Returns the offsets of the available and used rings
+/// and the total size.
+fn ring_layout(size: u16) -> (usize, usize, usize) {
+    let count = size as usize;
+    let desc_size = VIRTQ_DESC_SIZE * count;
+    let avail_size = VIRTQ_RING_HEADER + 2 * count + 2;
+    let used_size = VIRTQ_RING_HEADER + VIRTQ_USED_ELEM_SIZE * count + 2;
+    let used_offset = page_align(desc_size + avail_size);
+    (desc_size, used_offset, used_offset + page_align(used_size))
+}
+
+impl Virtqueue {
+    /// Allocates the rings.
+    fn new(index: u16, size: u16, notify: usize) -> Option<Self> {
+        let (avail_offset, used_offset, total) = ring_layout(size);
+        let base = match heap::kalloc_aligned(total, VIRTIO_PAGE_SIZE) {
+            Some(base) => base as usize,
+            None => return None,
+        };
+        let mut queue = Virtqueue {
+            index: index,
+            size: size,
+            desc: base,
+            avail: base + avail_offset,
+            used: base + used_offset,
+            notify: notify,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        };
+        queue.clear();
+        Some(queue)
+    }
+    /// Zeroes the rings and chains every descriptor into the free list.
+    fn clear(&mut self) {
+        let (_, _, total) = ring_layout(self.size);
+        unsafe {
+            ptr::write_bytes(self.desc as *mut u8, 0, total);
+        }
+        self.free_head = 0;
+        self.free_count = self.size;
+        self.last_used = 0;
+        for i in 0..self.size {
+            self.write_desc(i, 0, 0, 0, (i + 1) % self.size);
+        }
+    }
+    /// Gets the queue size.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+    /// Gets the number of free descriptors.
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+    /// Writes a descriptor.
+    fn write_desc(&self, i: u16, address: u64, len: u32, flags: u16, next: u16) {
+        let desc = self.desc + i as usize * VIRTQ_DESC_SIZE;
+        unsafe {
+            ptr::write_volatile(desc as *mut u64, address);
+            ptr::write_volatile((desc + 8) as *mut u32, len);
+            ptr::write_volatile((desc + 12) as *mut u16, flags);
+            ptr::write_volatile((desc + 14) as *mut u16, next);
+        }
+    }
+    /// Reads the flags and next field of a descriptor.
+    fn read_desc_link(&self, i: u16) -> (u16, u16) {
+        let desc = self.desc + i as usize * VIRTQ_DESC_SIZE;
+        unsafe {
+            (ptr::read_volatile((desc + 12) as *const u16),
+             ptr::read_volatile((desc + 14) as *const u16))
+        }
+    }
+    /// Chains buffers into descriptors and makes them available.
+    /// Returns the head descriptor, or `None` if the queue is full.
+    ///
+    /// The device is not notified, so several chains can be batched.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut i = head;
+        for (n, buffer) in buffers.iter().enumerate() {
+            let (_, next) = self.read_desc_link(i);
+            let mut flags = if buffer.writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if n + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.write_desc(i, buffer.address as u64, buffer.len, flags, next);
+            if n + 1 < buffers.len() {
+                i = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+        unsafe {
+            let idx_ptr = (self.avail + 2) as *mut u16;
+            let idx = ptr::read_volatile(idx_ptr);
+            let slot = self.avail + VIRTQ_RING_HEADER + (idx % self.size) as usize * 2;
+            ptr::write_volatile(slot as *mut u16, head);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(idx_ptr, idx.wrapping_add(1));
+        }
+        Some(head)
+    }
+    /// Takes a completed chain from the used ring, returning its head
+    /// descriptor and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        let idx = unsafe { ptr::read_volatile((self.used + 2) as *const u16) };
+        if idx == self.last_used {
+            return None;
+        }
+        let elem = self.used + VIRTQ_RING_HEADER +
+                   (self.last_used % self.size) as usize * VIRTQ_USED_ELEM_SIZE;
+        let (head, len) = unsafe {
+            (ptr::read_volatile(elem as *const u32) as u16,
+             ptr::read_volatile((elem + 4) as *const u32))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        // Put the chain back on the free list.
+        let mut i = head;
+        loop {
+            let (flags, next) = self.read_desc_link(i);
+            self.free_count += 1;
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                self.write_desc(i, 0, 0, 0, self.free_head);
+                break;
+            }
+            i = next;
+        }
+        self.free_head = head;
+        Some((head, len))
+    }
+}
+
+/// Shared interrupt handler, acknowledging every registered device.
+/// Waiters poll their used rings once woken up.
+fn interrupt(_: u8) {
+    for transport in INTERRUPTS.lock().iter().filter_map(|transport| *transport) {
+        transport.interrupt_status();
+    }
+}
+
+/// Routes a device's legacy interrupt to the shared handler.
+/// Returns `false` if the line is unusable, in which case waiters
+/// are only woken up by the timer. The line is only an ISA IRQ number
+/// while the 8259 PIC is in charge, so it is never used under the APIC.
+pub fn enable_interrupt(transport: Transport, line: u8) -> bool {
+    if apic::is_enabled() {
+        return false;
+    }
+    let added = cpu::without_interrupts(|| {
+        let mut interrupts = INTERRUPTS.lock();
+        match interrupts.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(transport);
+                true
+            }
+            None => false,
+        }
+    });
+    if !added {
+        return false;
+    }
+    // Devices on the same line share the handler.
+    let shared = line < 16 && IRQ_LINES.load(Ordering::SeqCst) & (1 << line) != 0;
+    if shared || irq::register(line, interrupt).is_ok() {
+        IRQ_LINES.fetch_or(1 << line, Ordering::SeqCst);
+        irq::enable(line);
+        return true;
+    }
+    false
+}
@@ -0,0 +1,348 @@
+#![allow(dead_code)]
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use block::{self, BlockDevice};
+use device::*;
+use pci::{self, PciDevice, PciDriver, PciMatch};
+use virtio::{self, Buffer, Transport, Virtqueue, VIRTIO_VENDOR_ID};
+use apic;
+use cpu;
+use heap;
+use pit;
+
+// Limits
+pub const VIRTIO_BLK_SECTOR_SIZE: usize = 512;
+const VIRTIO_BLK_MAX_REQUESTS: usize = 16;
+const VIRTIO_BLK_REQUEST_SECTORS: usize = 128;
+const VIRTIO_BLK_TIMEOUT_MS: u64 = 5000;
+const VIRTIO_BLK_POLL_LIMIT: usize = 1000000;
+
+// Device ids
+const VIRTIO_BLK_LEGACY_ID: u16 = 0x1001;
+const VIRTIO_BLK_MODERN_ID: u16 = 0x1042;
+
+// Features
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Configuration
+const VIRTIO_BLK_CONFIG_CAPACITY: usize = 0x00;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+// Request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Request slots
+const REQUEST_HEADER_SIZE: usize = 16;
+const REQUEST_STATUS: usize = 16;
+const REQUEST_SLOT_SIZE: usize = 32;
+const REQUEST_STATUS_PENDING: u8 = 0xFF;
+
+/// Next disk number.
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+static VIRTIO_BLK_MATCHES: [PciMatch; 2] = [PciMatch {
+                                                vendor_id: Some(VIRTIO_VENDOR_ID),
+                                                device_id: Some(VIRTIO_BLK_LEGACY_ID),
+                                                class: None,
+                                                subclass: None,
+                                            },
+                                            PciMatch {
+                                                vendor_id: Some(VIRTIO_VENDOR_ID),
+                                                device_id: Some(VIRTIO_BLK_MODERN_ID),
+                                                class: None,
+                                                subclass: None,
+                                            }];
+
+static VIRTIO_BLK_DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &VIRTIO_BLK_MATCHES,
+    probe: probe,
+};
+
+/// Request waiting to be submitted.
+#[derive(Copy, Clone)]
+struct Request {
+    kind: u32,
+    lba: u64,
+    data: usize,
+    len: usize,
+}
+
+/// Block device behind a virtio-blk function.
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: Virtqueue,
+    sectors: u64,
+    features: u64,
+    /// Headers and status bytes, one slot per request in flight.
+    slots: usize,
+    /// Head descriptor of the request in each slot.
+    heads: [Option<u16>; VIRTIO_BLK_MAX_REQUESTS],
+    /// Set when the device could not be brought back after a timeout.
+    failed: bool,
+}
+
+unsafe impl Send for VirtioBlk {}
+
+impl VirtioBlk {
+    /// Initializes the device behind a transport.
+    fn new(transport: Transport) -> Option<Self> {
+        let features = match transport.begin_init(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH) {
+            Some(features) => features,
+            None => return None,
+        };
+        let queue = match transport.setup_queue(0) {
+            Some(queue) => queue,
+            None => {
+                transport.add_status(virtio::VIRTIO_STATUS_FAILED);
+                return None;
+            }
+        };
+        let slots = match heap::kalloc_aligned(VIRTIO_BLK_MAX_REQUESTS * REQUEST_SLOT_SIZE,
+                                               REQUEST_SLOT_SIZE) {
+            Some(slots) => slots as usize,
+            None => {
+                transport.add_status(virtio::VIRTIO_STATUS_FAILED);
+                return None;
+            }
+        };
+        transport.finish_init();
+        Some(VirtioBlk {
+            transport: transport,
+            queue: queue,
+            sectors: transport.config_u64(VIRTIO_BLK_CONFIG_CAPACITY),
+            features: features,
+            slots: slots,
+            heads: [None; VIRTIO_BLK_MAX_REQUESTS],
+            failed: false,
+        })
+    }
+    /// Resets the device after a request timed out, so that it drops
+    /// the buffers of abandoned requests, then sets up the queue again
+    /// over its cleared rings. The disk is marked failed if the device
+    /// doesn't come back.
+    fn reset(&mut self) {
+        self.transport.set_status(0);
+        self.heads = [None; VIRTIO_BLK_MAX_REQUESTS];
+        let features = self.transport.begin_init(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH);
+        let restored = features.is_some() && self.transport.restore_queue(&mut self.queue);
+        match features {
+            Some(features) if restored => {
+                self.features = features;
+                self.transport.finish_init();
+                klog!("[virtio-blk] Request timed out, device reset");
+            }
+            _ => {
+                self.transport.add_status(virtio::VIRTIO_STATUS_FAILED);
+                self.failed = true;
+                klog!("[virtio-blk] Request timed out, device failed");
+            }
+        }
+    }
+    /// Checks whether the device rejects writes.
+    pub fn is_read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
+    }
+    /// Fills a free slot and queues its descriptor chain.
+    /// Returns `false` if no slot or descriptors are free.
+    fn submit(&mut self, request: Request) -> bool {
+        let index = match self.heads.iter().position(|head| head.is_none()) {
+            Some(index) => index,
+            None => return false,
+        };
+        let slot = self.slots + index * REQUEST_SLOT_SIZE;
+        unsafe {
+            ptr::write_volatile(slot as *mut u32, request.kind);
+            ptr::write_volatile((slot + 4) as *mut u32, 0);
+            ptr::write_volatile((slot + 8) as *mut u64, request.lba);
+            ptr::write_volatile((slot + REQUEST_STATUS) as *mut u8, REQUEST_STATUS_PENDING);
+        }
+        let header = Buffer {
+            address: slot,
+            len: REQUEST_HEADER_SIZE as u32,
+            writable: false,
+        };
+        let data = Buffer {
+            address: request.data,
+            len: request.len as u32,
+            writable: request.kind == VIRTIO_BLK_T_IN,
+        };
+        let status = Buffer {
+            address: slot + REQUEST_STATUS,
+            len: 1,
+            writable: true,
+        };
+        let head = if request.len == 0 {
+            self.queue.add(&[header, status])
+        } else {
+            self.queue.add(&[header, data, status])
+        };
+        self.heads[index] = head;
+        head.is_some()
+    }
+    /// Waits until every request in flight has completed, then checks
+    /// their status. Under the 8259 PIC, the interrupt handler
+    /// acknowledges the device and wakes us up. Under the APIC, or without
+    /// a usable line, only the timer does and the used ring is polled.
+    fn complete(&mut self) -> Result<(), DeviceError> {
+        let deadline = pit::uptime() + VIRTIO_BLK_TIMEOUT_MS;
+        let mut polls = 0;
+        let mut result = Ok(());
+        while self.heads.iter().any(|head| head.is_some()) {
+            if let Some((head, _)) = self.queue.pop_used() {
+                let index = match self.heads.iter().position(|slot| *slot == Some(head)) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                self.heads[index] = None;
+                let slot = self.slots + index * REQUEST_SLOT_SIZE;
+                let status = unsafe { ptr::read_volatile((slot + REQUEST_STATUS) as *const u8) };
+                result = match status {
+                    VIRTIO_BLK_S_OK => result,
+                    VIRTIO_BLK_S_UNSUPP => Err(DeviceError::Unsupported),
+                    _ => Err(DeviceError::Io),
+                };
+                continue;
+            }
+            let expired = if cpu::interrupts_enabled() {
+                pit::uptime() > deadline
+            } else {
+                polls += 1;
+                polls > VIRTIO_BLK_POLL_LIMIT
+            };
+            if expired {
+                self.reset();
+                return Err(DeviceError::Io);
+            }
+            if cpu::interrupts_enabled() {
+                cpu::hlt();
+            }
+        }
+        result
+    }
+    /// Moves sectors between the disk and memory, keeping up to
+    /// `VIRTIO_BLK_MAX_REQUESTS` requests in flight at once.
+    ///
+    /// Memory is identity mapped, so requests use the buffer in place.
+    fn transfer(&mut self, kind: u32, lba: u64, data: usize, len: usize)
+                -> Result<(), DeviceError> {
+        if self.failed {
+            return Err(DeviceError::Io);
+        }
+        let chunk = VIRTIO_BLK_REQUEST_SECTORS * VIRTIO_BLK_SECTOR_SIZE;
+        let mut offset = 0;
+        while offset < len {
+            let mut queued = 0;
+            while offset < len {
+                let part = if len - offset > chunk { chunk } else { len - offset };
+                let request = Request {
+                    kind: kind,
+                    lba: lba + (offset / VIRTIO_BLK_SECTOR_SIZE) as u64,
+                    data: data + offset,
+                    len: part,
+                };
+                if !self.submit(request) {
+                    break;
+                }
+                queued += 1;
+                offset += part;
+            }
+            if queued == 0 {
+                return Err(DeviceError::Io);
+            }
+            self.transport.notify(&self.queue);
+            self.complete()?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        VIRTIO_BLK_SECTOR_SIZE
+    }
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        self.transfer(VIRTIO_BLK_T_IN, lba, buf.as_mut_ptr() as usize, buf.len())
+    }
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        block::check_range(self, lba, buf.len())?;
+        if self.is_read_only() {
+            return Err(DeviceError::Unsupported);
+        }
+        self.transfer(VIRTIO_BLK_T_OUT, lba, buf.as_ptr() as usize, buf.len())
+    }
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        if self.failed {
+            return Err(DeviceError::Io);
+        }
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
+        }
+        let request = Request {
+            kind: VIRTIO_BLK_T_FLUSH,
+            lba: 0,
+            data: 0,
+            len: 0,
+        };
+        if !self.submit(request) {
+            return Err(DeviceError::Io);
+        }
+        self.transport.notify(&self.queue);
+        self.complete()
+    }
+}
+
+/// Sets up a virtio-blk function and registers its disk.
+fn probe(dev: &PciDevice) -> bool {
+    let transport = match Transport::new(dev) {
+        Some(transport) => transport,
+        None => return false,
+    };
+    let disk = match VirtioBlk::new(transport) {
+        Some(disk) => disk,
+        None => {
+            klog!("[virtio-blk] Device at {} failed to initialize", dev.address);
+            return false;
+        }
+    };
+    if apic::is_enabled() {
+        klog!("[virtio-blk] No legacy IRQ routing under the APIC, polling for completion");
+    } else if !virtio::enable_interrupt(transport, dev.interrupt_line) {
+        klog!("[virtio-blk] IRQ {} unavailable, polling for completion",
+              dev.interrupt_line);
+    }
+    let number = NEXT_DISK.fetch_add(1, Ordering::SeqCst);
+    let name = match device_name(format_args!("vd{}", number)) {
+        Some(name) => name,
+        None => return false,
+    };
+    klog!("[virtio-blk] {}: {} at {}, queue size {} ({} MiB{})",
+          name,
+          if transport.is_modern() { "modern" } else { "legacy" },
+          dev.address,
+          disk.queue.size(),
+          disk.sectors * VIRTIO_BLK_SECTOR_SIZE as u64 / (1024 * 1024),
+          if disk.is_read_only() { ", read-only" } else { "" });
+    if let Err(err) = block::register(disk, name) {
+        klog!("[virtio-blk] Unable to register {}: {:?}", name, err);
+    }
+    true
+}
+
+/// Registers the virtio-blk driver with the PCI subsystem. Returns the
+/// number of disks.
+pub fn init() -> usize {
+    pci::register_driver(&VIRTIO_BLK_DRIVER)
+}